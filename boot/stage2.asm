@@ -5,6 +5,10 @@
 [ORG 0x7E00]
 
 KERNEL_OFFSET equ 0x100000
+CMDLINE_OFFSET equ 1792     ; Kernel command line, see kernel/src/cmdline.rs
+CMDLINE_SIZE equ 256
+E820_MAP equ 0x5000         ; Entry count, followed by 24-byte entries
+E820_MAX_ENTRIES equ 128
 
 stage2_start:
     mov si, msg_stage2
@@ -20,6 +24,13 @@ stage2_start:
     mov si, msg_ok
     call print_string
     
+    ; Collect memory map for the kernel
+    mov si, msg_memory
+    call print_string
+    call detect_memory
+    mov si, msg_ok
+    call print_string
+    
     ; Load kernel
     mov si, msg_loading_kernel
     call print_string
@@ -134,6 +145,45 @@ enable_a20:
     jz .wait_output
     ret
 
+;===========================================
+; Collect BIOS E820 memory map
+;===========================================
+detect_memory:
+    pushad
+    mov dword [E820_MAP], 0
+    mov di, E820_MAP + 8
+    xor ebx, ebx
+    xor bp, bp
+    
+.next_entry:
+    mov eax, 0xE820
+    mov ecx, 24
+    mov edx, 0x534D4150     ; 'SMAP'
+    mov dword [di + 20], 1  ; Valid ACPI 3.x attributes by default
+    int 0x15
+    jc .done
+    cmp eax, 0x534D4150
+    jne .done
+    
+    ; Skip zero-length entries
+    mov eax, [di + 8]
+    or eax, [di + 12]
+    jz .skip
+    
+    inc bp
+    add di, 24
+    cmp bp, E820_MAX_ENTRIES
+    jae .done
+    
+.skip:
+    test ebx, ebx
+    jnz .next_entry
+    
+.done:
+    mov [E820_MAP], bp
+    popad
+    ret
+
 ;===========================================
 ; Load kernel from disk
 ;===========================================
@@ -310,6 +360,7 @@ DATA64_SEG equ gdt64_data - gdt64_start
 ;===========================================
 msg_stage2:         db 'Stage 2 Bootloader', 13, 10, 0
 msg_a20:            db 'Enabling A20...', 0
+msg_memory:         db 'Reading memory map...', 0
 msg_loading_kernel: db 'Loading kernel...', 0
 msg_paging:         db 'Setting up paging...', 0
 msg_protected:      db 'Entering protected mode...', 13, 10, 0
@@ -317,5 +368,9 @@ msg_ok:             db ' [OK]', 13, 10, 0
 msg_disk_error:     db ' [DISK ERROR]', 13, 10, 0
 msg_no_long_mode:   db 'ERROR: 64-bit long mode not supported!', 13, 10, 0
 
+; Kernel command line, patched into the image by tools/create-image.sh
+times CMDLINE_OFFSET-($-$$) db 0
+kernel_cmdline: times CMDLINE_SIZE db 0
+
 ; Pad to 4 sectors (2048 bytes)
 times 2048-($-$$) db 0
@@ -0,0 +1,951 @@
+use crate::buddy::{self, GlobalFrameAllocator};
+use crate::cow::PTE_COW;
+use crate::memory::{phys_to_virt, physical_memory_offset};
+use crate::process::Pid;
+use crate::rlimit::MemoryUsage;
+use crate::shm::{ShmError, ShmId};
+use crate::swap::SwapEntry;
+use crate::vma::{VmProtection, Vma, VmaBacking, VmaError, VmaList, MMAP_BASE, USER_SPACE_END};
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::{
+    instructions::tlb,
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{
+        mapper::MapToError, page_table::PageTableEntry, Mapper, OffsetPageTable, Page, PageSize,
+        PageTable, PageTableFlags, PageTableIndex, PhysFrame, Size2MiB, Size4KiB,
+    },
+    VirtAddr,
+};
+
+/// First PML4 entry of the kernel (higher) half
+pub const KERNEL_PML4_START: usize = 256;
+
+/// Software PTE bit for frames this address space does not own
+pub const PTE_SHARED: PageTableFlags = PageTableFlags::BIT_10;
+
+/// PML4 the kernel booted with, shared by every address space
+static KERNEL_PML4: Mutex<Option<PhysFrame>> = Mutex::new(None);
+
+/// A process address space rooted at its own PML4
+///
+/// The lower half belongs to the process and is torn down on drop, the
+/// higher half is copied from the kernel PML4 so kernel code and data stay
+/// mapped after a CR3 switch. User mappings are described by `vmas` and
+/// populated lazily by the page fault handler.
+#[derive(Debug)]
+pub struct AddressSpace {
+    pml4: PhysFrame,
+    /// Process the space belongs to, shared memory is attached for it
+    pid: Pid,
+    pub vmas: VmaList,
+    /// Bytes currently mapped, including pages shared with other spaces
+    resident: u64,
+    /// Highest value `resident` has reached
+    peak_resident: u64,
+    /// Where mmap starts looking for free space without a usable hint
+    mmap_base: VirtAddr,
+    /// Start and current end of the brk heap, empty until a program is loaded
+    brk_start: VirtAddr,
+    brk: VirtAddr,
+    /// JIT permission: mappings may be writable and executable at once
+    allow_write_exec: bool,
+}
+
+impl AddressSpace {
+    /// Allocate a fresh PML4 sharing the kernel higher half for process `pid`
+    pub fn new(pid: Pid) -> Option<Self> {
+        let kernel_pml4 = KERNEL_PML4.lock().expect("address spaces not initialized");
+        let pml4 = crate::buddy::allocate_frames(0)?;
+
+        unsafe {
+            let table = table_mut(pml4);
+            let kernel_table = table_mut(kernel_pml4);
+            table.zero();
+            for i in KERNEL_PML4_START..512 {
+                table[i] = kernel_table[i].clone();
+            }
+        }
+
+        Some(Self {
+            pml4,
+            pid,
+            vmas: VmaList::new(),
+            resident: 0,
+            peak_resident: 0,
+            mmap_base: VirtAddr::new(MMAP_BASE),
+            brk_start: VirtAddr::zero(),
+            brk: VirtAddr::zero(),
+            allow_write_exec: false,
+        })
+    }
+
+    /// Physical frame of the PML4
+    pub fn pml4_frame(&self) -> PhysFrame {
+        self.pml4
+    }
+
+    /// Get a mapper for this address space
+    pub fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe { OffsetPageTable::new(table_mut(self.pml4), physical_memory_offset()) }
+    }
+
+    /// Map a user page, creating intermediate tables as needed
+    ///
+    /// Intermediate tables are always writable and user accessible so that
+    /// the leaf entry alone decides the access rights.
+    pub fn map_user_page<S: PageSize>(
+        &mut self,
+        page: Page<S>,
+        frame: PhysFrame<S>,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<S>>
+    where
+        for<'a> OffsetPageTable<'a>: Mapper<S>,
+    {
+        let table_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let flush = unsafe {
+            self.mapper().map_to_with_table_flags(
+                page,
+                frame,
+                flags,
+                table_flags,
+                &mut GlobalFrameAllocator,
+            )?
+        };
+
+        if self.is_active() {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+
+        self.add_resident(S::SIZE);
+        Ok(())
+    }
+
+    /// Map a page read back from swap in place of its swap entry
+    pub fn map_swapped_page(&mut self, page: Page, frame: PhysFrame, flags: PageTableFlags) {
+        let is_active = self.is_active();
+        if let Some(entry) = self.entry_mut(page) {
+            entry.set_frame(frame, flags);
+            if is_active {
+                tlb::flush(page.start_address());
+            }
+            self.add_resident(Page::<Size4KiB>::SIZE);
+        }
+    }
+
+    /// Set where mmap places mappings without a usable hint
+    pub fn set_mmap_base(&mut self, base: VirtAddr) {
+        self.mmap_base = base;
+    }
+
+    /// Check whether writable and executable mappings are allowed
+    pub fn allows_write_exec(&self) -> bool {
+        self.allow_write_exec
+    }
+
+    /// Grant or revoke the JIT permission
+    ///
+    /// Mappings made while it was granted keep their protection.
+    pub fn set_allow_write_exec(&mut self, allow: bool) {
+        self.allow_write_exec = allow;
+    }
+
+    fn check_write_exec(&self, protection: VmProtection) -> Result<(), VmaError> {
+        if protection.is_write_exec() && !self.allow_write_exec {
+            return Err(VmaError::WriteExec);
+        }
+        Ok(())
+    }
+
+    /// Start the brk heap at `start`, with nothing mapped yet
+    pub fn init_brk(&mut self, start: VirtAddr) {
+        self.brk_start = start;
+        self.brk = start;
+    }
+
+    /// Current end of the brk heap
+    pub fn brk(&self) -> VirtAddr {
+        self.brk
+    }
+
+    /// Move the end of the brk heap to `end`, returning the new end
+    ///
+    /// The heap never shrinks below where it started. Whole pages are mapped
+    /// or unmapped as the rounded-up end moves.
+    pub fn set_brk(&mut self, end: VirtAddr) -> Result<VirtAddr, VmaError> {
+        if self.brk_start.is_null() || end < self.brk_start {
+            return Err(VmaError::InvalidRange);
+        }
+        let page = Size4KiB::SIZE;
+        let old_top = self.brk.align_up(page);
+        let new_top = end.align_up(page);
+
+        if new_top > old_top {
+            if self.vmas.overlaps(old_top, new_top) {
+                return Err(VmaError::NoSpace);
+            }
+            let protection = VmProtection::READ | VmProtection::WRITE;
+            self.map_anonymous(Some(old_top), new_top - old_top, protection, false, true)?;
+        } else if new_top < old_top {
+            self.unmap(new_top, old_top - new_top)?;
+        }
+        self.brk = end;
+        Ok(end)
+    }
+
+    /// Copy `data` to `addr` in freshly mapped areas
+    ///
+    /// Pages not yet populated get a zeroed frame with their area's flags,
+    /// so the rest of a partly written page reads as zero. Used to build a
+    /// new program image before it is ever activated.
+    pub fn populate(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), VmaError> {
+        let mut done = 0;
+        while done < data.len() {
+            let at = addr + done as u64;
+            let page = Page::<Size4KiB>::containing_address(at);
+            let vma = *self.vmas.find(at).ok_or(VmaError::NotMapped)?;
+            if vma.huge_pages {
+                return Err(VmaError::InvalidRange);
+            }
+
+            let frame = match self.entry_mut(page) {
+                Some(entry) if entry.flags().contains(PageTableFlags::PRESENT) => {
+                    PhysFrame::containing_address(entry.addr())
+                }
+                _ => {
+                    let frame = buddy::allocate_frames(0).ok_or(VmaError::OutOfMemory)?;
+                    unsafe { frame_bytes(frame).fill(0) };
+                    if self.map_user_page(page, frame, vma.page_flags()).is_err() {
+                        buddy::free_frames(frame, 0);
+                        return Err(VmaError::OutOfMemory);
+                    }
+                    frame
+                }
+            };
+
+            let offset = (at - page.start_address()) as usize;
+            let len = core::cmp::min(Size4KiB::SIZE as usize - offset, data.len() - done);
+            unsafe {
+                frame_bytes(frame)[offset..offset + len].copy_from_slice(&data[done..done + len]);
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn add_resident(&mut self, bytes: u64) {
+        self.resident += bytes;
+        self.peak_resident = core::cmp::max(self.peak_resident, self.resident);
+    }
+
+    /// Account for pages the swap scanner evicted
+    pub fn forget_resident(&mut self, bytes: u64) {
+        self.resident -= bytes;
+    }
+
+    /// Current memory use
+    pub fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            resident: self.resident,
+            peak_resident: self.peak_resident,
+            virtual_size: self.vmas.total_size(),
+        }
+    }
+
+    /// Walk down the tables along `indices`, if they exist
+    fn table_at(&mut self, indices: &[PageTableIndex]) -> Option<&mut PageTable> {
+        let mut table = unsafe { table_mut(self.pml4) };
+        for &index in indices {
+            let entry = &table[index];
+            if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return None;
+            }
+            table = unsafe { table_mut(frame_of(entry)) };
+        }
+        Some(table)
+    }
+
+    /// Get the level 1 entry for a 4 KiB page, if its tables exist
+    pub fn entry_mut(&mut self, page: Page) -> Option<&mut PageTableEntry> {
+        let indices = [page.p4_index(), page.p3_index(), page.p2_index()];
+        self.table_at(&indices)
+            .map(|table| &mut table[page.p1_index()])
+    }
+
+    /// Get the level 1 entry for a 4 KiB page, creating its tables as needed
+    fn entry_create(&mut self, page: Page) -> Option<&mut PageTableEntry> {
+        let table_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let mut table = unsafe { table_mut(self.pml4) };
+        for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+            let entry = &mut table[index];
+            if entry.is_unused() {
+                entry.set_frame(buddy::allocate_zeroed_frame()?, table_flags);
+            } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return None;
+            }
+            table = unsafe { table_mut(frame_of(entry)) };
+        }
+        Some(&mut table[page.p1_index()])
+    }
+
+    /// Get the level 2 entry for a 2 MiB page, if it maps a huge page
+    pub fn huge_entry_mut(&mut self, page: Page<Size2MiB>) -> Option<&mut PageTableEntry> {
+        let indices = [page.p4_index(), page.p3_index()];
+        self.table_at(&indices)
+            .map(|table| &mut table[page.p2_index()])
+            .filter(|entry| entry.flags().contains(PageTableFlags::HUGE_PAGE))
+    }
+
+    /// Call `f` for every present 4 KiB page in the user half
+    pub fn for_each_page(&mut self, mut f: impl FnMut(Page, &mut PageTableEntry)) {
+        self.for_each_leaf(|page, entry| {
+            if entry.flags().contains(PageTableFlags::PRESENT) {
+                f(page, entry);
+            }
+        });
+    }
+
+    /// Call `f` for every used level 1 entry in the user half, present or not
+    fn for_each_leaf(&mut self, mut f: impl FnMut(Page, &mut PageTableEntry)) {
+        let present =
+            |e: &PageTableEntry| !e.is_unused() && !e.flags().contains(PageTableFlags::HUGE_PAGE);
+        let index = |i: usize| PageTableIndex::new(i as u16);
+
+        unsafe {
+            let pml4 = table_mut(self.pml4);
+            for (i4, e4) in pml4.iter_mut().enumerate().take(KERNEL_PML4_START) {
+                if !present(e4) {
+                    continue;
+                }
+                for (i3, e3) in table_mut(frame_of(e4)).iter_mut().enumerate() {
+                    if !present(e3) {
+                        continue;
+                    }
+                    for (i2, e2) in table_mut(frame_of(e3)).iter_mut().enumerate() {
+                        if !present(e2) {
+                            continue;
+                        }
+                        for (i1, e1) in table_mut(frame_of(e2)).iter_mut().enumerate() {
+                            if !e1.is_unused() {
+                                let page = Page::from_page_table_indices(
+                                    index(i4),
+                                    index(i3),
+                                    index(i2),
+                                    index(i1),
+                                );
+                                f(page, e1);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Duplicate this address space for fork by process `pid`
+    ///
+    /// Private pages are shared with the child and marked copy-on-write in
+    /// both copies, writable or not; the first write to such a page copies it
+    /// (see `cow`). Swapped
+    /// out pages keep their slot, each copy reads it back on its own. The
+    /// child is attached to the shared memory mapped here.
+    pub fn fork(&mut self, pid: Pid) -> Option<AddressSpace> {
+        let mut child = AddressSpace::new(pid)?;
+        child.vmas = self.vmas.clone();
+        child.mmap_base = self.mmap_base;
+        child.brk_start = self.brk_start;
+        child.brk = self.brk;
+        child.allow_write_exec = self.allow_write_exec;
+        let mut failed = false;
+
+        self.for_each_leaf(|page, entry| {
+            if failed {
+                return;
+            }
+
+            // Swapped out pages share their swap slot
+            if let Some(swap) = SwapEntry::from_pte(entry) {
+                match child.entry_create(page) {
+                    Some(child_entry) if crate::swap::duplicate(swap) => {
+                        *child_entry = entry.clone();
+                    }
+                    _ => failed = true,
+                }
+                return;
+            }
+
+            // Read-only pages are marked too, so a later mprotect cannot
+            // make a frame still mapped by the other copy writable
+            let frame = PhysFrame::containing_address(entry.addr());
+            let mut flags = entry.flags();
+            if !flags.contains(PTE_SHARED) {
+                flags.remove(PageTableFlags::WRITABLE);
+                flags.insert(PTE_COW);
+                entry.set_flags(flags);
+            }
+
+            if child.map_user_page(page, frame, flags).is_err() {
+                failed = true;
+            } else if !flags.contains(PTE_SHARED) {
+                crate::page::get_page(frame);
+            }
+        });
+
+        // Write access was revoked in the parent
+        if self.is_active() {
+            tlb::flush_all();
+        }
+
+        // Huge pages are copied up front instead of shared copy-on-write
+        let huge_areas: Vec<Vma> = self.vmas.iter().filter(|v| v.huge_pages).copied().collect();
+        for vma in huge_areas {
+            let pages = Page::<Size2MiB>::range(
+                Page::containing_address(vma.start),
+                Page::containing_address(vma.end),
+            );
+            for page in pages {
+                if failed {
+                    break;
+                }
+                let entry = match self.huge_entry_mut(page) {
+                    Some(entry) if entry.flags().contains(PageTableFlags::PRESENT) => entry,
+                    _ => continue,
+                };
+                let flags = entry.flags();
+                let frame = PhysFrame::<Size2MiB>::containing_address(entry.addr());
+
+                if flags.contains(PTE_SHARED) {
+                    failed = child.map_user_page(page, frame, flags).is_err();
+                    continue;
+                }
+
+                let order = buddy::order_of::<Size2MiB>();
+                let copy = match buddy::allocate_frames(order) {
+                    Some(copy) => copy,
+                    None => {
+                        failed = true;
+                        break;
+                    }
+                };
+                unsafe {
+                    let offset = physical_memory_offset();
+                    core::ptr::copy_nonoverlapping(
+                        phys_to_virt(offset, frame.start_address()).as_ptr::<u8>(),
+                        phys_to_virt(offset, copy.start_address()).as_mut_ptr::<u8>(),
+                        Size2MiB::SIZE as usize,
+                    );
+                }
+                let copy = PhysFrame::<Size2MiB>::containing_address(copy.start_address());
+                if child.map_user_page(page, copy, flags).is_err() {
+                    buddy::free_frames(PhysFrame::containing_address(copy.start_address()), order);
+                    failed = true;
+                }
+            }
+        }
+
+        for id in self.shared_segments() {
+            if failed {
+                break;
+            }
+            failed = crate::shm::duplicate(id, self.pid, child.pid).is_err();
+        }
+
+        if failed {
+            None
+        } else {
+            Some(child)
+        }
+    }
+
+    /// Map anonymous private memory, returning its start address
+    ///
+    /// With `fixed` the mapping is placed exactly at `hint`, replacing any
+    /// existing mappings. Otherwise `hint` is used when free and the next
+    /// free gap above it is chosen if not. Writable and executable memory
+    /// needs the JIT permission.
+    pub fn map_anonymous(
+        &mut self,
+        hint: Option<VirtAddr>,
+        len: u64,
+        protection: VmProtection,
+        huge_pages: bool,
+        fixed: bool,
+    ) -> Result<VirtAddr, VmaError> {
+        self.check_write_exec(protection)?;
+        let mut vma = Vma::new(
+            VirtAddr::zero(),
+            VirtAddr::zero(),
+            protection,
+            VmaBacking::Anonymous,
+        );
+        if huge_pages {
+            vma = vma.with_huge_pages();
+        }
+        self.map_area(vma, hint, len, fixed)
+    }
+
+    /// Attach to a shared memory segment and map all of it, returning its
+    /// start address
+    ///
+    /// The segment must allow `protection` to this process, and it can be
+    /// attached only once. Segments created with huge pages are mapped with
+    /// 2 MiB pages.
+    pub fn map_shared_memory(
+        &mut self,
+        id: ShmId,
+        protection: VmProtection,
+    ) -> Result<VirtAddr, ShmError> {
+        self.check_write_exec(protection)?;
+        let (size, page_size, _) = crate::shm::layout(id).ok_or(ShmError::NotFound)?;
+        let address = crate::shm::attach(id, self.pid, protection.into())?;
+        let mut vma = Vma::new(
+            VirtAddr::zero(),
+            VirtAddr::zero(),
+            protection,
+            VmaBacking::SharedMemory { id, offset: 0 },
+        );
+        if page_size == Size2MiB::SIZE {
+            vma = vma.with_huge_pages();
+        }
+        let len = size.next_multiple_of(page_size);
+        self.map_area(vma, Some(address), len, false)
+            .map_err(|err| {
+                let _ = crate::shm::detach(id, self.pid);
+                ShmError::from(err)
+            })
+    }
+
+    /// Unmap every part of a shared memory segment, detaching from it
+    pub fn unmap_shared_memory(&mut self, id: ShmId) -> Result<(), ShmError> {
+        let areas: Vec<(VirtAddr, u64)> = self
+            .vmas
+            .iter()
+            .filter(|vma| vma.backing.shm_id() == Some(id))
+            .map(|vma| (vma.start, vma.end - vma.start))
+            .collect();
+        if areas.is_empty() {
+            return Err(ShmError::NotAttached);
+        }
+        for (start, len) in areas {
+            self.unmap(start, len)?;
+        }
+        Ok(())
+    }
+
+    /// Shared memory segments mapped in this space
+    fn shared_segments(&self) -> Vec<ShmId> {
+        let mut ids: Vec<ShmId> = self
+            .vmas
+            .iter()
+            .filter_map(|v| v.backing.shm_id())
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    /// Place `template` at a suitable address and record it
+    fn map_area(
+        &mut self,
+        template: Vma,
+        hint: Option<VirtAddr>,
+        len: u64,
+        fixed: bool,
+    ) -> Result<VirtAddr, VmaError> {
+        let align = template.page_size();
+        if len == 0 || !len.is_multiple_of(align) {
+            return Err(VmaError::InvalidRange);
+        }
+
+        let start = match hint {
+            Some(addr) if fixed => {
+                if !addr.is_aligned(align) {
+                    return Err(VmaError::InvalidRange);
+                }
+                self.unmap(addr, len)?;
+                addr
+            }
+            Some(addr)
+                if addr.as_u64() >= MMAP_BASE
+                    && addr.is_aligned(align)
+                    && user_range_end(addr, len)
+                        .is_ok_and(|end| !self.vmas.overlaps(addr, end)) =>
+            {
+                addr
+            }
+            _ => self
+                .vmas
+                .find_free(len, align, hint.unwrap_or(self.mmap_base))
+                .ok_or(VmaError::NoSpace)?,
+        };
+
+        let vma = Vma {
+            start,
+            end: start + len,
+            ..template
+        };
+        self.vmas.insert_merged(vma)?;
+        Ok(start)
+    }
+
+    /// Call `f` on every present leaf entry backing `vma`
+    ///
+    /// Each visited page is flushed from the TLB afterwards.
+    fn for_each_entry_in(&mut self, vma: &Vma, mut f: impl FnMut(&mut PageTableEntry)) {
+        let is_active = self.is_active();
+        let mut addr = vma.start;
+
+        while addr < vma.end {
+            let entry = if vma.huge_pages {
+                self.huge_entry_mut(Page::containing_address(addr))
+            } else {
+                self.entry_mut(Page::containing_address(addr))
+            };
+            if let Some(entry) = entry.filter(|e| e.flags().contains(PageTableFlags::PRESENT)) {
+                f(entry);
+                if is_active {
+                    tlb::flush(addr);
+                }
+            }
+            addr += vma.page_size();
+        }
+    }
+
+    /// Unmap `start..start + len`, releasing the frames behind it
+    ///
+    /// Unmapping a range that is not mapped is not an error, but huge pages
+    /// cannot be unmapped partially. Unmapping the last part of a shared
+    /// memory segment detaches from it.
+    pub fn unmap(&mut self, start: VirtAddr, len: u64) -> Result<(), VmaError> {
+        let end = user_range_end(start, len)?;
+        if self.vmas.splits_page(start) || self.vmas.splits_page(end) {
+            return Err(VmaError::InvalidRange);
+        }
+
+        let removed = self.vmas.remove_range(start, end);
+        let mapped = self.shared_segments();
+        let mut unmapped = Vec::new();
+        for vma in removed {
+            let order = buddy::order_of::<Size2MiB>();
+            let mut released = 0;
+            self.for_each_entry_in(&vma, |entry| {
+                let frame = frame_of(entry);
+                let shared = entry.flags().contains(PTE_SHARED);
+                entry.set_unused();
+
+                if shared {
+                    // Owned by the shared memory segment
+                } else if vma.huge_pages {
+                    buddy::free_frames(frame, order);
+                } else {
+                    crate::page::put_page(frame);
+                }
+                released += vma.page_size();
+            });
+            self.resident -= released;
+
+            if !vma.huge_pages {
+                self.release_swap_entries(&vma);
+            }
+            if let Some(id) = vma.backing.shm_id().filter(|id| !mapped.contains(id)) {
+                unmapped.push(id);
+            }
+        }
+
+        // Only once none of the segment's frames are mapped anymore
+        unmapped.dedup();
+        for id in unmapped {
+            let _ = crate::shm::detach(id, self.pid);
+        }
+        Ok(())
+    }
+
+    /// Free the swap slots of pages in `vma` that are swapped out
+    fn release_swap_entries(&mut self, vma: &Vma) {
+        let pages = Page::range(
+            Page::containing_address(vma.start),
+            Page::containing_address(vma.end),
+        );
+        for page in pages {
+            if let Some(entry) = self.entry_mut(page) {
+                if let Some(swap) = SwapEntry::from_pte(entry) {
+                    entry.set_unused();
+                    crate::swap::release(swap);
+                }
+            }
+        }
+    }
+
+    /// Change the protection of `start..start + len`
+    ///
+    /// The whole range must be mapped. Pages still shared copy-on-write stay
+    /// read-only and are copied on the next write as before. Making memory
+    /// writable and executable needs the JIT permission, and shared memory
+    /// cannot get more access than it was attached with.
+    pub fn protect(
+        &mut self,
+        start: VirtAddr,
+        len: u64,
+        protection: VmProtection,
+    ) -> Result<(), VmaError> {
+        let end = user_range_end(start, len)?;
+        if self.vmas.splits_page(start) || self.vmas.splits_page(end) {
+            return Err(VmaError::InvalidRange);
+        }
+        self.check_write_exec(protection)?;
+        let denied = self
+            .vmas
+            .iter()
+            .filter(|vma| vma.start < end && vma.end > start)
+            .filter_map(|vma| vma.backing.shm_id())
+            .any(|id| !crate::shm::check_permission(id, self.pid, protection.into()));
+        if denied {
+            return Err(VmaError::PermissionDenied);
+        }
+        self.vmas.protect_range(start, end, protection)?;
+
+        let kept = PageTableFlags::ACCESSED
+            | PageTableFlags::DIRTY
+            | PageTableFlags::HUGE_PAGE
+            | PTE_SHARED
+            | PTE_COW;
+        let areas: Vec<Vma> = self
+            .vmas
+            .iter()
+            .filter(|vma| vma.start < end && vma.end > start)
+            .copied()
+            .collect();
+
+        for vma in areas {
+            let range = Vma {
+                start: core::cmp::max(vma.start, start),
+                end: core::cmp::min(vma.end, end),
+                ..vma
+            };
+            self.for_each_entry_in(&range, |entry| {
+                let mut flags = vma.page_flags() | (entry.flags() & kept);
+                if flags.contains(PTE_COW) {
+                    flags.remove(PageTableFlags::WRITABLE);
+                }
+                entry.set_flags(flags);
+            });
+        }
+        Ok(())
+    }
+
+    /// Check whether this address space is loaded in CR3
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.pml4
+    }
+
+    /// Load this address space into CR3
+    ///
+    /// # Safety
+    /// The kernel higher half must be mapped identically in this address space
+    pub unsafe fn activate(&self) {
+        if !self.is_active() {
+            Cr3::write(self.pml4, Cr3Flags::empty());
+        }
+    }
+
+    /// Free every user frame and page table in the lower half
+    fn teardown(&mut self) -> usize {
+        let mut freed = 0;
+        unsafe {
+            let table = table_mut(self.pml4);
+            for entry in table.iter_mut().take(KERNEL_PML4_START) {
+                if !entry.is_unused() {
+                    freed += free_table(PhysFrame::containing_address(entry.addr()), 3);
+                    entry.set_unused();
+                }
+            }
+        }
+        freed
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            unsafe { activate_kernel() };
+        }
+
+        let freed = self.teardown();
+        crate::buddy::free_frames(self.pml4, 0);
+        for id in self.shared_segments() {
+            let _ = crate::shm::detach(id, self.pid);
+        }
+        crate::serial_println!(
+            "[VM] Released address space {:#x} ({} frames)",
+            self.pml4.start_address().as_u64(),
+            freed
+        );
+    }
+}
+
+/// Get a page table through the physical memory mapping
+unsafe fn table_mut(frame: PhysFrame) -> &'static mut PageTable {
+    let virt = phys_to_virt(physical_memory_offset(), frame.start_address());
+    &mut *virt.as_mut_ptr::<PageTable>()
+}
+
+/// The bytes of a frame through the physical memory mapping
+unsafe fn frame_bytes(frame: PhysFrame) -> &'static mut [u8] {
+    let virt = phys_to_virt(physical_memory_offset(), frame.start_address());
+    core::slice::from_raw_parts_mut(virt.as_mut_ptr::<u8>(), Size4KiB::SIZE as usize)
+}
+
+/// Validate a page aligned user range and return its end
+fn user_range_end(start: VirtAddr, len: u64) -> Result<VirtAddr, VmaError> {
+    let end = start
+        .as_u64()
+        .checked_add(len)
+        .filter(|&end| len > 0 && end <= USER_SPACE_END)
+        .ok_or(VmaError::InvalidRange)?;
+    if !start.is_aligned(4096u64) || end % 4096 != 0 {
+        return Err(VmaError::InvalidRange);
+    }
+    Ok(VirtAddr::new(end))
+}
+
+fn frame_of(entry: &PageTableEntry) -> PhysFrame {
+    PhysFrame::containing_address(entry.addr())
+}
+
+/// Free a page table and everything below it, returning the frames freed
+unsafe fn free_table(frame: PhysFrame, level: usize) -> usize {
+    let mut freed = 0;
+
+    for entry in table_mut(frame).iter_mut() {
+        if entry.is_unused() {
+            continue;
+        }
+
+        let child = PhysFrame::containing_address(entry.addr());
+        if let Some(swap) = SwapEntry::from_pte(entry) {
+            crate::swap::release(swap);
+        } else if entry.flags().contains(PTE_SHARED) {
+            // Owned by someone else, e.g. a shared memory segment
+        } else if level == 1 {
+            if crate::page::put_page(child) {
+                freed += 1;
+            }
+        } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            // 2 MiB pages live in level 2 tables, 1 GiB pages in level 3
+            let order = (level - 1) * 9;
+            crate::buddy::free_frames(child, order);
+            freed += 1 << order;
+        } else {
+            freed += free_table(child, level - 1);
+        }
+        entry.set_unused();
+    }
+
+    crate::buddy::free_frames(frame, 0);
+    freed + 1
+}
+
+/// Switch back to the kernel PML4
+///
+/// # Safety
+/// Must not be called while user mappings of the current space are in use
+pub unsafe fn activate_kernel() {
+    if let Some(pml4) = *KERNEL_PML4.lock() {
+        if Cr3::read().0 != pml4 {
+            Cr3::write(pml4, Cr3Flags::empty());
+        }
+    }
+}
+
+/// Record the boot PML4 as the kernel address space
+pub fn init() {
+    let (pml4, _) = Cr3::read();
+    *KERNEL_PML4.lock() = Some(pml4);
+    crate::serial_println!("[VM] Kernel PML4 at {:#x}", pml4.start_address().as_u64());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{frame_bytes, frame_of, AddressSpace, PTE_COW};
+    use crate::process::Pid;
+    use crate::rlimit::RLimit;
+    use crate::vma::VmProtection;
+    use crate::{serial_print, serial_println};
+    use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+    use x86_64::VirtAddr;
+
+    /// Process the test address spaces are built for
+    const TEST_PID: Pid = 1000;
+
+    /// Frame and flags behind `addr`
+    fn lookup(space: &mut AddressSpace, addr: VirtAddr) -> (PhysFrame, PageTableFlags) {
+        let entry = space.entry_mut(Page::containing_address(addr)).unwrap();
+        (frame_of(entry), entry.flags())
+    }
+
+    #[test_case]
+    fn test_fork_then_mprotect_keeps_cow() {
+        serial_print!("test_fork_then_mprotect_keeps_cow... ");
+        let mut parent = AddressSpace::new(TEST_PID).unwrap();
+        let addr = parent
+            .map_anonymous(None, 4096, VmProtection::READ, false, false)
+            .unwrap();
+        parent.populate(addr, b"parent").unwrap();
+
+        let mut child = parent.fork(TEST_PID + 1).unwrap();
+        let (shared, flags) = lookup(&mut parent, addr);
+        assert!(flags.contains(PTE_COW));
+        assert_eq!(lookup(&mut child, addr).0, shared);
+
+        // Making the page writable must not hand out the shared frame
+        let protection = VmProtection::READ | VmProtection::WRITE;
+        parent.protect(addr, 4096, protection).unwrap();
+        let (_, flags) = lookup(&mut parent, addr);
+        assert!(flags.contains(PTE_COW));
+        assert!(!flags.contains(PageTableFlags::WRITABLE));
+
+        // The write fault copies the page before the parent modifies it
+        let page = Page::containing_address(addr);
+        crate::cow::handle_cow_fault(&mut parent, page, 0, RLimit::UNLIMITED).unwrap();
+        let (copy, flags) = lookup(&mut parent, addr);
+        assert_ne!(copy, shared);
+        assert!(flags.contains(PageTableFlags::WRITABLE));
+        unsafe { frame_bytes(copy)[..6].copy_from_slice(b"scrawl") };
+        assert_eq!(unsafe { &frame_bytes(shared)[..6] }, b"parent");
+        assert_eq!(lookup(&mut child, addr).0, shared);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_prot_none_pages_are_not_user_accessible() {
+        serial_print!("test_prot_none_pages_are_not_user_accessible... ");
+        let mut space = AddressSpace::new(TEST_PID).unwrap();
+        let protection = VmProtection::READ | VmProtection::WRITE;
+        let addr = space
+            .map_anonymous(None, 4096, protection, false, false)
+            .unwrap();
+        space.populate(addr, b"data").unwrap();
+        let end = addr + 4096u64;
+
+        space.protect(addr, 4096, VmProtection::empty()).unwrap();
+        let (_, flags) = lookup(&mut space, addr);
+        assert!(flags.contains(PageTableFlags::PRESENT));
+        assert!(!flags.contains(PageTableFlags::USER_ACCESSIBLE));
+        assert!(!space.vmas.allows_range(addr, end, VmProtection::READ));
+
+        // Write without read cannot be expressed in a page table either
+        space.protect(addr, 4096, VmProtection::WRITE).unwrap();
+        assert!(!space.vmas.allows_range(addr, end, VmProtection::WRITE));
+
+        space.protect(addr, 4096, VmProtection::READ).unwrap();
+        let (_, flags) = lookup(&mut space, addr);
+        assert!(flags.contains(PageTableFlags::USER_ACCESSIBLE));
+        assert!(space.vmas.allows_range(addr, end, VmProtection::READ));
+        serial_println!("[ok]");
+    }
+}
@@ -1,19 +1,35 @@
+use crate::buddy::GlobalFrameAllocator;
+use crate::slab::SlabAllocator;
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
 use spin::Mutex;
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB, Translate,
     },
     VirtAddr,
 };
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MiB
+pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MiB mapped at boot
+pub const HEAP_MAX_SIZE: usize = 256 * 1024 * 1024; // 256 MiB virtual window
 
-/// A simple linked list allocator
+/// Minimum amount the heap grows by when it runs dry
+const HEAP_GROW_STEP: usize = 64 * 1024;
+
+/// Maps more heap for an allocator that ran dry
+///
+/// Receives the number of bytes needed and returns a freshly mapped region
+/// as `(start, size)`, or `None` if the heap cannot grow.
+pub type GrowFn = fn(usize) -> Option<(usize, usize)>;
+
+/// A linked list allocator with an address-ordered free list
+///
+/// Every block handed out is a multiple of `BLOCK_ALIGN` bytes and starts on
+/// a `BLOCK_ALIGN` boundary, so any leftover piece of a free region is large
+/// enough to hold a `Node`. Freed blocks are merged with their neighbours.
 pub struct LinkedListAllocator {
-    head: Mutex<Option<&'static mut Node>>,
+    head: Mutex<Node>,
+    grow: Option<GrowFn>,
 }
 
 struct Node {
@@ -25,12 +41,32 @@ impl Node {
     const fn new(size: usize) -> Self {
         Node { size, next: None }
     }
+
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
 }
 
+/// Granularity of every block, large enough to hold a free list node
+const BLOCK_ALIGN: usize = core::mem::size_of::<Node>().next_power_of_two();
+
 impl LinkedListAllocator {
     pub const fn new() -> Self {
         LinkedListAllocator {
-            head: Mutex::new(None),
+            head: Mutex::new(Node::new(0)),
+            grow: None,
+        }
+    }
+
+    /// Create an allocator that calls `grow` when it runs out of memory
+    pub const fn with_grow(grow: GrowFn) -> Self {
+        LinkedListAllocator {
+            head: Mutex::new(Node::new(0)),
+            grow: Some(grow),
         }
     }
 
@@ -39,130 +75,321 @@ impl LinkedListAllocator {
     /// # Safety
     /// This function must be called only once and the heap bounds must be valid
     pub unsafe fn init(&self, heap_start: usize, heap_size: usize) {
-        let mut head = self.head.lock();
-        *head = Some(&mut *(heap_start as *mut Node));
-        if let Some(node) = head.as_mut() {
-            node.size = heap_size;
-            node.next = None;
-        }
+        let start = align_up(heap_start, BLOCK_ALIGN);
+        let end = (heap_start + heap_size) & !(BLOCK_ALIGN - 1);
+        Self::add_free_region(&mut self.head.lock(), start, end - start);
     }
 
-    fn alloc_from_region(region: &Node, layout: Layout) -> Option<(*mut u8, &'static mut Node)> {
-        let alloc_start = align_up(
-            region as *const Node as usize + core::mem::size_of::<Node>(),
-            layout.align(),
-        );
-        let alloc_end = alloc_start.checked_add(layout.size())?;
+    /// Round a layout up to the block granularity
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let align = core::cmp::max(layout.align(), BLOCK_ALIGN);
+        let size = align_up(core::cmp::max(layout.size(), BLOCK_ALIGN), BLOCK_ALIGN);
+        (size, align)
+    }
 
-        let region_start = region as *const Node as usize;
-        let region_end = region_start.checked_add(region.size)?;
+    /// Insert a free region in address order, merging it with its neighbours
+    unsafe fn add_free_region(head: &mut Node, addr: usize, size: usize) {
+        if size == 0 {
+            return;
+        }
 
-        if alloc_end > region_end {
-            return None;
+        // Find the last node below the new region
+        let mut prev = head;
+        while prev
+            .next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() < addr)
+        {
+            prev = prev.next.as_mut().unwrap();
+        }
+
+        let node_ptr = addr as *mut Node;
+        node_ptr.write(Node::new(size));
+        let node = &mut *node_ptr;
+
+        // Merge with the following region
+        node.next = match prev.next.take() {
+            Some(next) if node.end_addr() == next.start_addr() => {
+                node.size += next.size;
+                next.next.take()
+            }
+            next => next,
+        };
+
+        // Merge with the preceding region (the list head has size 0)
+        if prev.size != 0 && prev.end_addr() == addr {
+            prev.size += node.size;
+            prev.next = node.next.take();
+        } else {
+            prev.next = Some(node);
         }
+    }
 
-        let excess_size = region_end - alloc_end;
-        if excess_size > 0 && excess_size < core::mem::size_of::<Node>() {
+    /// Find where an allocation would start inside a free region
+    fn alloc_from_region(region: &Node, size: usize, align: usize) -> Option<usize> {
+        let alloc_start = align_up(region.start_addr(), align);
+        let alloc_end = alloc_start.checked_add(size)?;
+
+        if alloc_end > region.end_addr() {
             return None;
         }
 
-        Some((alloc_start as *mut u8, unsafe {
-            &mut *(region_start as *mut Node)
-        }))
+        Some(alloc_start)
     }
-}
 
-unsafe impl GlobalAlloc for LinkedListAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut head = self.head.lock();
-        let mut current_ptr = head.as_deref_mut().map(|n| n as *mut Node);
-        let mut prev_ptr: Option<*mut Node> = None;
-
-        while let Some(region_ptr) = current_ptr {
-            let region = &mut *region_ptr;
-
-            if let Some((alloc_start, _)) = Self::alloc_from_region(region, layout) {
-                let alloc_end = alloc_start as usize + layout.size();
-                let region_start = region_ptr as usize;
-                let region_end = region_start + region.size;
-
-                if alloc_end < region_end {
-                    let new_node = &mut *((alloc_end) as *mut Node);
-                    new_node.size = region_end - alloc_end;
-                    new_node.next = region.next.take();
-
-                    if let Some(prev) = prev_ptr {
-                        (*prev).next = Some(new_node);
-                    } else {
-                        *head = Some(new_node);
-                    }
-                } else {
-                    if let Some(prev) = prev_ptr {
-                        (*prev).next = region.next.take();
-                    } else {
-                        *head = region.next.take();
-                    }
-                }
-
-                return alloc_start;
+    /// Take the first free region that fits
+    unsafe fn first_fit(head: &mut Node, size: usize, align: usize) -> *mut u8 {
+        let mut current = &mut *head;
+
+        while let Some(ref mut region) = current.next {
+            if let Some(alloc_start) = Self::alloc_from_region(region, size, align) {
+                let (region_start, region_end) = (region.start_addr(), region.end_addr());
+                let next = region.next.take();
+                current.next = next;
+
+                // Return the unused front and back of the region
+                Self::add_free_region(head, region_start, alloc_start - region_start);
+                Self::add_free_region(head, alloc_start + size, region_end - alloc_start - size);
+
+                return alloc_start as *mut u8;
             }
 
-            prev_ptr = Some(region_ptr);
-            current_ptr = region.next.as_deref_mut().map(|n| n as *mut Node);
+            current = current.next.as_mut().unwrap();
         }
 
         null_mut()
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let new_node = &mut *(ptr as *mut Node);
-        new_node.size = layout.size();
-        new_node.next = None;
+    /// Try to resize an allocation without moving it
+    ///
+    /// Shrinking always succeeds. Growing succeeds when the block directly
+    /// after the allocation is free and large enough.
+    unsafe fn realloc_in_place(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+        let (old_size, _) = Self::size_align(layout);
+        let (new_size, _) =
+            Self::size_align(Layout::from_size_align_unchecked(new_size, layout.align()));
+        let block_end = ptr as usize + old_size;
+        let mut head = self.head.lock();
+
+        if new_size <= old_size {
+            Self::add_free_region(&mut head, ptr as usize + new_size, old_size - new_size);
+            return true;
+        }
+
+        let needed = new_size - old_size;
+        let mut prev = &mut *head;
+        while prev
+            .next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() < block_end)
+        {
+            prev = prev.next.as_mut().unwrap();
+        }
 
+        let next = match prev.next.as_mut() {
+            Some(next) if next.start_addr() == block_end && next.size >= needed => next,
+            _ => return false,
+        };
+
+        let remaining = next.size - needed;
+        let after = next.next.take();
+        if remaining == 0 {
+            prev.next = after;
+        } else {
+            let moved = (block_end + needed) as *mut Node;
+            moved.write(Node::new(remaining));
+            (*moved).next = after;
+            prev.next = Some(&mut *moved);
+        }
+
+        true
+    }
+}
+
+unsafe impl GlobalAlloc for LinkedListAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::size_align(layout);
         let mut head = self.head.lock();
 
-        if head.is_none() {
-            *head = Some(new_node);
-            return;
+        let ptr = Self::first_fit(&mut head, size, align);
+        if !ptr.is_null() {
+            return ptr;
         }
 
-        let mut current = head.as_mut();
-        while let Some(node) = current {
-            if node.next.is_none() {
-                node.next = Some(new_node);
-                return;
+        // Ask for more memory and retry once
+        match self.grow.and_then(|grow| grow(size + align)) {
+            Some((start, len)) => {
+                Self::add_free_region(&mut head, start, len);
+                Self::first_fit(&mut head, size, align)
             }
-            current = node.next.as_mut();
+            None => null_mut(),
         }
     }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::size_align(layout);
+        Self::add_free_region(&mut self.head.lock(), ptr as usize, size);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if self.realloc_in_place(ptr, layout, new_size) {
+            return ptr;
+        }
+
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            core::ptr::copy_nonoverlapping(ptr, new_ptr, core::cmp::min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
 }
 
 #[global_allocator]
-static ALLOCATOR: LinkedListAllocator = LinkedListAllocator::new();
+pub(crate) static ALLOCATOR: SlabAllocator =
+    SlabAllocator::new(LinkedListAllocator::with_grow(grow_heap));
 
-/// Initialize the heap
-pub fn init_heap(
+/// Heap mapping state
+struct HeapState {
+    start: usize,
+    mapped_end: usize,
+    limit: usize,
+}
+
+static HEAP: Mutex<HeapState> = Mutex::new(HeapState {
+    start: 0,
+    mapped_end: 0,
+    limit: HEAP_MAX_SIZE,
+});
+
+/// Map `size` bytes of heap at `start`, returning how many bytes got mapped
+fn map_heap_range(
+    start: usize,
+    size: usize,
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
+) -> Result<usize, MapToError<Size4KiB>> {
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start = VirtAddr::new(start as u64);
+        let heap_end = heap_start + size - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
     };
 
+    let mut mapped = 0;
     for page in page_range {
         let frame = frame_allocator
             .allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        let flags = crate::memory::page_flags(
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        );
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                // Heap frames always come from the buddy allocator
+                crate::buddy::free_frames(frame, 0);
+                return Err(err);
+            }
+        }
+        mapped += page.size() as usize;
+    }
+
+    Ok(mapped)
+}
+
+/// Map more of the heap window, called by the allocator when it runs dry
+///
+/// Must not allocate from the heap: it runs with the heap lock held.
+fn grow_heap(min_size: usize) -> Option<(usize, usize)> {
+    let mut heap = HEAP.lock();
+    let start = heap.mapped_end;
+    let size = align_up(core::cmp::max(min_size, HEAP_GROW_STEP), 4096);
+    let size = core::cmp::min(size, heap.start + heap.limit - start);
+    if size < min_size {
+        return None;
+    }
+
+    let mut mapper = crate::memory::KERNEL_MAPPER.lock();
+    let mapper = mapper.as_mut()?;
+
+    // Keep whatever got mapped even if frames ran out part way through
+    let mapped = match map_heap_range(start, size, mapper, &mut GlobalFrameAllocator) {
+        Ok(mapped) => mapped,
+        Err(_) => {
+            let mapped = (start..start + size)
+                .step_by(4096)
+                .take_while(|&addr| mapper.translate_addr(VirtAddr::new(addr as u64)).is_some())
+                .count()
+                * 4096;
+            if mapped == 0 {
+                return None;
+            }
+            mapped
+        }
+    };
+
+    heap.mapped_end += mapped;
+    crate::serial_println!(
+        "[HEAP] Grew heap by {} KB to {} KB",
+        mapped / 1024,
+        (heap.mapped_end - heap.start) / 1024
+    );
+    Some((start, mapped))
+}
+
+/// Set the upper bound the heap may grow to
+///
+/// The limit is clamped to the reserved window and never below what is
+/// already mapped. Boot sets it from `heapmax=` (e.g. `heapmax=64M`).
+pub fn set_heap_limit(limit: usize) {
+    let mut heap = HEAP.lock();
+    let mapped = heap.mapped_end - heap.start;
+    heap.limit = limit.clamp(mapped, HEAP_MAX_SIZE);
+}
+
+/// Get the currently mapped heap size in bytes
+pub fn heap_size() -> usize {
+    let heap = HEAP.lock();
+    heap.mapped_end - heap.start
+}
+
+/// Get the upper bound the heap may grow to in bytes
+pub fn heap_limit() -> usize {
+    HEAP.lock().limit
+}
+
+/// Start of the heap window, placed at boot by `kaslr`
+pub fn heap_start() -> usize {
+    crate::kaslr::heap_base() as usize
+}
+
+/// Initialize the heap
+pub fn init_heap(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let start = heap_start();
+    map_heap_range(start, HEAP_SIZE, mapper, frame_allocator)?;
+    {
+        let mut heap = HEAP.lock();
+        heap.start = start;
+        heap.mapped_end = start + HEAP_SIZE;
     }
 
     unsafe {
-        ALLOCATOR.init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.init(start, HEAP_SIZE);
+    }
+
+    if let Some(value) = crate::cmdline::value("heapmax") {
+        match crate::cmdline::parse_size(value) {
+            Some(limit) => set_heap_limit(limit),
+            None => {
+                crate::serial_println!("[HEAP] Ignoring bad heapmax={}", value);
+            }
+        }
     }
 
     Ok(())
@@ -171,3 +398,117 @@ pub fn init_heap(
 fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{LinkedListAllocator, HEAP_MAX_SIZE};
+    use crate::{serial_print, serial_println, slab};
+    use alloc::alloc::{GlobalAlloc, Layout};
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    const ARENA_SIZE: usize = 32 * 1024;
+
+    #[repr(align(4096))]
+    struct Arena([u8; ARENA_SIZE]);
+
+    static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+    /// `(start, size)` of every free region, in list order
+    fn free_regions(allocator: &LinkedListAllocator) -> Vec<(usize, usize)> {
+        let head = allocator.head.lock();
+        let mut regions = Vec::new();
+        let mut node = head.next.as_deref();
+        while let Some(region) = node {
+            regions.push((region.start_addr(), region.size));
+            node = region.next.as_deref();
+        }
+        regions
+    }
+
+    #[test_case]
+    fn test_heap_allocation() {
+        serial_print!("test_heap_allocation... ");
+        let heap_value = Box::new(42);
+        assert_eq!(*heap_value, 42);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_vec_allocation() {
+        serial_print!("test_vec_allocation... ");
+        let mut vec = Vec::new();
+        for i in 0..100 {
+            vec.push(i);
+        }
+        assert_eq!(vec.len(), 100);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_slab_cache_accounting() {
+        serial_print!("test_slab_cache_accounting... ");
+        let in_use = |size: usize| {
+            slab::stats()
+                .iter()
+                .find(|c| c.object_size == size)
+                .map(|c| c.objects_in_use)
+                .unwrap()
+        };
+
+        let before = in_use(128);
+        let value = Box::new([0u8; 100]);
+        assert_eq!(in_use(128), before + 1);
+        drop(value);
+        assert_eq!(in_use(128), before);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_large_realloc_keeps_contents() {
+        serial_print!("test_large_realloc_keeps_contents... ");
+        let mut vec: Vec<u8> = Vec::with_capacity(8192);
+        vec.extend((0..8192).map(|i| i as u8));
+        vec.reserve(16384);
+        assert!(vec.iter().enumerate().all(|(i, &b)| b == i as u8));
+        vec.shrink_to_fit();
+        assert_eq!(vec.len(), 8192);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_free_blocks_coalesce() {
+        serial_print!("test_free_blocks_coalesce... ");
+        // A private allocator that cannot grow, so the full-size allocation
+        // at the end only fits if every freed neighbour was merged
+        let allocator = LinkedListAllocator::new();
+        let start = unsafe { core::ptr::addr_of_mut!(ARENA.0) } as usize;
+        unsafe { allocator.init(start, ARENA_SIZE) };
+        assert_eq!(free_regions(&allocator), [(start, ARENA_SIZE)]);
+
+        let layout = Layout::from_size_align(ARENA_SIZE / 8, 8).unwrap();
+        let blocks: Vec<*mut u8> = (0..8).map(|_| unsafe { allocator.alloc(layout) }).collect();
+        assert!(blocks.iter().all(|block| !block.is_null()));
+        assert!(free_regions(&allocator).is_empty());
+
+        // Free out of order so merges happen on both sides
+        for &i in &[1, 3, 5, 7, 0, 2, 6, 4] {
+            unsafe { allocator.dealloc(blocks[i], layout) };
+        }
+        assert_eq!(free_regions(&allocator), [(start, ARENA_SIZE)]);
+
+        let full = Layout::from_size_align(ARENA_SIZE, 8).unwrap();
+        let large = unsafe { allocator.alloc(full) };
+        assert_eq!(large as usize, start);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_try_reserve_reports_exhaustion() {
+        serial_print!("test_try_reserve_reports_exhaustion... ");
+        let mut vec: Vec<u8> = Vec::new();
+        assert!(vec.try_reserve(HEAP_MAX_SIZE * 2).is_err());
+        assert!(vec.try_reserve(1024).is_ok());
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,219 @@
+use x86_64::instructions::port::Port;
+
+/// Size of one sector in bytes
+pub const SECTOR_SIZE: usize = 512;
+
+/// Block device errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request runs past the end of the device
+    OutOfRange,
+    /// The buffer is not a whole number of sectors
+    BadBuffer,
+    /// The device reported an error
+    Io,
+}
+
+/// A device storing data in fixed size sectors
+pub trait BlockDevice: Send {
+    /// Number of sectors on the device
+    fn sector_count(&self) -> u64;
+
+    /// Read `buf.len() / SECTOR_SIZE` sectors starting at `sector`
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write `buf.len() / SECTOR_SIZE` sectors starting at `sector`
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), BlockError>;
+}
+
+/// Check that `len` bytes starting at `sector` fit on a device
+fn check_request(device: &dyn BlockDevice, sector: u64, len: usize) -> Result<u64, BlockError> {
+    if !len.is_multiple_of(SECTOR_SIZE) {
+        return Err(BlockError::BadBuffer);
+    }
+    let count = (len / SECTOR_SIZE) as u64;
+    match sector.checked_add(count) {
+        Some(end) if end <= device.sector_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+const ATA_STATUS_ERR: u8 = 1 << 0;
+const ATA_STATUS_DRQ: u8 = 1 << 3;
+const ATA_STATUS_DF: u8 = 1 << 5;
+const ATA_STATUS_BSY: u8 = 1 << 7;
+
+const ATA_CMD_READ_SECTORS: u8 = 0x20;
+const ATA_CMD_WRITE_SECTORS: u8 = 0x30;
+const ATA_CMD_CACHE_FLUSH: u8 = 0xE7;
+const ATA_CMD_IDENTIFY: u8 = 0xEC;
+
+/// Highest sector reachable with 28-bit LBA
+const ATA_LBA28_MAX: u64 = 1 << 28;
+
+/// I/O ports of an ATA bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaBus {
+    Primary,
+    Secondary,
+}
+
+impl AtaBus {
+    fn io_base(&self) -> u16 {
+        match self {
+            AtaBus::Primary => 0x1F0,
+            AtaBus::Secondary => 0x170,
+        }
+    }
+}
+
+/// An ATA disk driven with polled PIO and 28-bit LBA
+pub struct AtaPio {
+    bus: AtaBus,
+    slave: bool,
+    sectors: u64,
+}
+
+impl AtaPio {
+    /// Identify the disk at `bus`/`slave`, if there is one
+    pub fn probe(bus: AtaBus, slave: bool) -> Option<Self> {
+        let mut disk = AtaPio {
+            bus,
+            slave,
+            sectors: 0,
+        };
+
+        unsafe {
+            // No drive pulls the status lines high
+            if disk.status() == 0xFF {
+                return None;
+            }
+
+            disk.select(0);
+            disk.port(2).write(0u8);
+            disk.port(3).write(0u8);
+            disk.port(4).write(0u8);
+            disk.port(5).write(0u8);
+            disk.port(7).write(ATA_CMD_IDENTIFY);
+            if disk.status() == 0 {
+                return None;
+            }
+
+            while disk.status() & ATA_STATUS_BSY != 0 {}
+            // ATAPI and SATA devices set the LBA mid/high signature
+            if disk.port(4).read() != 0 || disk.port(5).read() != 0 {
+                return None;
+            }
+            disk.wait_drq().ok()?;
+
+            let mut identify = [0u16; 256];
+            for word in identify.iter_mut() {
+                *word = disk.port16(0).read();
+            }
+            disk.sectors = identify[60] as u64 | (identify[61] as u64) << 16;
+        }
+
+        (disk.sectors > 0).then_some(disk)
+    }
+
+    fn port(&self, offset: u16) -> Port<u8> {
+        Port::new(self.bus.io_base() + offset)
+    }
+
+    fn port16(&self, offset: u16) -> Port<u16> {
+        Port::new(self.bus.io_base() + offset)
+    }
+
+    unsafe fn status(&self) -> u8 {
+        self.port(7).read()
+    }
+
+    /// Select the drive and the top four bits of `lba`
+    unsafe fn select(&self, lba: u64) {
+        let drive = if self.slave { 0xF0 } else { 0xE0 };
+        self.port(6).write(drive | ((lba >> 24) & 0x0F) as u8);
+        // Give the drive 400ns to respond
+        for _ in 0..4 {
+            self.status();
+        }
+    }
+
+    /// Wait until the drive is ready to transfer a sector
+    unsafe fn wait_drq(&self) -> Result<(), BlockError> {
+        loop {
+            let status = self.status();
+            if status & ATA_STATUS_BSY != 0 {
+                continue;
+            }
+            if status & (ATA_STATUS_ERR | ATA_STATUS_DF) != 0 {
+                return Err(BlockError::Io);
+            }
+            if status & ATA_STATUS_DRQ != 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Issue a command for `count` sectors starting at `lba`
+    unsafe fn command(&self, command: u8, lba: u64, count: u8) {
+        self.select(lba);
+        self.port(2).write(count);
+        self.port(3).write(lba as u8);
+        self.port(4).write((lba >> 8) as u8);
+        self.port(5).write((lba >> 16) as u8);
+        self.port(7).write(command);
+    }
+}
+
+impl BlockDevice for AtaPio {
+    fn sector_count(&self) -> u64 {
+        core::cmp::min(self.sectors, ATA_LBA28_MAX)
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buf.len())?;
+
+        for (lba, chunk) in (sector..)
+            .step_by(255)
+            .zip(buf.chunks_mut(255 * SECTOR_SIZE))
+        {
+            unsafe {
+                self.command(ATA_CMD_READ_SECTORS, lba, (chunk.len() / SECTOR_SIZE) as u8);
+                for sector in chunk.chunks_mut(SECTOR_SIZE) {
+                    self.wait_drq()?;
+                    for bytes in sector.chunks_mut(2) {
+                        bytes.copy_from_slice(&self.port16(0).read().to_le_bytes());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buf.len())?;
+
+        for (lba, chunk) in (sector..).step_by(255).zip(buf.chunks(255 * SECTOR_SIZE)) {
+            unsafe {
+                self.command(
+                    ATA_CMD_WRITE_SECTORS,
+                    lba,
+                    (chunk.len() / SECTOR_SIZE) as u8,
+                );
+                for sector in chunk.chunks(SECTOR_SIZE) {
+                    self.wait_drq()?;
+                    for bytes in sector.chunks(2) {
+                        self.port16(0)
+                            .write(u16::from_le_bytes([bytes[0], bytes[1]]));
+                    }
+                }
+            }
+        }
+
+        unsafe {
+            self.port(7).write(ATA_CMD_CACHE_FLUSH);
+            while self.status() & ATA_STATUS_BSY != 0 {}
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,446 @@
+use crate::memmap::{MemoryMap, MemoryRegionKind};
+use crate::memory::{phys_to_virt, PhysicalMemoryManager};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+/// Largest block order (2^18 frames = 1 GiB)
+pub const MAX_ORDER: usize = 18;
+
+const ORDERS: usize = MAX_ORDER + 1;
+const FRAME_SIZE: u64 = 4096;
+const NONE: u64 = u64::MAX;
+
+/// Free list link stored in the first bytes of every free block
+#[repr(C)]
+struct FreeBlock {
+    next: u64,
+    prev: u64,
+}
+
+/// Buddy-system physical frame allocator
+///
+/// Free blocks of each order are kept on an intrusive doubly linked list
+/// threaded through the blocks themselves. One bit per block and order
+/// records whether the block is currently on a free list, so the buddy of
+/// a freed block can be found and unlinked in constant time.
+pub struct BuddyAllocator {
+    free_lists: [u64; ORDERS],
+    free_counts: [usize; ORDERS],
+    bitmap: &'static mut [u64],
+    bitmap_offsets: [usize; ORDERS],
+    total_frames: u64,
+    free_frames: u64,
+    physical_memory_offset: VirtAddr,
+    /// Firmware memory map, to refuse frees of memory that is not RAM
+    memory_map: MemoryMap,
+}
+
+impl BuddyAllocator {
+    /// Create an empty buddy allocator
+    pub const fn new() -> Self {
+        Self {
+            free_lists: [NONE; ORDERS],
+            free_counts: [0; ORDERS],
+            bitmap: &mut [],
+            bitmap_offsets: [0; ORDERS],
+            total_frames: 0,
+            free_frames: 0,
+            physical_memory_offset: VirtAddr::zero(),
+            memory_map: MemoryMap::new(),
+        }
+    }
+
+    /// Bitmap words needed to track `frames` frames at every order
+    fn bitmap_words(frames: u64) -> usize {
+        (0..ORDERS)
+            .map(|order| (((frames >> order) + 64) / 64) as usize)
+            .sum()
+    }
+
+    /// Take over every free frame from the boot physical memory manager
+    ///
+    /// # Safety
+    /// Physical memory must be mapped at `physical_memory_offset` and must
+    /// only be initialized once
+    pub unsafe fn init(
+        &mut self,
+        pmm: &mut PhysicalMemoryManager,
+        physical_memory_offset: VirtAddr,
+    ) -> Option<()> {
+        let frames = pmm.memory_map().max_physical_address().as_u64() / FRAME_SIZE;
+        let words = Self::bitmap_words(frames);
+        let bitmap_frame = pmm.allocate_contiguous((words * 8).div_ceil(FRAME_SIZE as usize))?;
+        let bitmap_addr = phys_to_virt(physical_memory_offset, bitmap_frame.start_address());
+
+        self.bitmap = core::slice::from_raw_parts_mut(bitmap_addr.as_mut_ptr::<u64>(), words);
+        self.bitmap.fill(0);
+
+        let mut offset = 0;
+        for order in 0..ORDERS {
+            self.bitmap_offsets[order] = offset;
+            offset += (((frames >> order) + 64) / 64) as usize;
+        }
+
+        self.total_frames = frames;
+        self.physical_memory_offset = physical_memory_offset;
+        self.memory_map = pmm.memory_map().clone();
+
+        pmm.drain_free_ranges(|start, end| {
+            self.add_range(start.as_u64() / FRAME_SIZE, end.as_u64() / FRAME_SIZE);
+        });
+
+        Some(())
+    }
+
+    /// Add the frames `start_pfn..end_pfn` as maximal aligned free blocks
+    fn add_range(&mut self, mut start_pfn: u64, end_pfn: u64) {
+        while start_pfn < end_pfn {
+            let mut order = MAX_ORDER;
+            while order > 0
+                && (start_pfn & ((1 << order) - 1) != 0 || start_pfn + (1 << order) > end_pfn)
+            {
+                order -= 1;
+            }
+
+            crate::page::on_free(start_pfn, order);
+            self.push(start_pfn, order);
+            self.free_frames += 1 << order;
+            start_pfn += 1 << order;
+        }
+    }
+
+    fn bit(&self, pfn: u64, order: usize) -> (usize, u64) {
+        let index = (pfn >> order) as usize;
+        (self.bitmap_offsets[order] + index / 64, 1 << (index % 64))
+    }
+
+    fn is_free(&self, pfn: u64, order: usize) -> bool {
+        let (word, mask) = self.bit(pfn, order);
+        self.bitmap[word] & mask != 0
+    }
+
+    /// Check whether any frame of `pfn..pfn + 2^order` is already free
+    ///
+    /// A free block at this order or above can only cover the range as a
+    /// whole, possibly merged since; smaller ones may sit anywhere inside.
+    fn overlaps_free(&self, pfn: u64, order: usize) -> bool {
+        let covered = (order..ORDERS).any(|o| self.is_free(pfn & !((1 << o) - 1), o));
+        covered
+            || (0..order).any(|o| {
+                (pfn..pfn + (1 << order))
+                    .step_by(1 << o)
+                    .any(|start| self.is_free(start, o))
+            })
+    }
+
+    /// Check whether the frames `pfn..pfn + 2^order` are all usable RAM
+    fn is_usable(&self, pfn: u64, order: usize) -> bool {
+        let (start, end) = (pfn * FRAME_SIZE, (pfn + (1 << order)) * FRAME_SIZE);
+        self.memory_map
+            .regions_of(MemoryRegionKind::Usable)
+            .any(|r| r.start.as_u64() <= start && end <= r.end.as_u64())
+    }
+
+    fn set_free(&mut self, pfn: u64, order: usize, free: bool) {
+        let (word, mask) = self.bit(pfn, order);
+        if free {
+            self.bitmap[word] |= mask;
+        } else {
+            self.bitmap[word] &= !mask;
+        }
+    }
+
+    fn block(&self, pfn: u64) -> &'static mut FreeBlock {
+        let addr = phys_to_virt(self.physical_memory_offset, PhysAddr::new(pfn * FRAME_SIZE));
+        unsafe { &mut *addr.as_mut_ptr::<FreeBlock>() }
+    }
+
+    /// Push a block onto the free list of `order`
+    fn push(&mut self, pfn: u64, order: usize) {
+        let head = self.free_lists[order];
+        let block = self.block(pfn);
+        block.next = head;
+        block.prev = NONE;
+        if head != NONE {
+            self.block(head).prev = pfn;
+        }
+
+        self.free_lists[order] = pfn;
+        self.free_counts[order] += 1;
+        self.set_free(pfn, order, true);
+    }
+
+    /// Unlink a block from the free list of `order`
+    fn remove(&mut self, pfn: u64, order: usize) {
+        let block = self.block(pfn);
+        let (next, prev) = (block.next, block.prev);
+
+        if prev != NONE {
+            self.block(prev).next = next;
+        } else {
+            self.free_lists[order] = next;
+        }
+        if next != NONE {
+            self.block(next).prev = prev;
+        }
+
+        self.free_counts[order] -= 1;
+        self.set_free(pfn, order, false);
+    }
+
+    /// Allocate 2^order physically contiguous, naturally aligned frames
+    pub fn allocate_frames(&mut self, order: usize) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        let mut current = (order..ORDERS).find(|&o| self.free_lists[o] != NONE)?;
+        let pfn = self.free_lists[current];
+        self.remove(pfn, current);
+
+        // Split down to the requested order, freeing the upper halves
+        while current > order {
+            current -= 1;
+            self.push(pfn + (1 << current), current);
+        }
+
+        self.free_frames -= 1 << order;
+        crate::page::on_allocate(pfn, order);
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            pfn * FRAME_SIZE,
+        )))
+    }
+
+    /// Free 2^order frames starting at `frame`, merging with free buddies
+    pub fn free_frames(&mut self, frame: PhysFrame, order: usize) {
+        let mut pfn = frame.start_address().as_u64() / FRAME_SIZE;
+        let mut order = order;
+
+        if order > MAX_ORDER
+            || pfn + (1 << order) > self.total_frames
+            || pfn & ((1 << order) - 1) != 0
+            || !self.is_usable(pfn, order)
+        {
+            crate::serial_println!(
+                "[BUDDY] Invalid free of {:#x} at order {}",
+                frame.start_address().as_u64(),
+                order
+            );
+            return;
+        }
+
+        // Part of the block may be free already, or merged into a larger one
+        if self.overlaps_free(pfn, order) {
+            crate::serial_println!(
+                "[BUDDY] Double free of {:#x} at order {}",
+                frame.start_address().as_u64(),
+                order
+            );
+            return;
+        }
+
+        self.free_frames += 1 << order;
+        crate::page::on_free(pfn, order);
+
+        while order < MAX_ORDER {
+            let buddy = pfn ^ (1 << order);
+            if buddy >= self.total_frames || !self.is_free(buddy, order) {
+                break;
+            }
+            self.remove(buddy, order);
+            pfn &= !(1 << order);
+            order += 1;
+        }
+
+        self.push(pfn, order);
+    }
+
+    /// Number of free blocks of a given order
+    pub fn free_blocks(&self, order: usize) -> usize {
+        self.free_counts.get(order).copied().unwrap_or(0)
+    }
+
+    /// Get free memory in bytes
+    pub fn free_memory(&self) -> u64 {
+        self.free_frames * FRAME_SIZE
+    }
+}
+
+impl Default for BuddyAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate_frames(0)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.free_frames(frame, 0);
+    }
+}
+
+/// Global buddy allocator
+pub static BUDDY_ALLOCATOR: Mutex<BuddyAllocator> = Mutex::new(BuddyAllocator::new());
+
+/// Frame allocator handle backed by the global buddy allocator
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        BUDDY_ALLOCATOR.lock().allocate_frames(0)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        BUDDY_ALLOCATOR.lock().free_frames(frame, 0);
+    }
+}
+
+/// Hand all free boot memory over to the buddy allocator
+///
+/// # Safety
+/// Physical memory must be mapped at `physical_memory_offset`
+pub unsafe fn init(physical_memory_offset: VirtAddr) {
+    let mut pmm = crate::memory::PHYSICAL_MEMORY.lock();
+    let pmm = pmm
+        .as_mut()
+        .expect("physical memory manager not initialized");
+
+    let mut buddy = BUDDY_ALLOCATOR.lock();
+    buddy
+        .init(pmm, physical_memory_offset)
+        .expect("no contiguous memory for the buddy bitmap");
+
+    crate::serial_println!(
+        "[BUDDY] Buddy allocator initialized ({} KB free)",
+        buddy.free_memory() / 1024
+    );
+}
+
+/// Allocate 2^order contiguous frames
+pub fn allocate_frames(order: usize) -> Option<PhysFrame> {
+    BUDDY_ALLOCATOR.lock().allocate_frames(order)
+}
+
+/// Allocate a single frame filled with zeroes
+pub fn allocate_zeroed_frame() -> Option<PhysFrame> {
+    allocate_zeroed_frames(0)
+}
+
+/// Allocate 2^order contiguous frames filled with zeroes
+pub fn allocate_zeroed_frames(order: usize) -> Option<PhysFrame> {
+    let frame = allocate_frames(order)?;
+    let virt = phys_to_virt(
+        crate::memory::physical_memory_offset(),
+        frame.start_address(),
+    );
+    unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, (FRAME_SIZE << order) as usize) };
+    Some(frame)
+}
+
+/// Buddy order of a block covering one page of size `S`
+pub fn order_of<S: PageSize>() -> usize {
+    (S::SIZE / FRAME_SIZE).trailing_zeros() as usize
+}
+
+/// Free 2^order contiguous frames
+pub fn free_frames(frame: PhysFrame, order: usize) {
+    BUDDY_ALLOCATOR.lock().free_frames(frame, order);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BuddyAllocator, BUDDY_ALLOCATOR, ORDERS};
+    use crate::{serial_print, serial_println};
+    use x86_64::{structures::paging::PhysFrame, PhysAddr};
+
+    /// Free memory and free block counts of every order
+    fn snapshot(buddy: &BuddyAllocator) -> (u64, [usize; ORDERS]) {
+        let mut counts = [0; ORDERS];
+        for (order, count) in counts.iter_mut().enumerate() {
+            *count = buddy.free_blocks(order);
+        }
+        (buddy.free_memory(), counts)
+    }
+
+    #[test_case]
+    fn test_split_and_merge() {
+        serial_print!("test_split_and_merge... ");
+        let mut buddy = BUDDY_ALLOCATOR.lock();
+        let before = snapshot(&buddy);
+
+        let small = buddy.allocate_frames(0).unwrap();
+        let block = buddy.allocate_frames(3).unwrap();
+        assert_eq!(block.start_address().as_u64() % (8 * 4096), 0);
+        assert_eq!(buddy.free_memory(), before.0 - 9 * 4096);
+
+        // Freeing both merges the split halves back into the same blocks
+        buddy.free_frames(block, 3);
+        buddy.free_frames(small, 0);
+        assert_eq!(snapshot(&buddy), before);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_double_free_is_ignored() {
+        serial_print!("test_double_free_is_ignored... ");
+        let mut buddy = BUDDY_ALLOCATOR.lock();
+        let frame = buddy.allocate_frames(0).unwrap();
+        buddy.free_frames(frame, 0);
+        let before = snapshot(&buddy);
+
+        // The frame is now part of a larger merged block
+        buddy.free_frames(frame, 0);
+        assert_eq!(snapshot(&buddy), before);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_free_overlapping_a_free_block_is_ignored() {
+        serial_print!("test_free_overlapping_a_free_block_is_ignored... ");
+        let mut buddy = BUDDY_ALLOCATOR.lock();
+        let block = buddy.allocate_frames(1).unwrap();
+        let upper = block + 1;
+        buddy.free_frames(upper, 0);
+        let before = snapshot(&buddy);
+
+        // The upper half is free, so the pair must not be freed again
+        buddy.free_frames(block, 1);
+        assert_eq!(snapshot(&buddy), before);
+
+        buddy.free_frames(block, 0);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_invalid_frees_are_ignored() {
+        serial_print!("test_invalid_frees_are_ignored... ");
+        let mut buddy = BUDDY_ALLOCATOR.lock();
+        let before = snapshot(&buddy);
+        let frame = |addr| PhysFrame::containing_address(PhysAddr::new(addr));
+
+        let block = buddy.allocate_frames(1).unwrap();
+        // Misaligned for its order
+        buddy.free_frames(frame(block.start_address().as_u64() + 4096), 1);
+        // Past the end of memory
+        let end = buddy.total_frames * 4096;
+        buddy.free_frames(frame(end), 0);
+        // The legacy VGA hole is not RAM
+        buddy.free_frames(frame(0xA0000), 0);
+        // Beyond the largest order
+        buddy.free_frames(block, super::MAX_ORDER + 1);
+
+        buddy.free_frames(block, 1);
+        assert_eq!(snapshot(&buddy), before);
+        serial_println!("[ok]");
+    }
+}
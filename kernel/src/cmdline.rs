@@ -0,0 +1,90 @@
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Physical address of the command line the stage 2 loader carries
+///
+/// `tools/create-image.sh` writes it into a reserved block at the end of
+/// stage 2, which is loaded at 0x7E00.
+pub const CMDLINE_ADDR: u64 = 0x7E00 + 0x700;
+
+/// Longest command line kept, in bytes
+pub const CMDLINE_MAX: usize = 256;
+
+static mut CMDLINE: [u8; CMDLINE_MAX] = [0; CMDLINE_MAX];
+static CMDLINE_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Copy the boot command line into the kernel image
+///
+/// # Safety
+/// Must be called once at boot while low memory is identity mapped
+pub unsafe fn init() {
+    let src = CMDLINE_ADDR as *const u8;
+    let dst = &mut *addr_of_mut!(CMDLINE);
+
+    let mut len = 0;
+    while len < CMDLINE_MAX {
+        let byte = src.add(len).read_volatile();
+        if byte == 0 || !byte.is_ascii() {
+            break;
+        }
+        dst[len] = byte;
+        len += 1;
+    }
+    CMDLINE_LEN.store(len, Ordering::Relaxed);
+}
+
+/// The whole command line
+pub fn get() -> &'static str {
+    let len = CMDLINE_LEN.load(Ordering::Relaxed);
+    let bytes = unsafe { &(&*addr_of!(CMDLINE))[..len] };
+    core::str::from_utf8(bytes).unwrap_or("")
+}
+
+/// Iterate over the space separated options
+pub fn options() -> impl Iterator<Item = &'static str> {
+    get().split_ascii_whitespace()
+}
+
+/// Check whether a bare `flag` was given
+pub fn has_flag(flag: &str) -> bool {
+    options().any(|option| option == flag)
+}
+
+/// Value of the last `key=value` option for `key`
+pub fn value(key: &str) -> Option<&'static str> {
+    options()
+        .filter_map(|option| option.split_once('='))
+        .filter(|&(k, _)| k == key)
+        .map(|(_, v)| v)
+        .last()
+}
+
+/// Parse a byte count with an optional `K`, `M` or `G` suffix
+pub fn parse_size(value: &str) -> Option<usize> {
+    let (digits, shift) = match value.as_bytes().last()? {
+        b'K' | b'k' => (&value[..value.len() - 1], 10),
+        b'M' | b'm' => (&value[..value.len() - 1], 20),
+        b'G' | b'g' => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_size;
+    use crate::{serial_print, serial_println};
+
+    #[test_case]
+    fn test_parse_size() {
+        serial_print!("test_parse_size... ");
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("512K"), Some(512 * 1024));
+        assert_eq!(parse_size("64M"), Some(64 * 1024 * 1024));
+        assert_eq!(parse_size("1g"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("12Q"), None);
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,107 @@
+use crate::gdt;
+use x86_64::VirtAddr;
+
+/// RFLAGS of a fresh context: interrupts enabled plus the reserved bit
+const INITIAL_RFLAGS: u64 = 0x202;
+
+/// Saved CPU state of a process that is not running
+///
+/// The layout is what `interrupts::timer_interrupt_entry` leaves on the
+/// stack: the general registers it pushes, last one first, followed by the
+/// frame the CPU pushes for the interrupt. While suspended in ring 0, `rsp`
+/// is the kernel stack pointer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct CpuContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl CpuContext {
+    /// A context entering a user program at `entry` with the stack at `stack_pointer`
+    pub fn user(entry: VirtAddr, stack_pointer: VirtAddr) -> Self {
+        let selectors = gdt::selectors();
+        Self {
+            rip: entry.as_u64(),
+            cs: u64::from(selectors.user_code_selector.0),
+            rflags: INITIAL_RFLAGS,
+            rsp: stack_pointer.as_u64(),
+            ss: u64::from(selectors.user_data_selector.0),
+            ..Self::default()
+        }
+    }
+
+    /// A context calling `entry(arg)` in ring 0 on the stack ending at `stack_top`
+    ///
+    /// `entry` must never return.
+    pub fn kernel(entry: VirtAddr, stack_top: VirtAddr, arg: u64) -> Self {
+        let selectors = gdt::selectors();
+        Self {
+            rdi: arg,
+            rip: entry.as_u64(),
+            cs: u64::from(selectors.code_selector.0),
+            rflags: INITIAL_RFLAGS,
+            // Aligned as if `entry` had been called
+            rsp: stack_top.as_u64() - 8,
+            ss: u64::from(selectors.data_selector.0),
+            ..Self::default()
+        }
+    }
+
+    /// Check whether this context runs in ring 3
+    pub fn is_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
+/// Resume `context`, abandoning the current stack
+///
+/// # Safety
+/// The address space and kernel stack belonging to `context` must be
+/// installed, and GS must hold the user base
+pub unsafe fn switch_to(context: CpuContext) -> ! {
+    restore(&context)
+}
+
+/// Load every register from `context` and return into it
+#[unsafe(naked)]
+unsafe extern "C" fn restore(context: *const CpuContext) -> ! {
+    core::arch::naked_asm!(
+        "cli",
+        "mov rsp, rdi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "iretq",
+    );
+}
@@ -0,0 +1,63 @@
+use crate::address_space::AddressSpace;
+use crate::fault::FaultError;
+use crate::memory::{phys_to_virt, physical_memory_offset};
+use crate::page::PageOwner;
+use crate::process::Pid;
+use crate::rlimit::RLimit;
+use x86_64::{
+    instructions::tlb,
+    structures::paging::{Page, PageSize, PageTableFlags, PhysFrame, Size4KiB},
+};
+
+/// Software PTE bit marking a page shared copy-on-write
+pub const PTE_COW: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Resolve a write by `pid` to a copy-on-write page
+///
+/// Sharing is tracked by the frame reference counts in `page`. The last
+/// remaining mapping simply regains write access, otherwise the page is
+/// copied into a fresh frame owned by `pid`, if `resident_limit` leaves
+/// room for one more page.
+pub fn handle_cow_fault(
+    space: &mut AddressSpace,
+    page: Page,
+    pid: Pid,
+    resident_limit: RLimit,
+) -> Result<(), FaultError> {
+    let resident = space.usage().resident;
+    let is_active = space.is_active();
+    let entry = space.entry_mut(page).ok_or(FaultError::NotMapped)?;
+    let flags = entry.flags();
+    if !flags.contains(PTE_COW) {
+        return Err(FaultError::AccessViolation);
+    }
+
+    let old_frame = PhysFrame::containing_address(entry.addr());
+    let new_flags = (flags - PTE_COW) | PageTableFlags::WRITABLE;
+
+    if crate::page::page_count(old_frame) == 1 {
+        entry.set_flags(new_flags);
+    } else {
+        // The copy is a new frame for this process, charged like any other
+        if !resident_limit.allows(resident + Size4KiB::SIZE) {
+            return Err(FaultError::LimitExceeded);
+        }
+        let new_frame = crate::buddy::allocate_frames(0).ok_or(FaultError::OutOfMemory)?;
+        crate::page::set_owner(new_frame, PageOwner::Process(pid));
+        unsafe {
+            let offset = physical_memory_offset();
+            core::ptr::copy_nonoverlapping(
+                phys_to_virt(offset, old_frame.start_address()).as_ptr::<u8>(),
+                phys_to_virt(offset, new_frame.start_address()).as_mut_ptr::<u8>(),
+                4096,
+            );
+        }
+        entry.set_addr(new_frame.start_address(), new_flags);
+        crate::page::put_page(old_frame);
+    }
+
+    if is_active {
+        tlb::flush(page.start_address());
+    }
+    Ok(())
+}
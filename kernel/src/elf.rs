@@ -0,0 +1,240 @@
+use crate::vma::{VmProtection, USER_SPACE_END};
+
+/// Size of the ELF64 file header
+const EHDR_SIZE: usize = 64;
+
+/// Size of an ELF64 program header
+pub const PHDR_SIZE: usize = 56;
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+/// Loadable segment
+pub const PT_LOAD: u32 = 1;
+
+/// Segment permission bits
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+/// ELF parsing errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// Not an ELF file
+    BadMagic,
+    /// Not a 64-bit little endian x86_64 executable
+    Unsupported,
+    /// A header points past the end of the file
+    Truncated,
+    /// A segment is malformed or reaches into the kernel half
+    BadSegment,
+}
+
+/// How an ELF file is meant to be loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfKind {
+    /// Linked at fixed addresses
+    Executable,
+    /// Position independent, loaded at a chosen bias
+    Dynamic,
+}
+
+/// One program header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+impl ProgramHeader {
+    /// Access permissions the segment asks for
+    pub fn protection(&self) -> VmProtection {
+        let mut protection = VmProtection::empty();
+        if self.flags & PF_R != 0 {
+            protection |= VmProtection::READ;
+        }
+        if self.flags & PF_W != 0 {
+            protection |= VmProtection::WRITE;
+        }
+        if self.flags & PF_X != 0 {
+            protection |= VmProtection::EXEC;
+        }
+        protection
+    }
+}
+
+fn read_u16(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+/// A validated ELF64 image in memory
+#[derive(Debug, Clone, Copy)]
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    pub kind: ElfKind,
+    pub entry: u64,
+    pub phoff: u64,
+    pub phnum: u16,
+}
+
+impl<'a> ElfFile<'a> {
+    /// Check the headers of `data` and every program header
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < EHDR_SIZE || &data[..4] != b"\x7fELF" {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB || read_u16(data, 18) != EM_X86_64 {
+            return Err(ElfError::Unsupported);
+        }
+        let kind = match read_u16(data, 16) {
+            ET_EXEC => ElfKind::Executable,
+            ET_DYN => ElfKind::Dynamic,
+            _ => return Err(ElfError::Unsupported),
+        };
+        if read_u16(data, 54) as usize != PHDR_SIZE {
+            return Err(ElfError::Unsupported);
+        }
+
+        let phoff = read_u64(data, 32);
+        let phnum = read_u16(data, 56);
+        let table_end = (phnum as u64 * PHDR_SIZE as u64).checked_add(phoff);
+        if table_end.is_none_or(|end| end > data.len() as u64) {
+            return Err(ElfError::Truncated);
+        }
+
+        let elf = Self {
+            data,
+            kind,
+            entry: read_u64(data, 24),
+            phoff,
+            phnum,
+        };
+        for header in elf.program_headers() {
+            if header.kind != PT_LOAD {
+                continue;
+            }
+            let file_end = header.offset.checked_add(header.filesz);
+            if file_end.is_none_or(|end| end > data.len() as u64) {
+                return Err(ElfError::Truncated);
+            }
+            let mem_end = header.vaddr.checked_add(header.memsz);
+            if header.filesz > header.memsz || mem_end.is_none_or(|end| end > USER_SPACE_END) {
+                return Err(ElfError::BadSegment);
+            }
+        }
+        Ok(elf)
+    }
+
+    /// Iterate over the program headers
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.phnum as usize).map(move |i| {
+            let at = self.phoff as usize + i * PHDR_SIZE;
+            ProgramHeader {
+                kind: read_u32(self.data, at),
+                flags: read_u32(self.data, at + 4),
+                offset: read_u64(self.data, at + 8),
+                vaddr: read_u64(self.data, at + 16),
+                filesz: read_u64(self.data, at + 32),
+                memsz: read_u64(self.data, at + 40),
+            }
+        })
+    }
+
+    /// Iterate over the loadable segments
+    pub fn load_segments(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        self.program_headers().filter(|h| h.kind == PT_LOAD)
+    }
+
+    /// Bytes of `header` stored in the file
+    pub fn segment_data(&self, header: &ProgramHeader) -> &'a [u8] {
+        &self.data[header.offset as usize..(header.offset + header.filesz) as usize]
+    }
+
+    /// Virtual address the program headers end up at, before the load bias
+    pub fn phdr_vaddr(&self) -> Option<u64> {
+        self.load_segments()
+            .find(|h| h.offset <= self.phoff && self.phoff < h.offset + h.filesz)
+            .map(|h| h.vaddr + (self.phoff - h.offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ElfError, ElfFile, ElfKind, PF_R, PF_X, PT_LOAD};
+    use crate::vma::VmProtection;
+    use crate::{serial_print, serial_println};
+
+    /// A position independent image with one read-execute segment
+    fn image() -> [u8; 256] {
+        let mut data = [0u8; 256];
+        data[..4].copy_from_slice(b"\x7fELF");
+        data[4] = 2; // ELFCLASS64
+        data[5] = 1; // ELFDATA2LSB
+        data[16..18].copy_from_slice(&3u16.to_le_bytes()); // ET_DYN
+        data[18..20].copy_from_slice(&62u16.to_le_bytes()); // EM_X86_64
+        data[24..32].copy_from_slice(&0x1000u64.to_le_bytes()); // e_entry
+        data[32..40].copy_from_slice(&64u64.to_le_bytes()); // e_phoff
+        data[54..56].copy_from_slice(&56u16.to_le_bytes()); // e_phentsize
+        data[56..58].copy_from_slice(&1u16.to_le_bytes()); // e_phnum
+
+        let ph = 64;
+        data[ph..ph + 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        data[ph + 4..ph + 8].copy_from_slice(&(PF_R | PF_X).to_le_bytes());
+        data[ph + 16..ph + 24].copy_from_slice(&0x1000u64.to_le_bytes()); // p_vaddr
+        data[ph + 32..ph + 40].copy_from_slice(&256u64.to_le_bytes()); // p_filesz
+        data[ph + 40..ph + 48].copy_from_slice(&0x2000u64.to_le_bytes()); // p_memsz
+        data
+    }
+
+    #[test_case]
+    fn test_parse_pie() {
+        serial_print!("test_parse_pie... ");
+        let data = image();
+        let elf = ElfFile::parse(&data).expect("valid image rejected");
+        assert_eq!(elf.kind, ElfKind::Dynamic);
+        assert_eq!(elf.entry, 0x1000);
+
+        let segment = elf.load_segments().next().unwrap();
+        assert_eq!(
+            segment.protection(),
+            VmProtection::READ | VmProtection::EXEC
+        );
+        assert_eq!(elf.segment_data(&segment).len(), 256);
+        assert_eq!(elf.phdr_vaddr(), Some(0x1000 + 64));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_bad_images_are_rejected() {
+        serial_print!("test_bad_images_are_rejected... ");
+        let mut data = image();
+        data[0] = 0;
+        assert_eq!(ElfFile::parse(&data).unwrap_err(), ElfError::BadMagic);
+
+        // File data past the end of the image
+        let mut data = image();
+        data[64 + 32..64 + 40].copy_from_slice(&512u64.to_le_bytes());
+        assert_eq!(ElfFile::parse(&data).unwrap_err(), ElfError::Truncated);
+
+        // A segment reaching into the kernel half
+        let mut data = image();
+        data[64 + 16..64 + 24].copy_from_slice(&0x0000_7FFF_FFFF_F000u64.to_le_bytes());
+        assert_eq!(ElfFile::parse(&data).unwrap_err(), ElfError::BadSegment);
+        serial_println!("[ok]");
+    }
+}
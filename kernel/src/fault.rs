@@ -0,0 +1,198 @@
+use crate::address_space::{AddressSpace, PTE_SHARED};
+use crate::page::PageOwner;
+use crate::process::{Pid, PROCESS_MANAGER};
+use crate::rlimit::Resource;
+use crate::signal::Signal;
+use crate::swap::SwapEntry;
+use crate::vma::{VmProtection, Vma, VmaBacking, USER_SPACE_END};
+use x86_64::{
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        mapper::MapToError, Mapper, OffsetPageTable, Page, PageSize, PhysFrame, Size2MiB, Size4KiB,
+    },
+    VirtAddr,
+};
+
+/// Why a page fault could not be resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    /// No user process is running
+    NoProcess,
+    /// The address is not covered by any area
+    NotMapped,
+    /// The area does not permit this kind of access
+    AccessViolation,
+    /// The backing store cannot provide the page
+    BackingUnavailable,
+    /// The page would exceed the resident memory limit
+    LimitExceeded,
+    OutOfMemory,
+    /// The OOM killer chose the faulting process
+    Killed,
+}
+
+impl FaultError {
+    /// Signal delivered to a process whose fault could not be resolved
+    pub fn signal(&self) -> Signal {
+        match self {
+            FaultError::BackingUnavailable => Signal::SIGBUS,
+            FaultError::Killed => Signal::SIGKILL,
+            _ => Signal::SIGSEGV,
+        }
+    }
+}
+
+/// Kind of access that caused a fault
+fn access_kind(error_code: PageFaultErrorCode) -> VmProtection {
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        VmProtection::EXEC
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        VmProtection::WRITE
+    } else {
+        VmProtection::READ
+    }
+}
+
+/// Resolve a page fault in the current process by demand paging
+///
+/// When physical memory runs out, pages are swapped out or else the OOM
+/// killer frees some, and the fault is retried.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> Result<(), FaultError> {
+    match resolve_fault(addr, error_code) {
+        Err(FaultError::OutOfMemory) => {}
+        result => return result,
+    }
+
+    if crate::swap::reclaim(crate::swap::SWAP_CLUSTER) > 0 {
+        match resolve_fault(addr, error_code) {
+            Err(FaultError::OutOfMemory) => {}
+            result => return result,
+        }
+    }
+
+    let pid = crate::process::current_pid();
+    match crate::oom::out_of_memory() {
+        Some(victim) if Some(victim) == pid => Err(FaultError::Killed),
+        Some(_) => resolve_fault(addr, error_code),
+        None => Err(FaultError::OutOfMemory),
+    }
+}
+
+fn resolve_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> Result<(), FaultError> {
+    if addr.as_u64() >= USER_SPACE_END {
+        return Err(FaultError::NotMapped);
+    }
+
+    let pid = crate::process::current_pid().ok_or(FaultError::NoProcess)?;
+    let mut pm = PROCESS_MANAGER.lock();
+    let process = pm.get_process_mut(pid).ok_or(FaultError::NoProcess)?;
+    let resident_limit = process.limits.get(Resource::Resident);
+    let space = process
+        .address_space
+        .as_mut()
+        .ok_or(FaultError::NoProcess)?;
+    let vma = *space.vmas.find(addr).ok_or(FaultError::NotMapped)?;
+
+    // Also rejects every access to PROT_NONE pages, which stay mapped
+    // supervisor-only; kernel accesses get EFAULT through the fixup
+    if !vma.allows(access_kind(error_code)) {
+        return Err(FaultError::AccessViolation);
+    }
+
+    // The page is present and the area allows the access, so the page was
+    // write-protected for copy-on-write
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            let page = Page::containing_address(addr);
+            return crate::cow::handle_cow_fault(space, page, pid, resident_limit);
+        }
+        return Err(FaultError::AccessViolation);
+    }
+
+    if !resident_limit.allows(space.usage().resident + vma.page_size()) {
+        return Err(FaultError::LimitExceeded);
+    }
+
+    if !vma.huge_pages {
+        let page = Page::containing_address(addr);
+        if let Some(swap) = space.entry_mut(page).and_then(|e| SwapEntry::from_pte(e)) {
+            return crate::swap::swap_in(space, page, swap, vma.page_flags());
+        }
+    }
+
+    if vma.huge_pages {
+        map_fault_page::<Size2MiB>(space, &vma, addr, pid)
+    } else {
+        map_fault_page::<Size4KiB>(space, &vma, addr, pid)
+    }
+}
+
+/// Populate the page of size `S` containing `addr` from the area's backing
+fn map_fault_page<S: PageSize>(
+    space: &mut AddressSpace,
+    vma: &Vma,
+    addr: VirtAddr,
+    pid: Pid,
+) -> Result<(), FaultError>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    let page = Page::<S>::containing_address(addr);
+    let offset = page.start_address() - vma.start;
+    let order = crate::buddy::order_of::<S>();
+
+    let (start, flags, owned) = match vma.backing {
+        VmaBacking::Anonymous => {
+            let frame =
+                crate::buddy::allocate_zeroed_frames(order).ok_or(FaultError::OutOfMemory)?;
+            crate::page::set_owner(frame, PageOwner::Process(pid));
+            (frame.start_address(), vma.page_flags(), true)
+        }
+        VmaBacking::SharedMemory { id, offset: base } => {
+            let frame = crate::shm::frame_at(id, base + offset)
+                .map_err(|_| FaultError::BackingUnavailable)?;
+            (frame.start_address(), vma.page_flags() | PTE_SHARED, false)
+        }
+        // No page cache yet
+        VmaBacking::File { .. } => return Err(FaultError::BackingUnavailable),
+    };
+
+    // A huge area over a segment with small pages cannot be mapped
+    let frame =
+        PhysFrame::<S>::from_start_address(start).map_err(|_| FaultError::BackingUnavailable)?;
+
+    match space.map_user_page(page, frame, flags) {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            if owned {
+                crate::buddy::free_frames(PhysFrame::containing_address(start), order);
+            }
+            match err {
+                MapToError::FrameAllocationFailed => Err(FaultError::OutOfMemory),
+                _ => Err(FaultError::AccessViolation),
+            }
+        }
+    }
+}
+
+/// Deliver the signal for an unresolvable user fault and terminate the process
+pub fn kill_faulting_process(pid: Pid, addr: VirtAddr, error: FaultError) {
+    // Already reclaimed by the OOM killer
+    if error == FaultError::Killed {
+        return;
+    }
+
+    let signal = error.signal();
+    crate::serial_println!(
+        "[FAULT] PID {} {:?} at {:#x} ({:?})",
+        pid,
+        signal,
+        addr.as_u64(),
+        error
+    );
+
+    crate::signal::send_signal(pid, signal, None);
+
+    // Neither SIGSEGV nor SIGBUS can resume the faulting instruction
+    crate::process::exit(pid);
+}
@@ -1,3 +1,4 @@
+use core::ptr::{addr_of, addr_of_mut};
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
@@ -5,48 +6,112 @@ use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
+/// Size of the unguarded double fault stack used until `kstack::init`
+const BOOT_STACK_SIZE: usize = 4096 * 5;
+
+static mut DOUBLE_FAULT_BOOT_STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
+
+/// Task state segment, mutable so stacks can be swapped after boot
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// Per-CPU data `syscall_entry` reaches through GS after `swapgs`
+#[repr(C)]
+struct CpuLocal {
+    /// Scratch slot for the user stack pointer
+    user_rsp: u64,
+    /// Stack the running process enters the kernel on
+    kernel_rsp: u64,
+}
+
+static mut CPU_LOCAL: CpuLocal = CpuLocal {
+    user_rsp: 0,
+    kernel_rsp: 0,
+};
+
+/// Set the stack the CPU switches to when entering the kernel from ring 3
+///
+/// Used for both interrupts (through the TSS) and system calls.
+///
+/// # Safety
+/// `top` must be the top of a mapped kernel stack that stays mapped while
+/// it is installed.
+pub unsafe fn set_kernel_stack(top: VirtAddr) {
+    (*addr_of_mut!(TSS)).privilege_stack_table[0] = top;
+    (*addr_of_mut!(CPU_LOCAL)).kernel_rsp = top.as_u64();
+}
+
+/// Stack the kernel is entered on from ring 3
+pub fn kernel_stack() -> VirtAddr {
+    VirtAddr::new(unsafe { (*addr_of!(CPU_LOCAL)).kernel_rsp })
+}
+
+/// Point an interrupt stack table entry at a new stack
+///
+/// # Safety
+/// `top` must be the top of a mapped stack that outlives its use, and no
+/// interrupt may be running on the old stack of this entry.
+pub unsafe fn set_ist(index: u16, top: VirtAddr) {
+    (*addr_of_mut!(TSS)).interrupt_stack_table[index as usize] = top;
 }
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
+        // SYSRET expects user data right before user code
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*addr_of!(TSS) }));
         (
             gdt,
             Selectors {
                 code_selector,
+                data_selector,
+                user_code_selector,
+                user_data_selector,
                 tss_selector,
             },
         )
     };
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
+/// Segment selectors of the GDT
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
+/// Get the segment selectors
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
 pub fn init() {
-    use x86_64::instructions::segmentation::{Segment, CS};
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
     use x86_64::instructions::tables::load_tss;
+    use x86_64::registers::model_specific::KernelGsBase;
+
+    unsafe {
+        let boot_stack_top = |stack: *const [u8; BOOT_STACK_SIZE]| {
+            VirtAddr::from_ptr(stack) + BOOT_STACK_SIZE as u64
+        };
+        set_ist(
+            DOUBLE_FAULT_IST_INDEX,
+            boot_stack_top(addr_of!(DOUBLE_FAULT_BOOT_STACK)),
+        );
+    }
 
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
+        SS::set_reg(GDT.1.data_selector);
         load_tss(GDT.1.tss_selector);
     }
+
+    // Swapped in by `swapgs` on every system call
+    KernelGsBase::write(VirtAddr::from_ptr(addr_of!(CPU_LOCAL)));
 }
@@ -1,12 +1,24 @@
+use crate::context::CpuContext;
 use crate::gdt;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin::Mutex;
+use x86_64::instructions::port::Port;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::VirtAddr;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+/// Timer interrupts per second
+pub const TIMER_HZ: u32 = 100;
+
+/// Input clock of the programmable interval timer
+const PIT_FREQUENCY: u32 = 1_193_182;
+
+/// Software interrupt kernel threads raise to give up the CPU
+pub const YIELD_VECTOR: u8 = 0x81;
+
 pub static PICS: Mutex<ChainedPics> =
     Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
@@ -36,8 +48,17 @@ lazy_static! {
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
+        unsafe {
+            idt[InterruptIndex::Timer.as_usize()]
+                .set_handler_addr(VirtAddr::new(timer_interrupt_entry as usize as u64));
+        }
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        unsafe {
+            idt[usize::from(YIELD_VECTOR)]
+                .set_handler_addr(VirtAddr::new(yield_interrupt_entry as usize as u64));
+        }
+        // No IST: a fault taken while handling a fault, e.g. on a user copy,
+        // must not reuse the stack of the outer one
         idt.page_fault.set_handler_fn(page_fault_handler);
         idt
     };
@@ -47,6 +68,21 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/// Program the PIT to interrupt `TIMER_HZ` times a second
+pub fn init_timer() {
+    let divisor = (PIT_FREQUENCY / TIMER_HZ) as u16;
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel0: Port<u8> = Port::new(0x40);
+
+    unsafe {
+        // Channel 0, low then high byte, rate generator
+        command.write(0x34);
+        channel0.write(divisor as u8);
+        channel0.write((divisor >> 8) as u8);
+    }
+    crate::serial_println!("[INIT] Timer running at {} Hz", TIMER_HZ);
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     crate::serial_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
@@ -55,19 +91,84 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    // A kernel stack overflow faults again pushing the page fault frame
+    // onto the guard page, which ends up here on the IST stack
+    let addr = x86_64::registers::control::Cr2::read();
+    if let Some(owner) = crate::kstack::guard_owner(addr) {
+        crate::serial_println!("{:#?}", stack_frame);
+        panic!("kernel stack overflow in {}", owner);
+    }
+
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+/// Define an interrupt entry saving the full context it interrupted
+///
+/// The pushed registers and the interrupt frame form a `CpuContext`, which
+/// the handler may replace with that of another process before it is
+/// restored.
+macro_rules! context_entry {
+    ($name:ident, $handler:ident) => {
+        #[unsafe(naked)]
+        extern "C" fn $name() {
+            core::arch::naked_asm!(
+                "push rax",
+                "push rbx",
+                "push rcx",
+                "push rdx",
+                "push rsi",
+                "push rdi",
+                "push rbp",
+                "push r8",
+                "push r9",
+                "push r10",
+                "push r11",
+                "push r12",
+                "push r13",
+                "push r14",
+                "push r15",
+                // The stack is 16 byte aligned again after the 20 words
+                "mov rdi, rsp",
+                "cld",
+                "call {}",
+                "pop r15",
+                "pop r14",
+                "pop r13",
+                "pop r12",
+                "pop r11",
+                "pop r10",
+                "pop r9",
+                "pop r8",
+                "pop rbp",
+                "pop rdi",
+                "pop rsi",
+                "pop rdx",
+                "pop rcx",
+                "pop rbx",
+                "pop rax",
+                "iretq",
+                sym $handler,
+            );
+        }
+    };
+}
+
+context_entry!(timer_interrupt_entry, timer_interrupt_handler);
+context_entry!(yield_interrupt_entry, yield_interrupt_handler);
+
+extern "C" fn timer_interrupt_handler(context: &mut CpuContext) {
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+    crate::process::tick(context);
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
+extern "C" fn yield_interrupt_handler(context: &mut CpuContext) {
+    crate::process::reschedule(context);
+}
 
+extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
 
@@ -82,13 +183,51 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
 use x86_64::structures::idt::PageFaultErrorCode;
 
 extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
+    mut stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
 
+    let addr = Cr2::read();
+
+    // Overflows that still leave room for the exception frame land here,
+    // the others become double faults
+    if let Some(owner) = crate::kstack::guard_owner(addr) {
+        crate::serial_println!("{:#?}", stack_frame);
+        panic!("kernel stack overflow in {}", owner);
+    }
+
+    let error = match crate::fault::handle_page_fault(addr, error_code) {
+        Ok(()) => return,
+        Err(error) => error,
+    };
+
+    // A bad pointer passed to a syscall fails the copy with EFAULT
+    if let Some(fixup) = crate::uaccess::fixup_address(stack_frame.instruction_pointer) {
+        // Unless the OOM killer reaped the caller, which must not run on
+        if error == crate::fault::FaultError::Killed {
+            crate::syscall::leave_syscall();
+            crate::process::run_next();
+        }
+        unsafe {
+            stack_frame
+                .as_mut()
+                .update(|frame| frame.instruction_pointer = fixup);
+        }
+        return;
+    }
+
+    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        if let Some(pid) = crate::process::current_pid() {
+            crate::fault::kill_faulting_process(pid, addr, error);
+
+            // The faulting instruction cannot be restarted
+            crate::process::run_next();
+        }
+    }
+
     crate::serial_println!("EXCEPTION: PAGE FAULT");
-    crate::serial_println!("Accessed Address: {:?}", Cr2::read());
+    crate::serial_println!("Accessed Address: {:?}", addr);
     crate::serial_println!("Error Code: {:?}", error_code);
     crate::serial_println!("{:#?}", stack_frame);
     crate::hlt_loop();
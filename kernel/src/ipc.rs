@@ -54,6 +54,9 @@ impl MessageQueue {
         if self.messages.len() >= self.max_size {
             return Err(IpcError::QueueFull);
         }
+        self.messages
+            .try_reserve(1)
+            .map_err(|_| IpcError::OutOfMemory)?;
         self.messages.push_back(msg);
         Ok(())
     }
@@ -62,6 +65,11 @@ impl MessageQueue {
         self.messages.pop_front()
     }
 
+    /// Put a popped message back at the head, even if the queue filled up
+    fn unpop(&mut self, msg: Message) {
+        self.messages.push_front(msg);
+    }
+
     fn len(&self) -> usize {
         self.messages.len()
     }
@@ -94,12 +102,13 @@ impl IpcManager {
         // Find receiver's queue
         for (pid, queue) in &mut self.queues {
             if *pid == msg.receiver {
-                queue.push(msg.clone())?;
+                let (sender, receiver, len) = (msg.sender, msg.receiver, msg.data.len());
+                queue.push(msg)?;
                 crate::serial_println!(
                     "[IPC] Message sent: {} -> {} ({} bytes)",
-                    msg.sender,
-                    msg.receiver,
-                    msg.data.len()
+                    sender,
+                    receiver,
+                    len
                 );
                 return Ok(());
             }
@@ -117,6 +126,15 @@ impl IpcManager {
         None
     }
 
+    /// Return a received message that could not be delivered
+    ///
+    /// It goes back to the head of the queue so it is received next.
+    pub fn requeue(&mut self, msg: Message) {
+        if let Some((_, queue)) = self.queues.iter_mut().find(|(p, _)| *p == msg.receiver) {
+            queue.unpop(msg);
+        }
+    }
+
     /// Check if messages are available
     pub fn has_messages(&self, pid: Pid) -> bool {
         for (p, queue) in &self.queues {
@@ -145,6 +163,7 @@ pub enum IpcError {
     ProcessNotFound,
     MessageTooLarge,
     InvalidMessage,
+    OutOfMemory,
 }
 
 /// Global IPC manager
@@ -161,7 +180,13 @@ pub fn send_message(sender: Pid, receiver: Pid, data: &[u8]) -> Result<(), IpcEr
         return Err(IpcError::MessageTooLarge);
     }
 
-    let msg = Message::new(sender, receiver, data.to_vec(), MessageType::Data);
+    let mut payload = Vec::new();
+    payload
+        .try_reserve_exact(data.len())
+        .map_err(|_| IpcError::OutOfMemory)?;
+    payload.extend_from_slice(data);
+
+    let msg = Message::new(sender, receiver, payload, MessageType::Data);
 
     IPC_MANAGER.lock().send(msg)
 }
@@ -171,6 +196,11 @@ pub fn receive_message(pid: Pid) -> Option<Message> {
     IPC_MANAGER.lock().receive(pid)
 }
 
+/// Put back a received message, e.g. when copying it out faulted
+pub fn requeue_message(msg: Message) {
+    IPC_MANAGER.lock().requeue(msg);
+}
+
 /// Check if messages are available
 pub fn has_messages(pid: Pid) -> bool {
     IPC_MANAGER.lock().has_messages(pid)
@@ -0,0 +1,249 @@
+use crate::random::{self, EntropySource};
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::{
+    instructions::tlb,
+    registers::control::Cr3,
+    structures::paging::{PageSize, PageTable, PageTableFlags, Size2MiB},
+    PhysAddr, VirtAddr,
+};
+
+/// Start of the 1 GiB window the kernel image is placed in
+///
+/// The image is linked as if physical address 0 were mapped here, see
+/// `linker.ld`.
+pub const KERNEL_WINDOW_START: u64 = 0xFFFF_FFFF_8000_0000;
+
+/// Number of 2 MiB slots in the kernel window
+const KERNEL_WINDOW_SLOTS: u64 = 512;
+
+/// Heap base without randomization
+pub const DEFAULT_HEAP_BASE: u64 = 0xFFFF_C000_0000_0000;
+
+/// First and last PML4 slots the heap may be placed in
+const HEAP_PML4_SLOTS: (u64, u64) = (384, 447);
+
+/// First and last PML4 slots the direct map may be placed in
+const DIRECT_MAP_PML4_SLOTS: (u64, u64) = (256, 319);
+
+/// Shared memory window base without randomization
+pub const DEFAULT_SHM_BASE: u64 = 0x0000_4000_0000_0000;
+
+/// Size of the range the shared memory window may start in
+const SHM_RANDOM_RANGE: u64 = 0x0000_2000_0000_0000;
+
+/// Size of the region one PML4 entry maps
+const PML4_SLOT_SIZE: u64 = 1 << 39;
+
+/// Size of the boot stack the relocated kernel starts on
+const BOOT_STACK_SIZE: usize = 64 * 1024;
+
+/// ELF relocation type adding the load offset to an address
+const R_X86_64_RELATIVE: u32 = 8;
+
+extern "C" {
+    static __kernel_start: u8;
+    static __kernel_end: u8;
+    static __rela_start: u8;
+    static __rela_end: u8;
+}
+
+/// ELF64 relocation entry with an addend
+#[repr(C)]
+struct Rela {
+    offset: u64,
+    info: u64,
+    addend: i64,
+}
+
+/// Page tables mapping the kernel window
+#[repr(C, align(4096))]
+struct KernelTables {
+    pdpt: PageTable,
+    pd: PageTable,
+}
+
+static mut KERNEL_TABLES: KernelTables = KernelTables {
+    pdpt: PageTable::new(),
+    pd: PageTable::new(),
+};
+
+#[repr(C, align(16))]
+struct BootStack([u8; BOOT_STACK_SIZE]);
+
+static mut BOOT_STACK: BootStack = BootStack([0; BOOT_STACK_SIZE]);
+
+static RANDOMIZED: AtomicBool = AtomicBool::new(false);
+static KERNEL_SLIDE: AtomicU64 = AtomicU64::new(0);
+static HEAP_BASE: AtomicU64 = AtomicU64::new(DEFAULT_HEAP_BASE);
+static DIRECT_MAP_BASE: AtomicU64 = AtomicU64::new(crate::memory::PHYSICAL_MEMORY_OFFSET);
+static SHM_BASE: AtomicU64 = AtomicU64::new(DEFAULT_SHM_BASE);
+
+/// Virtual minus physical address of the kernel image
+pub fn kernel_slide() -> u64 {
+    KERNEL_SLIDE.load(Ordering::Relaxed)
+}
+
+/// Virtual address of physical 0 through the kernel window
+///
+/// The window maps all memory below the end of the image, including the
+/// boot page tables and the tables the bootloader leaves under 1 MiB.
+pub fn low_memory() -> VirtAddr {
+    VirtAddr::new(kernel_slide())
+}
+
+/// Physical address of something in the kernel image
+pub fn image_phys<T>(ptr: *const T) -> PhysAddr {
+    PhysAddr::new(ptr as u64 - kernel_slide())
+}
+
+/// Start of the kernel heap window
+pub fn heap_base() -> u64 {
+    HEAP_BASE.load(Ordering::Relaxed)
+}
+
+/// Virtual address physical memory is mapped at
+pub fn direct_map_base() -> u64 {
+    DIRECT_MAP_BASE.load(Ordering::Relaxed)
+}
+
+/// Start of the user window shared memory segments are placed in
+pub fn shm_base() -> u64 {
+    SHM_BASE.load(Ordering::Relaxed)
+}
+
+/// Whether the layout was randomized at boot
+pub fn is_randomized() -> bool {
+    RANDOMIZED.load(Ordering::Relaxed)
+}
+
+/// Pick an address in PML4 slot range `slots`, aligned to `align`,
+/// leaving `size` bytes of room before the end of the slot
+fn random_in_slots(slots: (u64, u64), size: u64, align: u64) -> u64 {
+    let slot = slots.0 + random::random_below(slots.1 - slots.0 + 1);
+    let offset = random::random_below((PML4_SLOT_SIZE - size) / align + 1) * align;
+    0xFFFF_0000_0000_0000 | (slot * PML4_SLOT_SIZE + offset)
+}
+
+/// Choose the heap, direct map and shared memory windows
+fn choose_layout() {
+    let heap = random_in_slots(
+        HEAP_PML4_SLOTS,
+        crate::allocator::HEAP_MAX_SIZE as u64,
+        Size2MiB::SIZE,
+    );
+    // The direct map is built from a single PML4 entry
+    let direct_map = random_in_slots(DIRECT_MAP_PML4_SLOTS, PML4_SLOT_SIZE, PML4_SLOT_SIZE);
+    let shm =
+        DEFAULT_SHM_BASE + random::random_below(SHM_RANDOM_RANGE / Size2MiB::SIZE) * Size2MiB::SIZE;
+
+    HEAP_BASE.store(heap, Ordering::Relaxed);
+    DIRECT_MAP_BASE.store(direct_map, Ordering::Relaxed);
+    SHM_BASE.store(shm, Ordering::Relaxed);
+}
+
+/// Map the kernel window so that it starts at physical address 0
+///
+/// Returns the base of the mapping.
+unsafe fn map_kernel_window(image_end: u64, randomize: bool) -> u64 {
+    let tables = &mut *addr_of_mut!(KERNEL_TABLES);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let huge = flags | PageTableFlags::HUGE_PAGE | PageTableFlags::GLOBAL;
+
+    let slots = image_end.div_ceil(Size2MiB::SIZE);
+    let first = if randomize {
+        random::random_below(KERNEL_WINDOW_SLOTS - slots + 1)
+    } else {
+        0
+    };
+    for i in 0..slots {
+        tables.pd[(first + i) as usize].set_addr(PhysAddr::new(i * Size2MiB::SIZE), huge);
+    }
+
+    // Still running at the load address, so the tables are identity mapped
+    let identity = |table: &PageTable| PhysAddr::new(table as *const PageTable as u64);
+    let base = KERNEL_WINDOW_START + first * Size2MiB::SIZE;
+    let pdpt_index = (base >> 30) as usize & 511;
+    tables.pdpt[pdpt_index].set_addr(identity(&tables.pd), flags);
+
+    let (pml4_frame, _) = Cr3::read();
+    let pml4 = &mut *(pml4_frame.start_address().as_u64() as *mut PageTable);
+    pml4[511].set_addr(identity(&tables.pdpt), flags);
+    tlb::flush_all();
+    base
+}
+
+/// Add `delta` to every absolute address in the image
+///
+/// The targets are written through the freshly mapped window.
+unsafe fn apply_relocations(delta: u64) {
+    let start = addr_of!(__rela_start) as *const Rela;
+    let end = addr_of!(__rela_end) as *const Rela;
+    let count = end.offset_from(start) as usize;
+
+    for rela in core::slice::from_raw_parts(start, count) {
+        if rela.info as u32 == R_X86_64_RELATIVE {
+            let target = rela.offset.wrapping_add(delta) as *mut u64;
+            target.write((rela.addend as u64).wrapping_add(delta));
+        }
+    }
+}
+
+/// Switch to `stack_top` and call `entry`
+#[unsafe(naked)]
+unsafe extern "C" fn enter(entry: u64, stack_top: u64) -> ! {
+    core::arch::naked_asm!("mov rsp, rsi", "xor ebp, ebp", "call rdi", "ud2");
+}
+
+/// Move the kernel into the higher half and continue at `entry`
+///
+/// The image is a static PIE linked at `KERNEL_WINDOW_START` plus its
+/// physical load address. It is mapped at a random 2 MiB aligned slot of
+/// the top 1 GiB, its relocations are patched for the distance to the link
+/// address, and `entry` runs there on a fresh stack. With `randomize` unset
+/// the first slot is used, so the kernel runs exactly where it was linked.
+///
+/// # Safety
+/// Must be the first thing the kernel does, running identity mapped at the
+/// load address with nothing having used an absolute pointer yet
+pub unsafe fn relocate(entry: extern "C" fn() -> !, randomize: bool) -> ! {
+    // Position independent code sees load addresses until it is moved
+    let entry = entry as usize as u64;
+    let stack_top = addr_of!(BOOT_STACK) as u64 + BOOT_STACK_SIZE as u64;
+    let image_end = addr_of!(__kernel_end) as u64;
+
+    if randomize {
+        choose_layout();
+    }
+    RANDOMIZED.store(randomize, Ordering::Relaxed);
+
+    // Physical address 0 is mapped at `base`, so that is the slide
+    let slide = map_kernel_window(image_end, randomize);
+    KERNEL_SLIDE.store(slide, Ordering::Relaxed);
+    if slide != KERNEL_WINDOW_START {
+        apply_relocations(slide - KERNEL_WINDOW_START);
+    }
+
+    enter(entry + slide, stack_top + slide)
+}
+
+/// Log the chosen layout
+pub fn init() {
+    let source = match random::source() {
+        EntropySource::Rdseed => "RDSEED",
+        EntropySource::Rdrand => "RDRAND",
+        EntropySource::TscJitter => "TSC jitter",
+    };
+    if is_randomized() {
+        crate::serial_println!("[KASLR] Randomized with {}", source);
+    } else {
+        crate::serial_println!("[KASLR] Disabled by nokaslr");
+    }
+    crate::serial_println!(
+        "[KASLR] Kernel at {:#x}, heap at {:#x}, direct map at {:#x}, shm at {:#x}",
+        addr_of!(__kernel_start) as u64,
+        heap_base(),
+        direct_map_base(),
+        shm_base()
+    );
+}
@@ -0,0 +1,220 @@
+use crate::buddy::GlobalFrameAllocator;
+use crate::process::Pid;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{Mapper, Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+/// Start of the virtual region kernel stacks are carved from
+pub const KSTACK_REGION_START: u64 = 0xFFFF_FE00_0000_0000;
+
+/// Usable pages of each kernel stack
+pub const KSTACK_PAGES: u64 = 4;
+
+/// Size of each kernel stack, not counting its guard page
+pub const KSTACK_SIZE: u64 = KSTACK_PAGES * 4096;
+
+/// Virtual size of one slot: an unmapped guard page below the stack
+const SLOT_SIZE: u64 = KSTACK_SIZE + 4096;
+
+/// Maximum number of kernel stacks
+pub const MAX_KSTACKS: usize = 4096;
+
+/// Who a kernel stack belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackOwner {
+    Process(Pid),
+    Kernel(&'static str),
+}
+
+impl fmt::Display for StackOwner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StackOwner::Process(pid) => write!(f, "PID {}", pid),
+            StackOwner::Kernel(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Kernel stack errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KStackError {
+    NoSlots,
+    OutOfMemory,
+    NotInitialized,
+}
+
+/// A kernel stack with an unmapped guard page below it
+///
+/// The stack is unmapped and its slot recycled on drop.
+#[derive(Debug)]
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    /// Allocate and map a new kernel stack
+    pub fn allocate(owner: StackOwner) -> Result<Self, KStackError> {
+        let slot = KSTACK_MANAGER.lock().reserve(owner)?;
+        let stack = KernelStack { slot };
+        // On failure the partially mapped stack is torn down by drop
+        stack.map_pages()?;
+        Ok(stack)
+    }
+
+    fn map_pages(&self) -> Result<(), KStackError> {
+        let mut mapper = crate::memory::KERNEL_MAPPER.lock();
+        let mapper = mapper.as_mut().ok_or(KStackError::NotInitialized)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+        for page in self.pages() {
+            let frame = crate::buddy::allocate_zeroed_frame().ok_or(KStackError::OutOfMemory)?;
+            match unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator) } {
+                Ok(flush) => flush.flush(),
+                Err(_) => {
+                    crate::buddy::free_frames(frame, 0);
+                    return Err(KStackError::OutOfMemory);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Lowest usable address, right above the guard page
+    pub fn bottom(&self) -> VirtAddr {
+        slot_base(self.slot) + 4096u64
+    }
+
+    /// Initial stack pointer
+    pub fn top(&self) -> VirtAddr {
+        self.bottom() + KSTACK_SIZE
+    }
+
+    /// Check whether the CPU is currently running on this stack
+    pub fn is_active(&self) -> bool {
+        let rsp: u64;
+        unsafe {
+            core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+        }
+        (self.bottom().as_u64()..=self.top().as_u64()).contains(&rsp)
+    }
+
+    /// Change the recorded owner
+    pub fn set_owner(&self, owner: StackOwner) {
+        KSTACK_MANAGER.lock().owners.insert(self.slot, owner);
+    }
+
+    /// Keep the stack mapped forever and return its top
+    pub fn leak(self) -> VirtAddr {
+        let top = self.top();
+        core::mem::forget(self);
+        top
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        let start = Page::containing_address(self.bottom());
+        Page::range(start, start + KSTACK_PAGES)
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        if let Some(mapper) = crate::memory::KERNEL_MAPPER.lock().as_mut() {
+            for page in self.pages() {
+                // Pages past a failed allocation were never mapped
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                    crate::buddy::free_frames(frame, 0);
+                }
+            }
+        }
+        KSTACK_MANAGER.lock().release(self.slot);
+    }
+}
+
+/// Bookkeeping of kernel stack slots
+pub struct KStackManager {
+    owners: BTreeMap<usize, StackOwner>,
+    free: Vec<usize>,
+    next: usize,
+}
+
+impl KStackManager {
+    pub const fn new() -> Self {
+        Self {
+            owners: BTreeMap::new(),
+            free: Vec::new(),
+            next: 0,
+        }
+    }
+
+    fn reserve(&mut self, owner: StackOwner) -> Result<usize, KStackError> {
+        let slot = match self.free.pop() {
+            Some(slot) => slot,
+            None if self.next < MAX_KSTACKS => {
+                self.next += 1;
+                self.next - 1
+            }
+            None => return Err(KStackError::NoSlots),
+        };
+        self.owners.insert(slot, owner);
+        Ok(slot)
+    }
+
+    fn release(&mut self, slot: usize) {
+        self.owners.remove(&slot);
+        self.free.push(slot);
+    }
+
+    /// Number of kernel stacks in use
+    pub fn count(&self) -> usize {
+        self.owners.len()
+    }
+}
+
+impl Default for KStackManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Global kernel stack manager
+pub static KSTACK_MANAGER: Mutex<KStackManager> = Mutex::new(KStackManager::new());
+
+fn slot_base(slot: usize) -> VirtAddr {
+    VirtAddr::new(KSTACK_REGION_START + slot as u64 * SLOT_SIZE)
+}
+
+/// Owner of the kernel stack whose guard page contains `addr`, if any
+///
+/// Called from the fault handlers, so it must not block on a lock the
+/// faulting code may hold.
+pub fn guard_owner(addr: VirtAddr) -> Option<StackOwner> {
+    let offset = addr.as_u64().checked_sub(KSTACK_REGION_START)?;
+    if offset >= MAX_KSTACKS as u64 * SLOT_SIZE || offset % SLOT_SIZE >= 4096 {
+        return None;
+    }
+
+    let slot = (offset / SLOT_SIZE) as usize;
+    match KSTACK_MANAGER.try_lock() {
+        Some(manager) => manager.owners.get(&slot).copied(),
+        None => Some(StackOwner::Kernel("unknown stack")),
+    }
+}
+
+/// Move the double fault stack into a guarded kernel stack
+pub fn init() {
+    let stack = KernelStack::allocate(StackOwner::Kernel("double fault stack"))
+        .expect("failed to allocate the double fault stack");
+    unsafe { crate::gdt::set_ist(crate::gdt::DOUBLE_FAULT_IST_INDEX, stack.leak()) };
+
+    crate::serial_println!(
+        "[KSTACK] Kernel stacks at {:#x} ({} KB + guard page each)",
+        KSTACK_REGION_START,
+        KSTACK_SIZE / 1024
+    );
+}
@@ -0,0 +1,250 @@
+use crate::process::{Pid, ProcessError, ProcessState, PROCESS_MANAGER};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+/// Body of a kernel thread, boxed twice to pass it in one register
+type ThreadMain = Box<dyn FnOnce() + Send>;
+
+struct Inner {
+    pid: Pid,
+    name: &'static str,
+    /// Wakeup left for the next `park`, only touched under the process manager
+    unparked: AtomicBool,
+}
+
+/// A running kernel thread
+#[derive(Clone)]
+pub struct Thread {
+    inner: Arc<Inner>,
+}
+
+impl Thread {
+    pub fn pid(&self) -> Pid {
+        self.inner.pid
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.inner.name
+    }
+
+    /// Wake the thread from `park`, or make its next `park` return at once
+    ///
+    /// Must not be called from interrupt handlers.
+    pub fn unpark(&self) {
+        let mut pm = PROCESS_MANAGER.lock();
+        match pm.get_process(self.pid()).map(|process| process.state) {
+            Some(ProcessState::Blocked) => pm.unblock_process(self.pid()),
+            _ => self.inner.unparked.store(true, Ordering::Relaxed),
+        }
+    }
+}
+
+/// Where a thread leaves its result for `join`
+struct Packet<T> {
+    result: Mutex<Option<T>>,
+    /// Thread waiting in `join`
+    joiner: Mutex<Option<Thread>>,
+}
+
+/// Owned permission to wait for a kernel thread and take its result
+pub struct JoinHandle<T> {
+    thread: Thread,
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn thread(&self) -> &Thread {
+        &self.thread
+    }
+
+    /// Check whether the thread has returned
+    pub fn is_finished(&self) -> bool {
+        self.packet.result.lock().is_some()
+    }
+
+    /// Wait for the thread to return and take its result
+    ///
+    /// Outside a kernel thread this halts until timer ticks have run the
+    /// thread to completion, so interrupts must be enabled.
+    pub fn join(self) -> T {
+        let me = current();
+        loop {
+            // Register before checking so the exit cannot be missed
+            if let Some(me) = &me {
+                *self.packet.joiner.lock() = Some(me.clone());
+            }
+            if let Some(result) = self.packet.result.lock().take() {
+                return result;
+            }
+            match me {
+                Some(_) => park(),
+                None => x86_64::instructions::hlt(),
+            }
+        }
+    }
+}
+
+/// Kernel threads by PID
+static THREADS: Mutex<BTreeMap<Pid, Thread>> = Mutex::new(BTreeMap::new());
+
+/// Start a kernel thread running `f`, scheduled like any process
+///
+/// Kernel threads are never preempted by the timer (see
+/// `ProcessManager::tick`): one keeps the CPU until it returns, parks or
+/// calls `yield_now`, so long-running ones yield between units of work.
+pub fn spawn<F, T>(name: &'static str, f: F) -> Result<JoinHandle<T>, ProcessError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let packet = Arc::new(Packet {
+        result: Mutex::new(None),
+        joiner: Mutex::new(None),
+    });
+    let their_packet = packet.clone();
+    let main: ThreadMain = Box::new(move || {
+        let result = f();
+        *their_packet.result.lock() = Some(result);
+        if let Some(joiner) = their_packet.joiner.lock().take() {
+            joiner.unpark();
+        }
+    });
+    let main = Box::into_raw(Box::new(main));
+
+    // Registered before the thread can look itself up
+    let mut threads = THREADS.lock();
+    let created = PROCESS_MANAGER.lock().create_kernel_thread(
+        name,
+        VirtAddr::new(thread_start as usize as u64),
+        main as u64,
+    );
+    let pid = match created {
+        Ok(pid) => pid,
+        Err(err) => {
+            drop(unsafe { Box::from_raw(main) });
+            return Err(err);
+        }
+    };
+    let thread = Thread {
+        inner: Arc::new(Inner {
+            pid,
+            name,
+            unparked: AtomicBool::new(false),
+        }),
+    };
+    threads.insert(pid, thread.clone());
+
+    Ok(JoinHandle { thread, packet })
+}
+
+/// First code a kernel thread runs, with its body in `main`
+extern "C" fn thread_start(main: *mut ThreadMain) -> ! {
+    let main = unsafe { Box::from_raw(main) };
+    main();
+
+    let pid = crate::process::current_pid().expect("kernel thread without a PID");
+    THREADS.lock().remove(&pid);
+    // Still on this thread's stack: the PCB and stack are reaped after the
+    // switch away
+    crate::process::exit(pid);
+    crate::process::run_next()
+}
+
+/// The kernel thread calling this, `None` outside kernel threads
+pub fn current() -> Option<Thread> {
+    let pid = crate::process::current_pid()?;
+    THREADS.lock().get(&pid).cloned()
+}
+
+/// Block the current kernel thread until it is unparked
+///
+/// Returns at once if an `unpark` came first. Wakeups may be spurious, so
+/// callers check their condition in a loop.
+pub fn park() {
+    let Some(thread) = current() else {
+        return;
+    };
+    {
+        let mut pm = PROCESS_MANAGER.lock();
+        if thread.inner.unparked.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        pm.block_process(thread.pid());
+    }
+    yield_now();
+}
+
+/// Give up the CPU to the next ready process
+///
+/// Must be called from a kernel thread, without holding the process manager.
+pub fn yield_now() {
+    unsafe {
+        core::arch::asm!("int {}", const crate::interrupts::YIELD_VECTOR);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{park, spawn, yield_now, THREADS};
+    use crate::kstack::KSTACK_MANAGER;
+    use crate::process::{ProcessState, PROCESS_MANAGER};
+    use crate::{serial_print, serial_println};
+
+    // Tests run before preemption starts, so yielding from the test runner
+    // is what lets the threads run, each until it blocks or exits.
+
+    #[test_case]
+    fn test_spawn_join() {
+        serial_print!("test_spawn_join... ");
+        let handle = spawn("join test", || 6 * 7).unwrap();
+        assert!(!handle.is_finished());
+
+        yield_now();
+        assert!(handle.is_finished());
+        assert_eq!(handle.join(), 42);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_park_unpark() {
+        serial_print!("test_park_unpark... ");
+        let handle = spawn("park test", || {
+            park();
+            true
+        })
+        .unwrap();
+        let pid = handle.thread().pid();
+
+        yield_now();
+        assert!(!handle.is_finished());
+        let state = PROCESS_MANAGER.lock().get_process(pid).map(|p| p.state);
+        assert_eq!(state, Some(ProcessState::Blocked));
+
+        handle.thread().unpark();
+        yield_now();
+        assert!(handle.join());
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_exited_thread_is_reaped() {
+        serial_print!("test_exited_thread_is_reaped... ");
+        let stacks = KSTACK_MANAGER.lock().count();
+        let handle = spawn("reap test", || ()).unwrap();
+        let pid = handle.thread().pid();
+        assert_eq!(KSTACK_MANAGER.lock().count(), stacks + 1);
+
+        // The thread exits on its own stack, the switch after that reaps it
+        yield_now();
+        yield_now();
+        assert!(handle.is_finished());
+        assert!(!THREADS.lock().contains_key(&pid));
+        assert!(PROCESS_MANAGER.lock().get_process(pid).is_none());
+        assert_eq!(KSTACK_MANAGER.lock().count(), stacks);
+        serial_println!("[ok]");
+    }
+}
@@ -2,27 +2,66 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
+#![cfg_attr(test, feature(custom_test_frameworks))]
+#![cfg_attr(test, test_runner(crate::test_runner))]
+#![cfg_attr(test, reexport_test_harness_main = "test_main")]
 
 extern crate alloc;
 
 use core::panic::PanicInfo;
 
+pub mod address_space;
 pub mod allocator;
+pub mod block;
+pub mod buddy;
+pub mod cmdline;
+pub mod context;
+pub mod cow;
+pub mod elf;
+pub mod fault;
 pub mod gdt;
 pub mod interrupts;
 pub mod ipc;
+pub mod kaslr;
+pub mod kstack;
+pub mod kthread;
+pub mod loader;
+pub mod lz4;
+pub mod memmap;
 pub mod memory;
+pub mod oom;
+pub mod page;
 pub mod process;
+pub mod random;
+pub mod rlimit;
+pub mod sched;
 pub mod serial;
 pub mod shm;
 pub mod signal;
+pub mod slab;
+pub mod swap;
 pub mod syscall;
+pub mod uaccess;
 pub mod vga;
+pub mod vma;
+pub mod zram;
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
+    // Runs at the load address: nothing here may use an absolute pointer
+    unsafe {
+        cmdline::init();
+        kaslr::relocate(kernel_main, !cmdline::has_flag("nokaslr"));
+    }
+}
+
+extern "C" fn kernel_main() -> ! {
     serial_println!("FractureOS Kernel v0.1.0");
     serial_println!("Initializing...");
+    if !cmdline::get().is_empty() {
+        serial_println!("[INIT] Command line: {}", cmdline::get());
+    }
+    kaslr::init();
 
     // Initialize GDT
     serial_println!("[INIT] Setting up GDT...");
@@ -32,17 +71,47 @@ pub extern "C" fn _start() -> ! {
     serial_println!("[INIT] Setting up IDT...");
     interrupts::init_idt();
 
+    // Everything the kernel touches now lives in the higher half
+    unsafe { memory::unmap_identity() };
+
     // Initialize PIC
     serial_println!("[INIT] Initializing PIC...");
     unsafe { interrupts::PICS.lock().initialize() };
+    interrupts::init_timer();
 
     // Enable interrupts
     x86_64::instructions::interrupts::enable();
     serial_println!("[INIT] Interrupts enabled");
 
+    // No-execute pages must work before anything maps them
+    memory::enable_nx();
+
+    // Initialize physical memory from the firmware memory map
+    serial_println!("[INIT] Reading boot memory map...");
+    let physical_memory_offset = memory::physical_memory_offset();
+    unsafe {
+        // The E820 table sits in low memory, which the kernel window covers
+        let memory_map = memmap::MemoryMap::from_e820(kaslr::low_memory());
+        memory::init_direct_map(memory_map.max_physical_address());
+        memory::init_physical(memory_map, physical_memory_offset);
+        page::init(physical_memory_offset);
+        buddy::init(physical_memory_offset);
+    }
+
+    // Initialize the kernel heap
+    serial_println!("[INIT] Setting up kernel heap...");
+    let mut mapper = unsafe { memory::init(physical_memory_offset) };
+    allocator::init_heap(&mut mapper, &mut buddy::GlobalFrameAllocator)
+        .expect("heap initialization failed");
+    *memory::KERNEL_MAPPER.lock() = Some(mapper);
+    kstack::init();
+    address_space::init();
+    uaccess::init();
+
     // Initialize process management
     serial_println!("[INIT] Initializing process manager...");
     process::init();
+    oom::init();
 
     // Initialize IPC
     serial_println!("[INIT] Initializing IPC...");
@@ -56,30 +125,77 @@ pub extern "C" fn _start() -> ! {
     serial_println!("[INIT] Initializing shared memory...");
     shm::init();
 
+    // Initialize swap
+    serial_println!("[INIT] Looking for swap space...");
+    swap::init();
+
     // Initialize system calls
     serial_println!("[INIT] Initializing system calls...");
     syscall::init();
+    loader::init();
 
     // Print system info
     serial_println!("\n=== FractureOS System Info ===");
     serial_println!("Kernel: v0.1.0");
     serial_println!("Architecture: x86_64");
     serial_println!("Process Manager: Ready");
-    serial_println!("Heap Size: {} KB", allocator::HEAP_SIZE / 1024);
+    serial_println!(
+        "Heap Size: {} KB (limit {} KB)",
+        allocator::heap_size() / 1024,
+        allocator::heap_limit() / 1024
+    );
     serial_println!("==============================\n");
 
     serial_println!("Kernel initialized successfully");
+    #[cfg(test)]
+    test_main();
+    process::start_preemption();
     serial_println!("Entering idle loop...");
 
     hlt_loop();
 }
 
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     serial_println!("KERNEL PANIC: {}", info);
     hlt_loop();
 }
 
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("[failed]");
+    serial_println!("Error: {}", info);
+    exit_qemu(QemuExitCode::Failed);
+}
+
+/// Status reported through QEMU's isa-debug-exit device
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Leave QEMU with `code`, started with `-device isa-debug-exit,iobase=0xf4,iosize=0x04`
+#[cfg(test)]
+fn exit_qemu(code: QemuExitCode) -> ! {
+    unsafe { x86_64::instructions::port::Port::new(0xf4).write(code as u32) };
+    hlt_loop();
+}
+
+/// Run the in-crate tests once the kernel is initialized
+#[cfg(test)]
+fn test_runner(tests: &[&dyn Fn()]) {
+    serial_println!("Running {} tests", tests.len());
+    for test in tests {
+        test();
+    }
+    exit_qemu(QemuExitCode::Success);
+}
+
 pub fn hlt_loop() -> ! {
     loop {
         x86_64::instructions::hlt();
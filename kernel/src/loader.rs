@@ -0,0 +1,257 @@
+use crate::address_space::AddressSpace;
+use crate::context::CpuContext;
+use crate::elf::{ElfError, ElfFile, ElfKind, PHDR_SIZE};
+use crate::process::{self, Pid};
+use crate::rlimit::Resource;
+use crate::vma::{VmProtection, VmaError, MMAP_BASE, USER_SPACE_END};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::{
+    structures::paging::{PageSize, Size2MiB, Size4KiB},
+    VirtAddr,
+};
+
+/// Top of the user stack without randomization
+pub const STACK_TOP: u64 = USER_SPACE_END - Size4KiB::SIZE;
+
+/// Size of the stack area of a new program
+pub const STACK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Load address of position independent programs without randomization
+pub const PIE_BASE: u64 = 0x0000_0100_0000_0000;
+
+/// Ranges the stack top, PIE base, mmap base and brk start move within
+const STACK_RANDOM_RANGE: u64 = 16 * 1024 * 1024 * 1024;
+const PIE_RANDOM_RANGE: u64 = 0x0000_0100_0000_0000;
+const MMAP_RANDOM_RANGE: u64 = 0x0000_0100_0000_0000;
+const BRK_RANDOM_RANGE: u64 = 32 * 1024 * 1024;
+
+/// Auxiliary vector keys
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+/// Whether user layouts are randomized, cleared by `norandmaps`
+static RANDOMIZE: AtomicBool = AtomicBool::new(true);
+
+/// Program loading errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecError {
+    BadImage(ElfError),
+    /// A segment is writable and executable without the JIT permission
+    WriteExec,
+    /// The image does not fit the address space limit
+    LimitExceeded,
+    OutOfMemory,
+    NotFound,
+}
+
+impl From<VmaError> for ExecError {
+    fn from(err: VmaError) -> Self {
+        match err {
+            VmaError::WriteExec => ExecError::WriteExec,
+            VmaError::NoSpace | VmaError::OutOfMemory => ExecError::OutOfMemory,
+            _ => ExecError::BadImage(ElfError::BadSegment),
+        }
+    }
+}
+
+/// Where the parts of a new program go
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserLayout {
+    /// Offset added to every address of a position independent program
+    pub load_bias: u64,
+    pub mmap_base: u64,
+    /// Gap between the end of the image and the start of brk
+    pub brk_gap: u64,
+    pub stack_top: u64,
+}
+
+/// A random multiple of `align` below `range`, or 0 without randomization
+fn random_offset(range: u64, align: u64) -> u64 {
+    if RANDOMIZE.load(Ordering::Relaxed) {
+        crate::random::random_below(range / align) * align
+    } else {
+        0
+    }
+}
+
+impl UserLayout {
+    /// Pick a fresh layout for one exec
+    pub fn choose() -> Self {
+        Self {
+            load_bias: PIE_BASE + random_offset(PIE_RANDOM_RANGE, Size2MiB::SIZE),
+            mmap_base: MMAP_BASE + random_offset(MMAP_RANDOM_RANGE, Size4KiB::SIZE),
+            brk_gap: random_offset(BRK_RANDOM_RANGE, Size4KiB::SIZE),
+            stack_top: STACK_TOP - random_offset(STACK_RANDOM_RANGE, Size4KiB::SIZE),
+        }
+    }
+}
+
+/// Where a loaded program starts running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Program {
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+}
+
+/// Map the segments of `elf` and its stack into `space`
+fn load_image(
+    space: &mut AddressSpace,
+    elf: &ElfFile,
+    layout: &UserLayout,
+) -> Result<Program, ExecError> {
+    let bias = match elf.kind {
+        ElfKind::Executable => 0,
+        ElfKind::Dynamic => layout.load_bias,
+    };
+    let bad_segment = ExecError::BadImage(ElfError::BadSegment);
+
+    let mut image_end = VirtAddr::zero();
+    for segment in elf.load_segments().filter(|s| s.memsz > 0) {
+        let vaddr = segment.vaddr.checked_add(bias).ok_or(bad_segment)?;
+        let end = vaddr
+            .checked_add(segment.memsz)
+            .filter(|&end| end <= USER_SPACE_END)
+            .ok_or(bad_segment)?;
+        let start = VirtAddr::new(vaddr).align_down(Size4KiB::SIZE);
+        let end = VirtAddr::new(end).align_up(Size4KiB::SIZE);
+        if space.vmas.overlaps(start, end) {
+            return Err(bad_segment);
+        }
+
+        space.map_anonymous(Some(start), end - start, segment.protection(), false, true)?;
+        space.populate(VirtAddr::new(vaddr), elf.segment_data(&segment))?;
+        image_end = core::cmp::max(image_end, end);
+    }
+    if image_end.is_null() {
+        return Err(bad_segment);
+    }
+
+    space.init_brk(image_end + layout.brk_gap);
+    space.set_mmap_base(VirtAddr::new(layout.mmap_base));
+
+    let stack_top = VirtAddr::new(layout.stack_top);
+    let protection = VmProtection::READ | VmProtection::WRITE;
+    space.map_anonymous(
+        Some(stack_top - STACK_SIZE),
+        STACK_SIZE,
+        protection,
+        false,
+        true,
+    )?;
+
+    let entry = elf.entry.checked_add(bias).ok_or(bad_segment)?;
+    let stack_pointer = build_stack(space, elf, bias, entry, stack_top)?;
+    Ok(Program {
+        entry: VirtAddr::try_new(entry).map_err(|_| bad_segment)?,
+        stack_pointer,
+    })
+}
+
+/// Lay out argc, argv, envp and the auxiliary vector below `top`
+///
+/// There are no arguments or environment yet, so both lists are empty.
+fn build_stack(
+    space: &mut AddressSpace,
+    elf: &ElfFile,
+    bias: u64,
+    entry: u64,
+    top: VirtAddr,
+) -> Result<VirtAddr, ExecError> {
+    // Seed for stack protectors and the like, random even with norandmaps
+    let random_at = top - 16u64;
+    let mut seed = [0u8; 16];
+    seed[..8].copy_from_slice(&crate::random::random_u64().to_le_bytes());
+    seed[8..].copy_from_slice(&crate::random::random_u64().to_le_bytes());
+    space.populate(random_at, &seed)?;
+
+    let mut words: Vec<u64> = Vec::new();
+    words.extend([0, 0, 0]);
+    if let Some(phdr) = elf.phdr_vaddr() {
+        words.extend([AT_PHDR, phdr + bias]);
+    }
+    words.extend([
+        AT_PHENT,
+        PHDR_SIZE as u64,
+        AT_PHNUM,
+        elf.phnum as u64,
+        AT_PAGESZ,
+        Size4KiB::SIZE,
+        AT_ENTRY,
+        entry,
+        AT_RANDOM,
+        random_at.as_u64(),
+        AT_NULL,
+        0,
+    ]);
+
+    let stack_pointer = (random_at - words.len() as u64 * 8).align_down(16u64);
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    space.populate(stack_pointer, &bytes)?;
+    Ok(stack_pointer)
+}
+
+/// Replace the program of process `pid` with the ELF image `image`
+///
+/// The new image is built in a fresh address space with a freshly chosen
+/// layout, so a failed exec leaves the process untouched. The JIT
+/// permission carries over.
+pub fn exec(pid: Pid, image: &[u8]) -> Result<Program, ExecError> {
+    let elf = ElfFile::parse(image).map_err(ExecError::BadImage)?;
+    let (allow_write_exec, limit) = process::with_process(pid, |process| {
+        let jit = process
+            .address_space
+            .as_ref()
+            .is_some_and(AddressSpace::allows_write_exec);
+        (jit, process.limits.get(Resource::AddressSpace))
+    })
+    .ok_or(ExecError::NotFound)?;
+
+    let layout = UserLayout::choose();
+    let mut space = AddressSpace::new(pid).ok_or(ExecError::OutOfMemory)?;
+    space.set_allow_write_exec(allow_write_exec);
+    let program = load_image(&mut space, &elf, &layout)?;
+    if !limit.allows(space.vmas.total_size()) {
+        return Err(ExecError::LimitExceeded);
+    }
+
+    let brk = space.brk();
+    process::with_process(pid, move |process| {
+        // Switch first so the old space is not active when it is dropped
+        if process
+            .address_space
+            .as_ref()
+            .is_some_and(AddressSpace::is_active)
+        {
+            unsafe { space.activate() };
+        }
+        process.address_space = Some(space);
+        process.context = Some(CpuContext::user(program.entry, program.stack_pointer));
+    })
+    .ok_or(ExecError::NotFound)?;
+
+    crate::serial_println!(
+        "[EXEC] PID {}: entry {:#x}, stack {:#x}, brk {:#x}, mmap {:#x}",
+        pid,
+        program.entry.as_u64(),
+        program.stack_pointer.as_u64(),
+        brk.as_u64(),
+        layout.mmap_base
+    );
+    Ok(program)
+}
+
+/// Read the randomization switch from the command line
+pub fn init() {
+    let randomize = !crate::cmdline::has_flag("norandmaps");
+    RANDOMIZE.store(randomize, Ordering::Relaxed);
+    crate::serial_println!(
+        "[EXEC] User address space randomization {}",
+        if randomize { "enabled" } else { "disabled" }
+    );
+}
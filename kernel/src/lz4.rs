@@ -0,0 +1,264 @@
+/// Shortest match the format can encode
+const MIN_MATCH: usize = 4;
+
+/// The last match must start at least this far from the end of the input
+const MF_LIMIT: usize = 12;
+
+/// The last bytes of the input are always encoded as literals
+const LAST_LITERALS: usize = 5;
+
+/// Largest distance a match may point back
+const MAX_OFFSET: usize = u16::MAX as usize;
+
+const HASH_LOG: u32 = 10;
+const HASH_SIZE: usize = 1 << HASH_LOG;
+
+/// LZ4 decoding errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lz4Error {
+    /// The input is not a valid LZ4 block
+    Corrupt,
+    /// The output buffer is too small for the decoded data
+    OutputTooSmall,
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]])
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+/// Bounded output cursor, `None` once the buffer is full
+struct Output<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Output<'_> {
+    fn push(&mut self, byte: u8) -> Option<()> {
+        *self.buf.get_mut(self.pos)? = byte;
+        self.pos += 1;
+        Some(())
+    }
+
+    fn extend(&mut self, bytes: &[u8]) -> Option<()> {
+        self.buf
+            .get_mut(self.pos..self.pos + bytes.len())?
+            .copy_from_slice(bytes);
+        self.pos += bytes.len();
+        Some(())
+    }
+
+    /// Write the part of a length that did not fit in the token
+    fn length(&mut self, mut len: usize) -> Option<()> {
+        while len >= 255 {
+            self.push(255)?;
+            len -= 255;
+        }
+        self.push(len as u8)
+    }
+
+    /// Write one sequence; the final one has no match
+    fn sequence(&mut self, literals: &[u8], found: Option<(usize, usize)>) -> Option<()> {
+        let lit_len = literals.len();
+        let match_len = found.map_or(0, |(_, len)| len - MIN_MATCH);
+        self.push((lit_len.min(15) << 4) as u8 | match_len.min(15) as u8)?;
+        if lit_len >= 15 {
+            self.length(lit_len - 15)?;
+        }
+        self.extend(literals)?;
+
+        if let Some((offset, _)) = found {
+            self.extend(&(offset as u16).to_le_bytes())?;
+            if match_len >= 15 {
+                self.length(match_len - 15)?;
+            }
+        }
+        Some(())
+    }
+}
+
+/// Greedy LZ4 block compressor
+///
+/// Keeps its hash table between calls so none of it lives on the stack.
+pub struct Compressor {
+    /// Last position + 1 of each hashed 4-byte sequence, 0 if none
+    table: [u32; HASH_SIZE],
+}
+
+impl Compressor {
+    pub const fn new() -> Self {
+        Self {
+            table: [0; HASH_SIZE],
+        }
+    }
+
+    /// Compress `src` into `dst` as one LZ4 block
+    ///
+    /// Returns the compressed size, or `None` if it does not fit in `dst`.
+    pub fn compress(&mut self, src: &[u8], dst: &mut [u8]) -> Option<usize> {
+        let mut out = Output { buf: dst, pos: 0 };
+        let mut anchor = 0;
+        let mut pos = 0;
+        self.table.fill(0);
+
+        if src.len() > MF_LIMIT {
+            let match_limit = src.len() - MF_LIMIT;
+            let match_end = src.len() - LAST_LITERALS;
+
+            while pos < match_limit {
+                let sequence = read_u32(src, pos);
+                let slot = &mut self.table[hash(sequence)];
+                let candidate = *slot as usize;
+                *slot = pos as u32 + 1;
+
+                if candidate == 0
+                    || pos - (candidate - 1) > MAX_OFFSET
+                    || read_u32(src, candidate - 1) != sequence
+                {
+                    pos += 1;
+                    continue;
+                }
+
+                let start = candidate - 1;
+                let mut len = MIN_MATCH;
+                while pos + len < match_end && src[start + len] == src[pos + len] {
+                    len += 1;
+                }
+
+                out.sequence(&src[anchor..pos], Some((pos - start, len)))?;
+                pos += len;
+                anchor = pos;
+            }
+        }
+
+        out.sequence(&src[anchor..], None)?;
+        Some(out.pos)
+    }
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Read the part of a length that did not fit in the token
+fn read_length(src: &[u8], pos: &mut usize) -> Result<usize, Lz4Error> {
+    let mut len = 0;
+    loop {
+        let byte = *src.get(*pos).ok_or(Lz4Error::Corrupt)?;
+        *pos += 1;
+        len += byte as usize;
+        if byte != 255 {
+            return Ok(len);
+        }
+    }
+}
+
+/// Decode one LZ4 block from `src` into `dst`, returning the decoded size
+pub fn decompress(src: &[u8], dst: &mut [u8]) -> Result<usize, Lz4Error> {
+    let mut ip = 0;
+    let mut op = 0;
+
+    loop {
+        let token = *src.get(ip).ok_or(Lz4Error::Corrupt)?;
+        ip += 1;
+
+        let mut lit_len = (token >> 4) as usize;
+        if lit_len == 15 {
+            lit_len += read_length(src, &mut ip)?;
+        }
+        let literals = src.get(ip..ip + lit_len).ok_or(Lz4Error::Corrupt)?;
+        dst.get_mut(op..op + lit_len)
+            .ok_or(Lz4Error::OutputTooSmall)?
+            .copy_from_slice(literals);
+        ip += lit_len;
+        op += lit_len;
+
+        // The last sequence ends with its literals
+        if ip == src.len() {
+            return Ok(op);
+        }
+
+        let offset = src.get(ip..ip + 2).ok_or(Lz4Error::Corrupt)?;
+        let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+        ip += 2;
+        if offset == 0 || offset > op {
+            return Err(Lz4Error::Corrupt);
+        }
+
+        let mut match_len = (token & 0x0F) as usize;
+        if match_len == 15 {
+            match_len += read_length(src, &mut ip)?;
+        }
+        match_len += MIN_MATCH;
+        if op + match_len > dst.len() {
+            return Err(Lz4Error::OutputTooSmall);
+        }
+
+        // Byte by byte, matches may overlap their own output
+        for i in op..op + match_len {
+            dst[i] = dst[i - offset];
+        }
+        op += match_len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decompress, Compressor, Lz4Error};
+    use crate::{serial_print, serial_println};
+
+    static mut COMPRESSOR: Compressor = Compressor::new();
+
+    fn round_trip(page: &[u8; 4096]) -> usize {
+        let mut compressed = [0u8; 4096 + 64];
+        let mut decoded = [0u8; 4096];
+        let compressor = unsafe { &mut *core::ptr::addr_of_mut!(COMPRESSOR) };
+        let len = compressor
+            .compress(page, &mut compressed)
+            .expect("output buffer too small");
+        assert_eq!(decompress(&compressed[..len], &mut decoded), Ok(4096));
+        assert_eq!(&decoded[..], &page[..]);
+        len
+    }
+
+    #[test_case]
+    fn test_repetitive_page_shrinks() {
+        serial_print!("test_repetitive_page_shrinks... ");
+        let mut page = [0u8; 4096];
+        for (i, byte) in page.iter_mut().enumerate() {
+            *byte = b"fracture"[i % 8];
+        }
+        assert!(round_trip(&page) < 100);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_random_page_round_trips() {
+        serial_print!("test_random_page_round_trips... ");
+        let mut page = [0u8; 4096];
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        for byte in page.iter_mut() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            *byte = state as u8;
+        }
+        round_trip(&page);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_corrupt_offset_is_rejected() {
+        serial_print!("test_corrupt_offset_is_rejected... ");
+        let mut out = [0u8; 64];
+        // One literal, then a match reaching back past the start
+        let block = [0x10, b'a', 0x05, 0x00];
+        assert_eq!(decompress(&block, &mut out), Err(Lz4Error::Corrupt));
+        serial_println!("[ok]");
+    }
+}
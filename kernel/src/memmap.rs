@@ -0,0 +1,427 @@
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Physical address where stage 2 stores the BIOS E820 map
+pub const E820_MAP_ADDR: u64 = 0x5000;
+
+/// Maximum number of E820 entries stage 2 collects
+pub const E820_MAX_ENTRIES: usize = 128;
+
+/// Maximum number of regions a memory map can hold
+pub const MAX_MEMORY_REGIONS: usize = 128;
+
+/// Start of the legacy VGA/BIOS hole
+const LEGACY_HOLE_START: u64 = 0xA0000;
+
+/// End of the legacy VGA/BIOS hole (and of low memory)
+const LEGACY_HOLE_END: u64 = 0x100000;
+
+/// Low memory used by the real-mode IVT, BDA, boot page tables and stage 2
+const BOOTLOADER_END: u64 = 0x10000;
+
+const FRAME_SIZE: u64 = 4096;
+
+/// Size of a UEFI memory descriptor as the specification defines it
+const UEFI_DESCRIPTOR_SIZE: usize = 40;
+
+/// Physical memory region type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryRegionKind {
+    /// Free RAM that can be handed out by the frame allocator
+    Usable,
+    /// Firmware or device memory that must never be touched
+    Reserved,
+    /// ACPI tables, reusable once they have been parsed
+    AcpiReclaimable,
+    /// ACPI non-volatile storage
+    AcpiNvs,
+    /// RAM reported as defective
+    BadMemory,
+    /// Memory still used by the bootloader (page tables, memory map)
+    Bootloader,
+    /// Kernel image and early kernel data structures
+    Kernel,
+}
+
+impl MemoryRegionKind {
+    /// Translate an E820 / multiboot2 region type
+    pub fn from_e820(kind: u32) -> Self {
+        match kind {
+            1 => Self::Usable,
+            3 => Self::AcpiReclaimable,
+            4 => Self::AcpiNvs,
+            5 => Self::BadMemory,
+            _ => Self::Reserved,
+        }
+    }
+
+    /// Translate a UEFI memory descriptor type
+    pub fn from_uefi(kind: u32) -> Self {
+        match kind {
+            // Boot services code/data and conventional memory
+            3 | 4 | 7 => Self::Usable,
+            // Loader code/data
+            1 | 2 => Self::Bootloader,
+            8 => Self::BadMemory,
+            9 => Self::AcpiReclaimable,
+            10 => Self::AcpiNvs,
+            _ => Self::Reserved,
+        }
+    }
+}
+
+/// A contiguous physical memory region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: PhysAddr,
+    pub end: PhysAddr,
+    pub kind: MemoryRegionKind,
+}
+
+impl MemoryRegion {
+    const EMPTY: Self = Self {
+        start: PhysAddr::zero(),
+        end: PhysAddr::zero(),
+        kind: MemoryRegionKind::Reserved,
+    };
+
+    pub fn new(start: u64, end: u64, kind: MemoryRegionKind) -> Self {
+        Self {
+            start: PhysAddr::new(start),
+            end: PhysAddr::new(end),
+            kind,
+        }
+    }
+
+    /// Size of the region in bytes
+    pub fn size(&self) -> u64 {
+        self.end.as_u64() - self.start.as_u64()
+    }
+}
+
+/// BIOS E820 entry as stored by stage 2
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct E820Entry {
+    base: u64,
+    length: u64,
+    kind: u32,
+    acpi: u32,
+}
+
+/// Physical memory map, sorted by address with no overlapping regions
+#[derive(Debug, Clone)]
+pub struct MemoryMap {
+    regions: [MemoryRegion; MAX_MEMORY_REGIONS],
+    len: usize,
+}
+
+impl MemoryMap {
+    /// Create an empty memory map
+    pub const fn new() -> Self {
+        Self {
+            regions: [MemoryRegion::EMPTY; MAX_MEMORY_REGIONS],
+            len: 0,
+        }
+    }
+
+    /// Build the memory map from the E820 entries collected by stage 2
+    ///
+    /// # Safety
+    /// The E820 buffer at `E820_MAP_ADDR` must be mapped at `physical_memory_offset`
+    pub unsafe fn from_e820(physical_memory_offset: VirtAddr) -> Self {
+        let base = physical_memory_offset + E820_MAP_ADDR;
+        let count = core::ptr::read_volatile(base.as_ptr::<u32>()) as usize;
+        let entries = core::slice::from_raw_parts(
+            (base + 8u64).as_ptr::<E820Entry>(),
+            core::cmp::min(count, E820_MAX_ENTRIES),
+        );
+
+        let mut map = Self::new();
+        map.add_entries(entries.iter().map(|e| {
+            let (base, length, kind) = (e.base, e.length, e.kind);
+            (
+                base,
+                base.saturating_add(length),
+                MemoryRegionKind::from_e820(kind),
+            )
+        }));
+        map
+    }
+
+    /// Build the memory map from a multiboot2 boot information structure
+    ///
+    /// # Safety
+    /// `info` must point to a valid multiboot2 information structure
+    pub unsafe fn from_multiboot2(info: VirtAddr) -> Self {
+        const TAG_END: u32 = 0;
+        const TAG_MEMORY_MAP: u32 = 6;
+
+        let mut map = Self::new();
+        let total_size = *info.as_ptr::<u32>() as u64;
+        let mut tag = info + 8u64;
+
+        while tag.as_u64() < info.as_u64() + total_size {
+            let tag_type = *tag.as_ptr::<u32>();
+            let tag_size = *(tag + 4u64).as_ptr::<u32>() as u64;
+
+            if tag_type == TAG_END {
+                break;
+            }
+
+            if tag_type == TAG_MEMORY_MAP {
+                let entry_size = *(tag + 8u64).as_ptr::<u32>() as u64;
+                let mut entry = tag + 16u64;
+                let mut entries = [(0u64, 0u64, MemoryRegionKind::Reserved); MAX_MEMORY_REGIONS];
+                let mut count = 0;
+
+                while entry.as_u64() < tag.as_u64() + tag_size && count < MAX_MEMORY_REGIONS {
+                    let raw = core::ptr::read_unaligned(entry.as_ptr::<E820Entry>());
+                    let (base, length, kind) = (raw.base, raw.length, raw.kind);
+                    entries[count] = (
+                        base,
+                        base.saturating_add(length),
+                        MemoryRegionKind::from_e820(kind),
+                    );
+                    count += 1;
+                    entry += entry_size;
+                }
+
+                map.add_entries(entries[..count].iter().copied());
+            }
+
+            // Tags are padded to 8 bytes
+            tag += (tag_size + 7) & !7;
+        }
+
+        map
+    }
+
+    /// Build the memory map from a UEFI memory map
+    ///
+    /// # Safety
+    /// `descriptors` must point to `map_size` bytes of UEFI memory descriptors
+    pub unsafe fn from_uefi(
+        descriptors: VirtAddr,
+        map_size: usize,
+        descriptor_size: usize,
+    ) -> Self {
+        let mut map = Self::new();
+        // Firmware may pad descriptors, but never make them smaller
+        if descriptor_size < UEFI_DESCRIPTOR_SIZE {
+            crate::serial_println!(
+                "[MEMMAP] Bad UEFI descriptor size {}, ignoring memory map",
+                descriptor_size
+            );
+            return map;
+        }
+        let mut entries = [(0u64, 0u64, MemoryRegionKind::Reserved); MAX_MEMORY_REGIONS];
+        let count = core::cmp::min(map_size / descriptor_size, MAX_MEMORY_REGIONS);
+
+        for (i, entry) in entries.iter_mut().enumerate().take(count) {
+            let desc = descriptors + (i * descriptor_size) as u64;
+            let kind = *desc.as_ptr::<u32>();
+            let start = *(desc + 8u64).as_ptr::<u64>();
+            let pages = *(desc + 24u64).as_ptr::<u64>();
+            *entry = (
+                start,
+                start.saturating_add(pages * FRAME_SIZE),
+                MemoryRegionKind::from_uefi(kind),
+            );
+        }
+
+        map.add_entries(entries[..count].iter().copied());
+        map
+    }
+
+    /// Add firmware entries, letting non-usable entries win on overlap
+    fn add_entries(&mut self, entries: impl Iterator<Item = (u64, u64, MemoryRegionKind)> + Clone) {
+        for (start, end, kind) in entries.clone() {
+            if kind == MemoryRegionKind::Usable {
+                // Only whole frames are usable
+                let start = align_up(start, FRAME_SIZE);
+                let end = align_down(end, FRAME_SIZE);
+                if start < end {
+                    self.mark(start, end, kind);
+                }
+            }
+        }
+
+        for (start, end, kind) in entries {
+            if kind != MemoryRegionKind::Usable && start < end {
+                self.mark(start, end, kind);
+            }
+        }
+
+        // Never hand out the IVT/BDA, boot structures or the legacy VGA/BIOS hole
+        self.mark(0, BOOTLOADER_END, MemoryRegionKind::Bootloader);
+        self.mark(
+            LEGACY_HOLE_START,
+            LEGACY_HOLE_END,
+            MemoryRegionKind::Reserved,
+        );
+    }
+
+    /// Mark the kernel image as occupied
+    pub fn reserve_kernel(&mut self) {
+        extern "C" {
+            static __kernel_start: u8;
+            static __kernel_end: u8;
+        }
+
+        // The linker symbols are virtual addresses in the kernel window
+        let (start, end) = unsafe {
+            (
+                crate::kaslr::image_phys(&__kernel_start).as_u64(),
+                crate::kaslr::image_phys(&__kernel_end).as_u64(),
+            )
+        };
+        self.mark(
+            align_down(start, FRAME_SIZE),
+            align_up(end, FRAME_SIZE),
+            MemoryRegionKind::Kernel,
+        );
+    }
+
+    /// Mark `start..end` as `kind`, splitting any regions it overlaps
+    pub fn mark(&mut self, start: u64, end: u64, kind: MemoryRegionKind) {
+        if start >= end {
+            return;
+        }
+
+        let mut new_regions = [MemoryRegion::EMPTY; MAX_MEMORY_REGIONS];
+        let mut new_len = 0;
+        let mut push = |region: MemoryRegion| {
+            if region.start >= region.end {
+                return;
+            }
+            if new_len == MAX_MEMORY_REGIONS {
+                // Left out of the map, the range is never handed out
+                crate::serial_println!(
+                    "[MEMMAP] Region table full, dropping {:#x}-{:#x} ({:?})",
+                    region.start.as_u64(),
+                    region.end.as_u64(),
+                    region.kind
+                );
+                return;
+            }
+            new_regions[new_len] = region;
+            new_len += 1;
+        };
+
+        for region in self.regions() {
+            let (r_start, r_end) = (region.start.as_u64(), region.end.as_u64());
+            if r_end <= start || r_start >= end {
+                push(*region);
+                continue;
+            }
+            // Keep the parts of the old region outside the new one
+            push(MemoryRegion::new(r_start, start, region.kind));
+            push(MemoryRegion::new(end, r_end, region.kind));
+        }
+
+        push(MemoryRegion::new(start, end, kind));
+
+        new_regions[..new_len].sort_unstable_by_key(|r| r.start);
+        self.regions = new_regions;
+        self.len = new_len;
+        self.coalesce();
+    }
+
+    /// Merge adjacent regions of the same kind
+    fn coalesce(&mut self) {
+        let mut i = 0;
+        while i + 1 < self.len {
+            let (current, next) = (self.regions[i], self.regions[i + 1]);
+            if current.kind == next.kind && current.end == next.start {
+                self.regions[i].end = next.end;
+                self.regions.copy_within(i + 2..self.len, i + 1);
+                self.len -= 1;
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Get all regions
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions[..self.len]
+    }
+
+    /// Iterate over regions of a given kind
+    pub fn regions_of(&self, kind: MemoryRegionKind) -> impl Iterator<Item = &MemoryRegion> {
+        self.regions().iter().filter(move |r| r.kind == kind)
+    }
+
+    /// Total bytes of a given kind
+    pub fn total_of(&self, kind: MemoryRegionKind) -> u64 {
+        self.regions_of(kind).map(|r| r.size()).sum()
+    }
+
+    /// Highest physical address covered by RAM
+    pub fn max_physical_address(&self) -> PhysAddr {
+        self.regions()
+            .iter()
+            .filter(|r| r.kind != MemoryRegionKind::Reserved)
+            .map(|r| r.end)
+            .max()
+            .unwrap_or(PhysAddr::zero())
+    }
+
+    /// Find `size` bytes of usable memory and mark them as kernel-occupied
+    pub fn allocate(&mut self, size: u64) -> Option<PhysAddr> {
+        let size = align_up(size, FRAME_SIZE);
+        let start = self
+            .regions_of(MemoryRegionKind::Usable)
+            .find(|r| r.size() >= size)?
+            .start;
+        self.mark(
+            start.as_u64(),
+            start.as_u64() + size,
+            MemoryRegionKind::Kernel,
+        );
+        Some(start)
+    }
+
+    /// Log the memory map to serial
+    pub fn dump(&self) {
+        for region in self.regions() {
+            crate::serial_println!(
+                "[MEM] {:#012x}-{:#012x} {:?}",
+                region.start.as_u64(),
+                region.end.as_u64(),
+                region.kind
+            );
+        }
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+fn align_down(addr: u64, align: u64) -> u64 {
+    addr & !(align - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryMap, UEFI_DESCRIPTOR_SIZE};
+    use crate::{serial_print, serial_println};
+    use x86_64::VirtAddr;
+
+    #[test_case]
+    fn test_uefi_map_with_short_descriptors_is_empty() {
+        serial_print!("test_uefi_map_with_short_descriptors_is_empty... ");
+        for size in [0, UEFI_DESCRIPTOR_SIZE - 1] {
+            let map = unsafe { MemoryMap::from_uefi(VirtAddr::zero(), 4096, size) };
+            assert!(map.regions().is_empty());
+        }
+        serial_println!("[ok]");
+    }
+}
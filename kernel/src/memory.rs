@@ -1,19 +1,168 @@
+use crate::memmap::{MemoryMap, MemoryRegionKind, MAX_MEMORY_REGIONS};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
 use x86_64::{
+    instructions::tlb,
+    registers::{
+        control::Cr3,
+        model_specific::{Efer, EferFlags},
+    },
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTable,
-        PageTableFlags, PhysFrame, Size4KiB,
+        mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
+/// Direct map base used when the layout is not randomized
 pub const PHYSICAL_MEMORY_OFFSET: u64 = 0xFFFF_8000_0000_0000;
 
+/// Physical memory covered by the direct map without 1 GiB page support
+pub const DIRECT_MAP_MAX_2MIB: u64 = 16 * Size1GiB::SIZE;
+
+/// Virtual address where all physical memory is mapped
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(crate::kaslr::direct_map_base())
+}
+
+/// Check whether the CPU supports 1 GiB pages
+pub fn has_1gib_pages() -> bool {
+    // CPUID.80000001h:EDX.Page1GB[bit 26]
+    let extended = unsafe { core::arch::x86_64::__cpuid(0x8000_0000) };
+    extended.eax >= 0x8000_0001
+        && unsafe { core::arch::x86_64::__cpuid(0x8000_0001) }.edx & (1 << 26) != 0
+}
+
+/// Whether EFER.NXE is set and the NO_EXECUTE bit may be used
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enable no-execute pages where the CPU supports them
+///
+/// Without NXE the NO_EXECUTE bit is reserved, so `page_flags` strips it.
+pub fn enable_nx() {
+    // CPUID.80000001h:EDX.NX[bit 20]
+    let extended = unsafe { core::arch::x86_64::__cpuid(0x8000_0000) };
+    let supported = extended.eax >= 0x8000_0001
+        && unsafe { core::arch::x86_64::__cpuid(0x8000_0001) }.edx & (1 << 20) != 0;
+
+    if supported {
+        unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+    }
+    NX_ENABLED.store(supported, Ordering::Relaxed);
+    crate::serial_println!(
+        "[MEM] NX {}",
+        if supported { "enabled" } else { "unsupported" }
+    );
+}
+
+/// Check whether no-execute pages are enforced
+pub fn nx_enabled() -> bool {
+    NX_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Adjust `flags` to what the CPU accepts in a leaf entry
+pub fn page_flags(flags: PageTableFlags) -> PageTableFlags {
+    if nx_enabled() {
+        flags
+    } else {
+        flags - PageTableFlags::NO_EXECUTE
+    }
+}
+
+/// Page tables of the direct map
+///
+/// They live in the kernel image so they are reachable through the kernel
+/// window before any frame allocator exists.
+#[repr(C, align(4096))]
+struct DirectMapTables {
+    pdpt: PageTable,
+    pds: [PageTable; (DIRECT_MAP_MAX_2MIB / Size1GiB::SIZE) as usize],
+}
+
+static mut DIRECT_MAP_TABLES: DirectMapTables = DirectMapTables {
+    pdpt: PageTable::new(),
+    pds: [const { PageTable::new() }; (DIRECT_MAP_MAX_2MIB / Size1GiB::SIZE) as usize],
+};
+
+/// Map all physical memory below `max_phys` at `physical_memory_offset()`
+///
+/// Uses 1 GiB pages when the CPU has them and 2 MiB pages otherwise, so the
+/// whole direct map costs at most a handful of page tables. Returns the
+/// number of bytes mapped.
+///
+/// # Safety
+/// Must be called once, while the boot PML4 is active
+pub unsafe fn init_direct_map(max_phys: PhysAddr) -> u64 {
+    let tables = &mut *core::ptr::addr_of_mut!(DIRECT_MAP_TABLES);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let huge = flags | PageTableFlags::HUGE_PAGE;
+    let phys = |table: &PageTable| crate::kaslr::image_phys(table);
+
+    let use_1gib = has_1gib_pages();
+    let limit = if use_1gib {
+        512 * Size1GiB::SIZE
+    } else {
+        DIRECT_MAP_MAX_2MIB
+    };
+    let size = core::cmp::min(max_phys.as_u64().next_multiple_of(Size1GiB::SIZE), limit);
+
+    for i in 0..(size / Size1GiB::SIZE) as usize {
+        let base = i as u64 * Size1GiB::SIZE;
+        if use_1gib {
+            tables.pdpt[i].set_addr(PhysAddr::new(base), huge);
+        } else {
+            let pd = &mut tables.pds[i];
+            for (j, entry) in pd.iter_mut().enumerate() {
+                entry.set_addr(PhysAddr::new(base + j as u64 * Size2MiB::SIZE), huge);
+            }
+            tables.pdpt[i].set_addr(phys(pd), flags);
+        }
+    }
+
+    let pml4 = boot_pml4();
+    let index = physical_memory_offset().p4_index();
+    pml4[index].set_addr(phys(&tables.pdpt), flags);
+    tlb::flush_all();
+
+    crate::serial_println!(
+        "[MEM] Direct map: {} MB at {:#x} ({} pages)",
+        size / (1024 * 1024),
+        physical_memory_offset().as_u64(),
+        if use_1gib { "1 GiB" } else { "2 MiB" }
+    );
+    size
+}
+
+/// The PML4 the bootloader set up, reached through the kernel window
+unsafe fn boot_pml4() -> &'static mut PageTable {
+    let (pml4_frame, _) = Cr3::read();
+    let addr = crate::kaslr::low_memory() + pml4_frame.start_address().as_u64();
+    &mut *addr.as_mut_ptr::<PageTable>()
+}
+
+/// Drop the bootloader's identity mapping of low memory
+///
+/// Afterwards the whole lower half belongs to user space, and low memory is
+/// only reachable through the kernel window and the direct map.
+///
+/// # Safety
+/// The boot PML4 must be active and nothing may use a low address anymore,
+/// including the descriptor tables the bootloader loaded
+pub unsafe fn unmap_identity() {
+    boot_pml4()[0].set_unused();
+    tlb::flush_all();
+    crate::serial_println!("[MEM] Identity map of low memory removed");
+}
+
 /// Initialize memory management
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// Kernel page table mapper, available once the heap is set up
+pub static KERNEL_MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
 /// Get active level 4 page table
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
     use x86_64::registers::control::Cr3;
@@ -27,48 +176,138 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr
 }
 
+/// Translate a physical address through the physical memory mapping
+pub fn phys_to_virt(physical_memory_offset: VirtAddr, addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset + addr.as_u64()
+}
+
 /// Physical Memory Manager
 pub struct PhysicalMemoryManager {
     memory_start: PhysAddr,
     memory_end: PhysAddr,
     next_free_frame: PhysAddr,
     bitmap: &'static mut [u64],
+    memory_map: MemoryMap,
+    free_frames: u64,
 }
 
 impl PhysicalMemoryManager {
-    /// Create a new physical memory manager
-    pub unsafe fn new(memory_start: PhysAddr, memory_end: PhysAddr, bitmap_addr: VirtAddr) -> Self {
-        let total_frames = ((memory_end.as_u64() - memory_start.as_u64()) / 4096) as usize;
-        let bitmap_size = (total_frames + 63) / 64;
+    /// Create a physical memory manager from a firmware memory map
+    ///
+    /// Only `Usable` regions are ever handed out. The bitmap itself is
+    /// carved out of usable memory and recorded as kernel-occupied.
+    ///
+    /// # Safety
+    /// The memory map must be accurate and physical memory must be mapped
+    /// at `physical_memory_offset`
+    pub unsafe fn from_memory_map(
+        mut memory_map: MemoryMap,
+        physical_memory_offset: VirtAddr,
+    ) -> Option<Self> {
+        let memory_start = PhysAddr::zero();
+        let memory_end = memory_map.max_physical_address();
+        let total_frames = (memory_end.as_u64() / 4096) as usize;
+        let bitmap_size = total_frames.div_ceil(64);
 
+        let bitmap_phys = memory_map.allocate((bitmap_size * 8) as u64)?;
+        let bitmap_addr = phys_to_virt(physical_memory_offset, bitmap_phys);
         let bitmap = core::slice::from_raw_parts_mut(bitmap_addr.as_mut_ptr::<u64>(), bitmap_size);
 
-        // Initialize bitmap - all frames free
+        // Everything starts out used, only usable regions are released
         for entry in bitmap.iter_mut() {
-            *entry = 0;
+            *entry = u64::MAX;
         }
 
-        Self {
+        let mut pmm = Self {
             memory_start,
             memory_end,
-            next_free_frame: memory_start,
+            next_free_frame: memory_end,
             bitmap,
+            memory_map,
+            free_frames: 0,
+        };
+
+        let mut usable = [(0u64, 0u64); MAX_MEMORY_REGIONS];
+        let mut count = 0;
+        for region in pmm.memory_map.regions_of(MemoryRegionKind::Usable) {
+            usable[count] = (region.start.as_u64(), region.end.as_u64());
+            count += 1;
+        }
+
+        for &(start, end) in &usable[..count] {
+            for addr in (start..end).step_by(4096) {
+                pmm.release(PhysAddr::new(addr));
+            }
+            if PhysAddr::new(start) < pmm.next_free_frame {
+                pmm.next_free_frame = PhysAddr::new(start);
+            }
+        }
+
+        Some(pmm)
+    }
+
+    fn frame_index(&self, addr: PhysAddr) -> usize {
+        ((addr.as_u64() - self.memory_start.as_u64()) / 4096) as usize
+    }
+
+    fn release(&mut self, addr: PhysAddr) {
+        let frame_index = self.frame_index(addr);
+        let bitmap_index = frame_index / 64;
+        let bit_index = frame_index % 64;
+
+        if self.bitmap[bitmap_index] & (1 << bit_index) != 0 {
+            self.bitmap[bitmap_index] &= !(1 << bit_index);
+            self.free_frames += 1;
         }
     }
 
     /// Allocate a physical frame
     pub fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame_count = ((self.memory_end.as_u64() - self.memory_start.as_u64()) / 4096) as usize;
+        let frame_count = self.frame_index(self.memory_end);
+        let hint = self.frame_index(self.next_free_frame);
 
-        for i in 0..frame_count {
+        for i in (hint..frame_count).chain(0..hint) {
             let bitmap_index = i / 64;
             let bit_index = i % 64;
 
             if self.bitmap[bitmap_index] & (1 << bit_index) == 0 {
                 // Frame is free, mark as used
                 self.bitmap[bitmap_index] |= 1 << bit_index;
+                self.free_frames -= 1;
 
                 let frame_addr = self.memory_start + (i as u64 * 4096);
+                self.next_free_frame = frame_addr + 4096u64;
+                return Some(PhysFrame::containing_address(frame_addr));
+            }
+        }
+
+        None
+    }
+
+    /// Allocate `count` physically contiguous frames
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        let frame_count = self.frame_index(self.memory_end);
+        let mut run_start = 0;
+        let mut run_len = 0;
+
+        for i in 0..frame_count {
+            if self.bitmap[i / 64] & (1 << (i % 64)) != 0 {
+                run_len = 0;
+                continue;
+            }
+
+            if run_len == 0 {
+                run_start = i;
+            }
+            run_len += 1;
+
+            if run_len == count {
+                for j in run_start..run_start + count {
+                    self.bitmap[j / 64] |= 1 << (j % 64);
+                }
+                self.free_frames -= count as u64;
+
+                let frame_addr = self.memory_start + (run_start as u64 * 4096);
                 return Some(PhysFrame::containing_address(frame_addr));
             }
         }
@@ -76,30 +315,104 @@ impl PhysicalMemoryManager {
         None
     }
 
+    /// Hand every run of free frames to `f` and mark them used
+    ///
+    /// Used to pass the remaining boot memory on to the buddy allocator.
+    pub fn drain_free_ranges(&mut self, mut f: impl FnMut(PhysAddr, PhysAddr)) {
+        let frame_count = self.frame_index(self.memory_end);
+        let mut i = 0;
+
+        while i < frame_count {
+            if self.bitmap[i / 64] & (1 << (i % 64)) != 0 {
+                i += 1;
+                continue;
+            }
+
+            let run_start = i;
+            while i < frame_count && self.bitmap[i / 64] & (1 << (i % 64)) == 0 {
+                self.bitmap[i / 64] |= 1 << (i % 64);
+                i += 1;
+            }
+
+            self.free_frames -= (i - run_start) as u64;
+            f(
+                self.memory_start + (run_start as u64 * 4096),
+                self.memory_start + (i as u64 * 4096),
+            );
+        }
+
+        self.next_free_frame = self.memory_end;
+    }
+
     /// Free a physical frame
     pub fn free_frame(&mut self, frame: PhysFrame) {
         let frame_addr = frame.start_address();
-        let frame_index = ((frame_addr.as_u64() - self.memory_start.as_u64()) / 4096) as usize;
+        if !self.is_usable(frame_addr) {
+            crate::serial_println!(
+                "[MEM] Refusing to free non-usable frame {:#x}",
+                frame_addr.as_u64()
+            );
+            return;
+        }
 
-        let bitmap_index = frame_index / 64;
-        let bit_index = frame_index % 64;
+        self.release(frame_addr);
+        if frame_addr < self.next_free_frame {
+            self.next_free_frame = frame_addr;
+        }
+    }
 
-        self.bitmap[bitmap_index] &= !(1 << bit_index);
+    /// Check whether a frame lies in a usable region
+    pub fn is_usable(&self, addr: PhysAddr) -> bool {
+        self.memory_map
+            .regions_of(MemoryRegionKind::Usable)
+            .any(|r| r.start <= addr && addr < r.end)
     }
 
-    /// Get total memory in bytes
+    /// Get the memory map this manager was built from
+    pub fn memory_map(&self) -> &MemoryMap {
+        &self.memory_map
+    }
+
+    /// Get total usable memory in bytes
     pub fn total_memory(&self) -> u64 {
-        self.memory_end.as_u64() - self.memory_start.as_u64()
+        self.memory_map.total_of(MemoryRegionKind::Usable)
     }
 
     /// Get used memory in bytes
     pub fn used_memory(&self) -> u64 {
-        let mut used_frames = 0u64;
-        for &entry in self.bitmap.iter() {
-            used_frames += entry.count_ones() as u64;
-        }
-        used_frames * 4096
+        self.total_memory() - self.free_frames * 4096
     }
+
+    /// Get free memory in bytes
+    pub fn free_memory(&self) -> u64 {
+        self.free_frames * 4096
+    }
+}
+
+/// Global physical memory manager
+pub static PHYSICAL_MEMORY: Mutex<Option<PhysicalMemoryManager>> = Mutex::new(None);
+
+/// Initialize the physical memory manager from the boot memory map
+///
+/// # Safety
+/// Physical memory must be mapped at `physical_memory_offset`
+pub unsafe fn init_physical(mut memory_map: MemoryMap, physical_memory_offset: VirtAddr) {
+    memory_map.reserve_kernel();
+    memory_map.dump();
+
+    let pmm = PhysicalMemoryManager::from_memory_map(memory_map, physical_memory_offset)
+        .expect("no usable memory for the frame bitmap");
+
+    let map = pmm.memory_map();
+    crate::serial_println!(
+        "[MEM] Usable: {} KB, reserved: {} KB, ACPI reclaimable: {} KB, kernel: {} KB",
+        map.total_of(MemoryRegionKind::Usable) / 1024,
+        map.total_of(MemoryRegionKind::Reserved) / 1024,
+        map.total_of(MemoryRegionKind::AcpiReclaimable) / 1024,
+        map.total_of(MemoryRegionKind::Kernel) / 1024
+    );
+
+    *PHYSICAL_MEMORY.lock() = Some(pmm);
 }
 
 /// Boot frame allocator
@@ -133,6 +446,27 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     }
 }
 
+/// Map a 2 MiB or 1 GiB page to a frame of the same size
+///
+/// 1 GiB pages require CPU support, see `has_1gib_pages`.
+pub fn map_huge_page<S: PageSize>(
+    page: Page<S>,
+    frame: PhysFrame<S>,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<S>>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    unsafe {
+        mapper
+            .map_to(page, frame, page_flags(flags), frame_allocator)?
+            .flush();
+    }
+    Ok(())
+}
+
 /// Map a page to a frame
 pub fn map_page(
     page: Page,
@@ -142,7 +476,9 @@ pub fn map_page(
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
     unsafe {
-        mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+        mapper
+            .map_to(page, frame, page_flags(flags), frame_allocator)?
+            .flush();
     }
     Ok(())
 }
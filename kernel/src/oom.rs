@@ -0,0 +1,131 @@
+use crate::process::{Pid, Priority, Process, ProcessState, INIT_PID, PROCESS_MANAGER};
+use crate::signal::Signal;
+use spin::Mutex;
+
+/// Badness of a process, its resident size in KB weighted by priority
+///
+/// Low priority processes count half again as much and high priority ones
+/// half as much, so background work is killed first.
+pub fn badness(process: &Process) -> u64 {
+    let resident_kb = process.memory_usage().resident / 1024;
+    let weight = match process.priority {
+        Priority::Low => 3,
+        Priority::Normal => 2,
+        Priority::High => 1,
+    };
+    resident_kb * weight / 2
+}
+
+/// Whether the OOM killer may pick a process at all
+fn is_killable(process: &Process) -> bool {
+    process.pid != INIT_PID
+        && process.state != ProcessState::Terminated
+        && process.address_space.is_some()
+}
+
+/// Picks and kills processes when physical memory runs out
+///
+/// Runs from the page fault path with memory exhausted, so it must not
+/// allocate from the kernel heap.
+pub struct OomKiller {
+    kills: u64,
+    reclaimed: u64,
+}
+
+impl OomKiller {
+    pub const fn new() -> Self {
+        Self {
+            kills: 0,
+            reclaimed: 0,
+        }
+    }
+
+    /// Score every process and return the one with the highest badness
+    fn select_victim(&self) -> Option<(Pid, u64)> {
+        let pm = PROCESS_MANAGER.lock();
+        let mut victim: Option<(Pid, u64)> = None;
+
+        for process in pm.processes() {
+            if !is_killable(process) {
+                continue;
+            }
+
+            let score = badness(process);
+            crate::serial_println!(
+                "[OOM] PID {}: resident {} KB, priority {:?}, score {}",
+                process.pid,
+                process.memory_usage().resident / 1024,
+                process.priority,
+                score
+            );
+            // Ties go to the newer process
+            if victim.is_none_or(|(_, best)| score >= best) {
+                victim = Some((process.pid, score));
+            }
+        }
+
+        victim
+    }
+
+    /// Kill the process with the highest badness to free memory
+    ///
+    /// Returns the victim, or `None` if nothing could be killed.
+    pub fn out_of_memory(&mut self) -> Option<Pid> {
+        let free_before = crate::buddy::BUDDY_ALLOCATOR.lock().free_memory();
+        crate::serial_println!(
+            "[OOM] Out of memory ({} KB free), scoring processes",
+            free_before / 1024
+        );
+
+        let Some((pid, score)) = self.select_victim() else {
+            crate::serial_println!("[OOM] No killable process, PID {} is protected", INIT_PID);
+            return None;
+        };
+
+        // Reclaim before queueing the signal, which needs heap memory
+        crate::process::exit(pid);
+        crate::signal::send_signal(pid, Signal::SIGKILL, None);
+
+        let free_after = crate::buddy::BUDDY_ALLOCATOR.lock().free_memory();
+        let reclaimed = free_after.saturating_sub(free_before);
+        self.kills += 1;
+        self.reclaimed += reclaimed;
+
+        crate::serial_println!(
+            "[OOM] Killed PID {} (score {}), reclaimed {} KB",
+            pid,
+            score,
+            reclaimed / 1024
+        );
+        Some(pid)
+    }
+
+    /// Number of processes killed so far
+    pub fn kills(&self) -> u64 {
+        self.kills
+    }
+
+    /// Bytes reclaimed by all kills so far
+    pub fn reclaimed(&self) -> u64 {
+        self.reclaimed
+    }
+}
+
+impl Default for OomKiller {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Global OOM killer
+pub static OOM_KILLER: Mutex<OomKiller> = Mutex::new(OomKiller::new());
+
+/// Initialize the OOM killer
+pub fn init() {
+    crate::serial_println!("[OOM] OOM killer ready, PID {} is protected", INIT_PID);
+}
+
+/// Kill the process with the highest badness to free memory
+pub fn out_of_memory() -> Option<Pid> {
+    OOM_KILLER.lock().out_of_memory()
+}
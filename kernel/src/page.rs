@@ -0,0 +1,229 @@
+use crate::memory::phys_to_virt;
+use crate::process::Pid;
+use crate::shm::ShmId;
+use bitflags::bitflags;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use spin::Once;
+use x86_64::{
+    structures::paging::{PageSize, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+
+bitflags! {
+    /// State bits of a physical frame
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PageFlags: u32 {
+        /// Under I/O, must not be evicted or freed
+        const LOCKED = 1;
+        /// Modified since it was last written back
+        const DIRTY = 2;
+        /// Never handed out by the frame allocator
+        const RESERVED = 4;
+        /// Backs slab objects
+        const SLAB = 8;
+    }
+}
+
+/// Who a frame was allocated for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageOwner {
+    None,
+    Kernel,
+    Process(Pid),
+    SharedMemory(ShmId),
+}
+
+impl PageOwner {
+    const KIND_SHIFT: u32 = 56;
+    const ID_MASK: u64 = (1 << Self::KIND_SHIFT) - 1;
+
+    /// Pack into one word, the kind in the top byte
+    fn encode(self) -> u64 {
+        let (kind, id) = match self {
+            PageOwner::None => (0, 0),
+            PageOwner::Kernel => (1, 0),
+            PageOwner::Process(pid) => (2, pid),
+            PageOwner::SharedMemory(id) => (3, id),
+        };
+        (kind << Self::KIND_SHIFT) | (id & Self::ID_MASK)
+    }
+
+    fn decode(word: u64) -> Self {
+        let id = word & Self::ID_MASK;
+        match word >> Self::KIND_SHIFT {
+            1 => PageOwner::Kernel,
+            2 => PageOwner::Process(id),
+            3 => PageOwner::SharedMemory(id),
+            _ => PageOwner::None,
+        }
+    }
+}
+
+/// Bookkeeping for one physical frame
+///
+/// `refcount` counts the mappings and kernel users holding the frame. A
+/// frame fresh from the buddy allocator starts at one and goes back to it
+/// when `put_page` drops the count to zero.
+#[repr(C)]
+pub struct PageDescriptor {
+    refcount: AtomicU32,
+    flags: AtomicU32,
+    owner: AtomicU64,
+}
+
+impl PageDescriptor {
+    pub fn refcount(&self) -> u32 {
+        self.refcount.load(Ordering::Acquire)
+    }
+
+    pub fn flags(&self) -> PageFlags {
+        PageFlags::from_bits_truncate(self.flags.load(Ordering::Relaxed))
+    }
+
+    pub fn owner(&self) -> PageOwner {
+        PageOwner::decode(self.owner.load(Ordering::Relaxed))
+    }
+
+    fn reset(&self, refcount: u32, flags: PageFlags, owner: PageOwner) {
+        self.refcount.store(refcount, Ordering::Release);
+        self.flags.store(flags.bits(), Ordering::Relaxed);
+        self.owner.store(owner.encode(), Ordering::Relaxed);
+    }
+}
+
+/// Descriptors of every frame, indexed by PFN
+static PAGES: Once<&'static [PageDescriptor]> = Once::new();
+
+/// Get the descriptor of a frame
+pub fn descriptor(frame: PhysFrame) -> Option<&'static PageDescriptor> {
+    let pfn = frame.start_address().as_u64() / Size4KiB::SIZE;
+    PAGES.get()?.get(pfn as usize)
+}
+
+/// Record a freshly allocated block of 2^order frames
+pub(crate) fn on_allocate(pfn: u64, order: usize) {
+    if let Some(pages) = PAGES.get() {
+        for page in &pages[pfn as usize..(pfn as usize + (1 << order))] {
+            page.reset(1, PageFlags::empty(), PageOwner::Kernel);
+        }
+    }
+}
+
+/// Record a block of 2^order frames going back to the buddy allocator
+pub(crate) fn on_free(pfn: u64, order: usize) {
+    if let Some(pages) = PAGES.get() {
+        let block = &pages[pfn as usize..(pfn as usize + (1 << order))];
+        if block[0].refcount() > 1 {
+            crate::serial_println!(
+                "[PAGE] Freeing frame {:#x} with {} references",
+                pfn * Size4KiB::SIZE,
+                block[0].refcount()
+            );
+        }
+        for page in block {
+            page.reset(0, PageFlags::empty(), PageOwner::None);
+        }
+    }
+}
+
+/// Take one more reference to a frame
+pub fn get_page(frame: PhysFrame) {
+    if let Some(page) = descriptor(frame) {
+        page.refcount.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+/// Drop one reference to a frame, freeing it with the last one
+///
+/// Returns whether the frame was freed.
+pub fn put_page(frame: PhysFrame) -> bool {
+    let Some(page) = descriptor(frame) else {
+        return false;
+    };
+    if page.flags().contains(PageFlags::RESERVED) {
+        crate::serial_println!(
+            "[PAGE] Refusing to release reserved frame {:#x}",
+            frame.start_address().as_u64()
+        );
+        return false;
+    }
+
+    match page.refcount.fetch_sub(1, Ordering::AcqRel) {
+        0 => {
+            page.refcount.store(0, Ordering::Release);
+            crate::serial_println!(
+                "[PAGE] Release of free frame {:#x}",
+                frame.start_address().as_u64()
+            );
+            false
+        }
+        1 => {
+            crate::buddy::free_frames(frame, 0);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Number of references to a frame
+pub fn page_count(frame: PhysFrame) -> u32 {
+    descriptor(frame).map_or(1, PageDescriptor::refcount)
+}
+
+/// Set state bits of a frame
+pub fn set_flags(frame: PhysFrame, flags: PageFlags) {
+    if let Some(page) = descriptor(frame) {
+        page.flags.fetch_or(flags.bits(), Ordering::Relaxed);
+    }
+}
+
+/// Clear state bits of a frame
+pub fn clear_flags(frame: PhysFrame, flags: PageFlags) {
+    if let Some(page) = descriptor(frame) {
+        page.flags.fetch_and(!flags.bits(), Ordering::Relaxed);
+    }
+}
+
+/// Record who a frame belongs to
+pub fn set_owner(frame: PhysFrame, owner: PageOwner) {
+    if let Some(page) = descriptor(frame) {
+        page.owner.store(owner.encode(), Ordering::Relaxed);
+    }
+}
+
+/// Allocate the descriptor array out of boot memory
+///
+/// Every frame starts out reserved; the buddy allocator releases the ones
+/// it takes over.
+///
+/// # Safety
+/// Physical memory must be mapped at `physical_memory_offset` and this must
+/// run before `buddy::init`
+pub unsafe fn init(physical_memory_offset: VirtAddr) {
+    let mut pmm = crate::memory::PHYSICAL_MEMORY.lock();
+    let pmm = pmm
+        .as_mut()
+        .expect("physical memory manager not initialized");
+
+    let frames = (pmm.memory_map().max_physical_address().as_u64() / Size4KiB::SIZE) as usize;
+    let bytes = frames * core::mem::size_of::<PageDescriptor>();
+    let array = pmm
+        .allocate_contiguous(bytes.div_ceil(Size4KiB::SIZE as usize))
+        .expect("no contiguous memory for the page descriptors");
+
+    let addr = phys_to_virt(physical_memory_offset, array.start_address());
+    let pages = core::slice::from_raw_parts_mut(addr.as_mut_ptr::<PageDescriptor>(), frames);
+    for page in pages.iter_mut() {
+        core::ptr::write(
+            page,
+            PageDescriptor {
+                refcount: AtomicU32::new(1),
+                flags: AtomicU32::new(PageFlags::RESERVED.bits()),
+                owner: AtomicU64::new(PageOwner::Kernel.encode()),
+            },
+        );
+    }
+    PAGES.call_once(|| pages);
+
+    crate::serial_println!("[PAGE] {} page descriptors ({} KB)", frames, bytes / 1024);
+}
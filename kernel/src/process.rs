@@ -1,11 +1,24 @@
-use alloc::collections::VecDeque;
+use crate::address_space::AddressSpace;
+use crate::context::CpuContext;
+use crate::kstack::{KernelStack, StackOwner};
+use crate::rlimit::{MemoryUsage, Resource, ResourceLimits};
+use crate::sched::{RunQueue, SchedClass, SchedEntity, SchedError, SchedulerPolicy};
+use alloc::boxed::Box;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
 use spin::Mutex;
 use x86_64::VirtAddr;
 
 /// Process ID type
 pub type Pid = u64;
 
+/// PID of the init process
+pub const INIT_PID: Pid = 1;
+
+/// Whether timer ticks switch processes, set once booting is done
+static PREEMPTION: AtomicBool = AtomicBool::new(false);
+
 /// Process state
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProcessState {
@@ -23,6 +36,24 @@ pub enum Priority {
     High = 2,
 }
 
+impl Priority {
+    /// One level up, if there is one
+    pub fn raised(self) -> Self {
+        match self {
+            Priority::Low => Priority::Normal,
+            _ => Priority::High,
+        }
+    }
+
+    /// One level down, if there is one
+    pub fn lowered(self) -> Self {
+        match self {
+            Priority::High => Priority::Normal,
+            _ => Priority::Low,
+        }
+    }
+}
+
 /// Process Control Block
 #[derive(Debug)]
 pub struct Process {
@@ -30,22 +61,69 @@ pub struct Process {
     pub parent_pid: Option<Pid>,
     pub state: ProcessState,
     pub priority: Priority,
-    pub stack_pointer: VirtAddr,
-    pub instruction_pointer: VirtAddr,
-    pub page_table: VirtAddr,
+    /// Bookkeeping of the scheduler policy
+    pub sched: SchedEntity,
+    /// Saved registers while not running, `None` until there is a program
+    pub context: Option<CpuContext>,
+    /// Stack the process enters the kernel on, freed with the PCB
+    pub kernel_stack: Option<KernelStack>,
+    pub address_space: Option<AddressSpace>,
+    pub limits: ResourceLimits,
+    /// Name of a kernel thread, `None` for user processes
+    pub kernel_thread: Option<&'static str>,
 }
 
 impl Process {
     /// Create a new process
-    pub fn new(pid: Pid, parent_pid: Option<Pid>) -> Self {
+    pub fn new(pid: Pid, parent_pid: Option<Pid>, address_space: Option<AddressSpace>) -> Self {
         Self {
             pid,
             parent_pid,
             state: ProcessState::Ready,
             priority: Priority::Normal,
-            stack_pointer: VirtAddr::new(0),
-            instruction_pointer: VirtAddr::new(0),
-            page_table: VirtAddr::new(0),
+            sched: SchedEntity::new(Priority::Normal),
+            context: None,
+            kernel_stack: None,
+            address_space,
+            limits: ResourceLimits::new(),
+            kernel_thread: None,
+        }
+    }
+
+    /// Current memory use
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.address_space
+            .as_ref()
+            .map(AddressSpace::usage)
+            .unwrap_or_default()
+    }
+
+    /// Check whether `extra` more bytes of `resource` stay within its soft limit
+    pub fn can_grow(&self, resource: Resource, extra: u64) -> bool {
+        let current = self.memory_usage().of(resource);
+        self.limits
+            .get(resource)
+            .allows(current.saturating_add(extra))
+    }
+
+    /// Switch to this process's page tables
+    ///
+    /// # Safety
+    /// Must only be called when this process is about to run
+    pub unsafe fn activate_address_space(&self) {
+        match &self.address_space {
+            Some(space) => space.activate(),
+            None => crate::address_space::activate_kernel(),
+        }
+    }
+
+    /// Install this process's kernel stack for entries from ring 3
+    ///
+    /// # Safety
+    /// Must only be called when this process is about to run
+    pub unsafe fn activate_kernel_stack(&self) {
+        if let Some(stack) = &self.kernel_stack {
+            crate::gdt::set_kernel_stack(stack.top());
         }
     }
 
@@ -57,39 +135,117 @@ impl Process {
     /// Set process priority
     pub fn set_priority(&mut self, priority: Priority) {
         self.priority = priority;
+        self.sched.level = priority;
     }
 }
 
 /// Process Manager
 pub struct ProcessManager {
     processes: Vec<Process>,
-    ready_queue: VecDeque<Pid>,
+    /// Picks what runs next
+    run_queue: RunQueue,
     current_pid: Option<Pid>,
     next_pid: Pid,
+    /// Where the boot thread idles while no process runs
+    idle_context: Option<CpuContext>,
 }
 
 impl ProcessManager {
-    /// Create a new process manager
-    pub const fn new() -> Self {
+    /// Create a new process manager scheduling with `policy`
+    pub fn new(policy: Box<dyn SchedulerPolicy>) -> Self {
         Self {
             processes: Vec::new(),
-            ready_queue: VecDeque::new(),
+            run_queue: RunQueue::new(policy),
             current_pid: None,
             next_pid: 1,
+            idle_context: None,
         }
     }
 
+    /// Name of the scheduler policy for normal processes
+    pub fn policy_name(&self) -> &'static str {
+        self.run_queue.policy_name()
+    }
+
     /// Create a new process
-    pub fn create_process(&mut self, parent_pid: Option<Pid>) -> Pid {
+    pub fn create_process(&mut self, parent_pid: Option<Pid>) -> Result<Pid, ProcessError> {
         let pid = self.next_pid;
+        let address_space = AddressSpace::new(pid).ok_or(ProcessError::OutOfMemory)?;
+        let kernel_stack = KernelStack::allocate(StackOwner::Process(pid))
+            .map_err(|_| ProcessError::OutOfMemory)?;
         self.next_pid += 1;
 
-        let process = Process::new(pid, parent_pid);
+        let mut process = Process::new(pid, parent_pid, Some(address_space));
+        process.kernel_stack = Some(kernel_stack);
+        self.run_queue.enqueue(&mut process);
         self.processes.push(process);
-        self.ready_queue.push_back(pid);
 
         crate::serial_println!("[PM] Created process PID={}", pid);
-        pid
+        Ok(pid)
+    }
+
+    /// Create a kernel thread calling `entry(arg)` on a stack of its own
+    ///
+    /// It runs in the kernel address space and `entry` must not return.
+    pub fn create_kernel_thread(
+        &mut self,
+        name: &'static str,
+        entry: VirtAddr,
+        arg: u64,
+    ) -> Result<Pid, ProcessError> {
+        let pid = self.next_pid;
+        let kernel_stack = KernelStack::allocate(StackOwner::Kernel(name))
+            .map_err(|_| ProcessError::OutOfMemory)?;
+        self.next_pid += 1;
+
+        let mut thread = Process::new(pid, None, None);
+        thread.kernel_thread = Some(name);
+        thread.context = Some(CpuContext::kernel(entry, kernel_stack.top(), arg));
+        thread.kernel_stack = Some(kernel_stack);
+        self.run_queue.enqueue(&mut thread);
+        self.processes.push(thread);
+
+        crate::serial_println!("[PM] Created kernel thread {} PID={}", name, pid);
+        Ok(pid)
+    }
+
+    /// Fork a process, duplicating its address space copy-on-write
+    ///
+    /// The child resumes at `context`, normally the parent's return from
+    /// the fork system call.
+    pub fn fork_process(
+        &mut self,
+        parent_pid: Pid,
+        context: CpuContext,
+    ) -> Result<Pid, ProcessError> {
+        let pid = self.next_pid;
+        let kernel_stack = KernelStack::allocate(StackOwner::Process(pid))
+            .map_err(|_| ProcessError::OutOfMemory)?;
+        let parent = self
+            .get_process_mut(parent_pid)
+            .ok_or(ProcessError::NotFound)?;
+
+        let address_space = match parent.address_space.as_mut() {
+            Some(space) => Some(space.fork(pid).ok_or(ProcessError::OutOfMemory)?),
+            None => None,
+        };
+
+        let mut child = Process::new(pid, Some(parent_pid), address_space);
+        child.set_priority(parent.priority);
+        // A deadline reservation is not duplicated, the child starts normal
+        if !matches!(parent.sched.class, SchedClass::Deadline(_)) {
+            child.sched.class = parent.sched.class;
+        }
+        child.limits = parent.limits;
+        child.context = Some(context);
+        child.kernel_stack = Some(kernel_stack);
+
+        self.next_pid += 1;
+        self.run_queue.enqueue(&mut child);
+        self.processes.push(child);
+
+        crate::serial_println!("[PM] Forked process PID={} from PID={}", pid, parent_pid);
+        Ok(pid)
     }
 
     /// Get process by PID
@@ -102,65 +258,216 @@ impl ProcessManager {
         self.processes.iter_mut().find(|p| p.pid == pid)
     }
 
-    /// Schedule next process (simple round-robin)
+    /// Schedule next process as the policy decides
     pub fn schedule(&mut self) -> Option<Pid> {
-        // Move current process back to ready queue if still running
+        // Whatever exited before the last switch is off its stack by now
+        self.reap();
+
+        // Move current process back to the run queue if still running
         if let Some(current) = self.current_pid {
-            if let Some(process) = self.get_process(current) {
+            if let Some(process) = self.processes.iter_mut().find(|p| p.pid == current) {
                 if process.state == ProcessState::Running {
-                    self.ready_queue.push_back(current);
+                    self.run_queue.enqueue(process);
                 }
             }
         }
 
         // Get next ready process
-        while let Some(pid) = self.ready_queue.pop_front() {
-            if let Some(process) = self.get_process_mut(pid) {
-                if process.state == ProcessState::Ready || process.state == ProcessState::Running {
+        let previous = self.current_pid;
+        for _ in 0..self.run_queue.len() {
+            let Some(pid) = self.run_queue.pick_next() else {
+                break;
+            };
+            let Some(process) = self.processes.iter_mut().find(|p| p.pid == pid) else {
+                continue;
+            };
+            match process.state {
+                ProcessState::Ready | ProcessState::Running if process.context.is_some() => {
                     process.state = ProcessState::Running;
+                    process.sched.ran = 0;
+                    if previous != Some(pid) {
+                        unsafe {
+                            process.activate_address_space();
+                            process.activate_kernel_stack();
+                        }
+                    }
                     self.current_pid = Some(pid);
                     return Some(pid);
                 }
+                // Nothing to run until exec gives it a program
+                ProcessState::Ready | ProcessState::Running => self.run_queue.enqueue(process),
+                _ => {}
             }
         }
 
         None
     }
 
+    /// Pick the next context to run, saving `context` as the current one's
+    ///
+    /// Returns `None` when the current context keeps running.
+    fn switch(&mut self, context: &CpuContext) -> Option<CpuContext> {
+        let previous = self.current_pid;
+        match previous.and_then(|pid| self.get_process_mut(pid)) {
+            Some(process) => process.context = Some(*context),
+            None => self.idle_context = Some(*context),
+        }
+
+        let next = self.schedule();
+        if next.is_none() && previous.is_some() {
+            // The last process left, go back to idling
+            self.current_pid = None;
+            unsafe { crate::address_space::activate_kernel() };
+        }
+
+        match self.current_pid {
+            current if current == previous => None,
+            Some(pid) => self.get_process(pid).and_then(|process| process.context),
+            None => self.idle_context,
+        }
+    }
+
+    /// Account a timer tick, switching `context` to the next process when
+    /// the policy preempts the current one
+    ///
+    /// The idle loop gives way as soon as anything is ready. Kernel threads
+    /// are never preempted: kernel locks are spinlocks taken with interrupts
+    /// enabled, and a system call spinning on one a preempted thread holds
+    /// would never let the thread run again. They switch in `reschedule`.
+    pub fn tick(&mut self, context: &mut CpuContext) {
+        let mut current = self
+            .current_pid
+            .and_then(|pid| self.processes.iter_mut().find(|p| p.pid == pid));
+        let idle = current.is_none();
+        let kernel_thread = current.as_ref().is_some_and(|p| p.kernel_thread.is_some());
+        if let Some(process) = current.as_deref_mut() {
+            process.sched.ran += 1;
+        }
+
+        let preempt = self.run_queue.tick(current) && !kernel_thread;
+        if preempt || (idle && !self.run_queue.is_empty()) {
+            if let Some(next) = self.switch(context) {
+                *context = next;
+            }
+        }
+    }
+
+    /// Switch `context` away from the running process, which gave up the CPU
+    ///
+    /// A process still running goes back to the run queue; a blocked one
+    /// stays off it.
+    pub fn reschedule(&mut self, context: &mut CpuContext) {
+        let current = self
+            .current_pid
+            .and_then(|pid| self.processes.iter_mut().find(|p| p.pid == pid));
+        if let Some(process) = current.filter(|p| p.state == ProcessState::Running) {
+            self.run_queue.yield_task(process);
+        }
+        if let Some(next) = self.switch(context) {
+            *context = next;
+        }
+    }
+
+    /// Record that the running process gives up the CPU at `context`
+    fn yield_current(&mut self, context: CpuContext) {
+        let current = self
+            .current_pid
+            .and_then(|pid| self.processes.iter_mut().find(|p| p.pid == pid));
+        if let Some(process) = current {
+            process.context = Some(context);
+            self.run_queue.yield_task(process);
+        }
+    }
+
     /// Terminate a process
+    ///
+    /// Its PCB and kernel stack are released by `reap`, right away unless
+    /// the process is terminating itself.
     pub fn terminate_process(&mut self, pid: Pid) {
         if let Some(process) = self.get_process_mut(pid) {
             process.state = ProcessState::Terminated;
+            process.context = None;
+            // Dropping the address space returns its frames
+            process.address_space = None;
             crate::serial_println!("[PM] Terminated process PID={}", pid);
         }
+        self.run_queue.remove(pid);
 
         if self.current_pid == Some(pid) {
             self.current_pid = None;
         }
+        self.reap();
+    }
+
+    /// Drop the PCBs of terminated processes, freeing their kernel stacks
+    ///
+    /// A process that terminated itself runs on its kernel stack until the
+    /// switch away from it, so it is left for a later call.
+    fn reap(&mut self) {
+        self.processes.retain(|process| {
+            process.state != ProcessState::Terminated
+                || process
+                    .kernel_stack
+                    .as_ref()
+                    .is_some_and(KernelStack::is_active)
+        });
     }
 
     /// Block a process
+    ///
+    /// A running process blocking gives up the rest of its slice.
     pub fn block_process(&mut self, pid: Pid) {
-        if let Some(process) = self.get_process_mut(pid) {
+        let running = self.current_pid == Some(pid);
+        if let Some(process) = self.processes.iter_mut().find(|p| p.pid == pid) {
             process.state = ProcessState::Blocked;
+            if running {
+                self.run_queue.block(process);
+            }
         }
+        self.run_queue.dequeue(pid);
     }
 
     /// Unblock a process
     pub fn unblock_process(&mut self, pid: Pid) {
-        if let Some(process) = self.get_process_mut(pid) {
+        if let Some(process) = self.processes.iter_mut().find(|p| p.pid == pid) {
             if process.state == ProcessState::Blocked {
                 process.state = ProcessState::Ready;
-                self.ready_queue.push_back(pid);
+                self.run_queue.enqueue(process);
             }
         }
     }
 
+    /// Move a process to another scheduling class
+    ///
+    /// Takes effect from the next tick if it lets the process preempt the
+    /// running one.
+    pub fn set_scheduler(&mut self, pid: Pid, class: SchedClass) -> Result<(), SchedError> {
+        let process = self
+            .processes
+            .iter_mut()
+            .find(|p| p.pid == pid && p.state != ProcessState::Terminated)
+            .ok_or(SchedError::ProcessNotFound)?;
+        let queued = process.state == ProcessState::Ready;
+        self.run_queue.set_class(process, class, queued)?;
+        crate::serial_println!("[SCHED] PID {} now scheduled as {:?}", pid, class);
+        Ok(())
+    }
+
     /// Get current running process PID
     pub fn current_pid(&self) -> Option<Pid> {
         self.current_pid
     }
 
+    /// Iterate over all processes
+    pub fn processes(&self) -> impl Iterator<Item = &Process> {
+        self.processes.iter()
+    }
+
+    /// Iterate mutably over all processes
+    pub fn processes_mut(&mut self) -> impl Iterator<Item = &mut Process> {
+        self.processes.iter_mut()
+    }
+
     /// Get process count
     pub fn process_count(&self) -> usize {
         self.processes.len()
@@ -168,12 +475,22 @@ impl ProcessManager {
 
     /// Get ready process count
     pub fn ready_count(&self) -> usize {
-        self.ready_queue.len()
+        self.run_queue.len()
     }
 }
 
-/// Global process manager
-pub static PROCESS_MANAGER: Mutex<ProcessManager> = Mutex::new(ProcessManager::new());
+/// Process management errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    OutOfMemory,
+    NotFound,
+}
+
+lazy_static! {
+    /// Global process manager, scheduling with the policy picked by `sched=`
+    pub static ref PROCESS_MANAGER: Mutex<ProcessManager> =
+        Mutex::new(ProcessManager::new(crate::sched::from_cmdline()));
+}
 
 /// Initialize process management
 pub fn init() {
@@ -181,20 +498,89 @@ pub fn init() {
 
     // Create init process (PID 1)
     let mut pm = PROCESS_MANAGER.lock();
-    let init_pid = pm.create_process(None);
-    assert_eq!(init_pid, 1, "Init process must have PID 1");
+    crate::serial_println!("[PM] Scheduler policy: {}", pm.policy_name());
+    let init_pid = pm
+        .create_process(None)
+        .expect("Failed to create init process");
+    assert_eq!(init_pid, INIT_PID, "Init process must have PID 1");
 }
 
 /// Create a new process
-pub fn create_process(parent_pid: Option<Pid>) -> Pid {
+pub fn create_process(parent_pid: Option<Pid>) -> Result<Pid, ProcessError> {
     PROCESS_MANAGER.lock().create_process(parent_pid)
 }
 
+/// Fork a process
+pub fn fork(parent_pid: Pid, context: CpuContext) -> Result<Pid, ProcessError> {
+    PROCESS_MANAGER.lock().fork_process(parent_pid, context)
+}
+
 /// Schedule next process
 pub fn schedule() -> Option<Pid> {
     PROCESS_MANAGER.lock().schedule()
 }
 
+/// Account a timer tick to the context the timer interrupted
+///
+/// Called from the timer interrupt with `context` saved on the stack; it is
+/// overwritten with the context to resume when the time slice is up.
+pub fn tick(context: &mut CpuContext) {
+    if !PREEMPTION.load(Ordering::Relaxed) {
+        return;
+    }
+    // The tick may have interrupted the kernel holding the lock
+    if let Some(mut pm) = PROCESS_MANAGER.try_lock() {
+        pm.tick(context);
+    }
+}
+
+/// Switch away from a kernel thread giving up the CPU at `context`
+///
+/// Called from the yield interrupt, which the thread raises without holding
+/// the process manager.
+pub fn reschedule(context: &mut CpuContext) {
+    PROCESS_MANAGER.lock().reschedule(context);
+}
+
+/// Give up the CPU from a system call, to be resumed at `context`
+pub fn yield_now(context: CpuContext) -> ! {
+    PROCESS_MANAGER.lock().yield_current(context);
+    run_next()
+}
+
+/// Leave the current context and run the next process
+///
+/// Used once the current process has terminated or saved where to resume,
+/// from a system call or a fault handler. Idles until the timer moves on
+/// if nothing is ready.
+pub fn run_next() -> ! {
+    let next = {
+        let mut pm = PROCESS_MANAGER.lock();
+        match pm.schedule() {
+            Some(pid) => pm.get_process(pid).and_then(|process| process.context),
+            None => {
+                pm.current_pid = None;
+                unsafe { crate::address_space::activate_kernel() };
+                pm.idle_context
+            }
+        }
+    };
+
+    match next {
+        Some(context) => unsafe { crate::context::switch_to(context) },
+        None => {
+            x86_64::instructions::interrupts::enable();
+            crate::hlt_loop();
+        }
+    }
+}
+
+/// Let timer ticks preempt processes
+pub fn start_preemption() {
+    PREEMPTION.store(true, Ordering::Relaxed);
+    crate::serial_println!("[PM] Preemptive scheduling enabled");
+}
+
 /// Terminate current process
 pub fn exit(pid: Pid) {
     PROCESS_MANAGER.lock().terminate_process(pid);
@@ -204,3 +590,77 @@ pub fn exit(pid: Pid) {
 pub fn current_pid() -> Option<Pid> {
     PROCESS_MANAGER.lock().current_pid()
 }
+
+/// Move a process to another scheduling class
+pub fn set_scheduler(pid: Pid, class: SchedClass) -> Result<(), SchedError> {
+    PROCESS_MANAGER.lock().set_scheduler(pid, class)
+}
+
+/// Run `f` on a process
+pub fn with_process<R>(pid: Pid, f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    PROCESS_MANAGER.lock().get_process_mut(pid).map(f)
+}
+
+/// Run `f` on the address space of a process
+pub fn with_address_space<R>(pid: Pid, f: impl FnOnce(&mut AddressSpace) -> R) -> Option<R> {
+    PROCESS_MANAGER
+        .lock()
+        .get_process_mut(pid)
+        .and_then(|p| p.address_space.as_mut())
+        .map(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ProcessManager, ProcessState};
+    use crate::kstack::KSTACK_MANAGER;
+    use crate::sched::RoundRobin;
+    use crate::{serial_print, serial_println};
+    use alloc::boxed::Box;
+    use x86_64::VirtAddr;
+
+    #[test_case]
+    fn test_terminated_process_is_reaped() {
+        serial_print!("test_terminated_process_is_reaped... ");
+        let stacks = KSTACK_MANAGER.lock().count();
+        let mut pm = ProcessManager::new(Box::new(RoundRobin::new()));
+        let pid = pm.create_process(None).unwrap();
+        assert_eq!(KSTACK_MANAGER.lock().count(), stacks + 1);
+
+        pm.terminate_process(pid);
+        assert!(pm.get_process(pid).is_none());
+        assert_eq!(pm.process_count(), 0);
+        assert_eq!(KSTACK_MANAGER.lock().count(), stacks);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_exited_kernel_thread_is_reaped() {
+        serial_print!("test_exited_kernel_thread_is_reaped... ");
+        let stacks = KSTACK_MANAGER.lock().count();
+        let mut pm = ProcessManager::new(Box::new(RoundRobin::new()));
+        let entry = VirtAddr::new(crate::hlt_loop as usize as u64);
+        let pid = pm.create_kernel_thread("reap test", entry, 0).unwrap();
+        assert_eq!(KSTACK_MANAGER.lock().count(), stacks + 1);
+
+        pm.terminate_process(pid);
+        assert!(pm.get_process(pid).is_none());
+        assert_eq!(KSTACK_MANAGER.lock().count(), stacks);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_reap_keeps_live_processes() {
+        serial_print!("test_reap_keeps_live_processes... ");
+        let mut pm = ProcessManager::new(Box::new(RoundRobin::new()));
+        let first = pm.create_process(None).unwrap();
+        let second = pm.create_process(None).unwrap();
+
+        pm.terminate_process(first);
+        assert!(pm.get_process(first).is_none());
+        let survivor = pm.get_process(second).unwrap();
+        assert_eq!(survivor.state, ProcessState::Ready);
+        assert!(survivor.kernel_stack.is_some());
+        serial_println!("[ok]");
+    }
+}
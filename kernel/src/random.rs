@@ -0,0 +1,98 @@
+use core::arch::asm;
+
+/// Attempts before a hardware generator is considered exhausted
+const HW_RETRIES: usize = 10;
+
+/// Rounds of timing measurements folded into one jitter sample
+const JITTER_ROUNDS: usize = 64;
+
+/// Where random numbers come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntropySource {
+    Rdseed,
+    Rdrand,
+    /// Timing noise of the TSC, the fallback on CPUs without RDRAND
+    TscJitter,
+}
+
+/// Best entropy source this CPU offers
+pub fn source() -> EntropySource {
+    // CPUID.(EAX=07h,ECX=0):EBX.RDSEED[bit 18], CPUID.01h:ECX.RDRAND[bit 30]
+    let max_leaf = unsafe { core::arch::x86_64::__cpuid(0) }.eax;
+    if max_leaf >= 7 && unsafe { core::arch::x86_64::__cpuid_count(7, 0) }.ebx & (1 << 18) != 0 {
+        EntropySource::Rdseed
+    } else if unsafe { core::arch::x86_64::__cpuid(1) }.ecx & (1 << 30) != 0 {
+        EntropySource::Rdrand
+    } else {
+        EntropySource::TscJitter
+    }
+}
+
+fn rdseed() -> Option<u64> {
+    for _ in 0..HW_RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn rdrand() -> Option<u64> {
+    for _ in 0..HW_RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// SplitMix64 finalizer, spreads every input bit over the output
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+/// Fold the timing noise of short busy loops into a value
+fn tsc_jitter() -> u64 {
+    let mut state = rdtsc();
+    for _ in 0..JITTER_ROUNDS {
+        let start = rdtsc();
+        for _ in 0..(state & 0xFF) {
+            core::hint::spin_loop();
+        }
+        state = mix(state ^ rdtsc().wrapping_sub(start));
+    }
+    state
+}
+
+/// A random 64-bit value from the best available source
+///
+/// Safe to call before the kernel is relocated: it touches no statics.
+pub fn random_u64() -> u64 {
+    let hardware = match source() {
+        EntropySource::Rdseed => rdseed().or_else(rdrand),
+        EntropySource::Rdrand => rdrand(),
+        EntropySource::TscJitter => None,
+    };
+    hardware.unwrap_or_else(tsc_jitter)
+}
+
+/// A random value in `0..bound`
+pub fn random_below(bound: u64) -> u64 {
+    ((random_u64() as u128 * bound as u128) >> 64) as u64
+}
@@ -0,0 +1,127 @@
+/// Value of an unlimited resource
+pub const RLIM_INFINITY: u64 = u64::MAX;
+
+/// Limited resources, numbered like their Linux counterparts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Resource {
+    /// Resident memory in bytes
+    Resident = 5,
+    /// Virtual address space in bytes
+    AddressSpace = 9,
+}
+
+impl Resource {
+    pub fn from_u64(n: u64) -> Option<Self> {
+        match n {
+            5 => Some(Self::Resident),
+            9 => Some(Self::AddressSpace),
+            _ => None,
+        }
+    }
+}
+
+/// Soft and hard limit of one resource
+///
+/// Laid out like `struct rlimit` so it can be copied to and from user space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct RLimit {
+    pub soft: u64,
+    pub hard: u64,
+}
+
+impl RLimit {
+    pub const UNLIMITED: Self = Self {
+        soft: RLIM_INFINITY,
+        hard: RLIM_INFINITY,
+    };
+
+    /// Check whether a usage of `value` stays within the soft limit
+    pub fn allows(&self, value: u64) -> bool {
+        value <= self.soft
+    }
+}
+
+/// Resource limit errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitError {
+    /// The soft limit is above the hard limit
+    Invalid,
+    /// Raising a hard limit needs privileges
+    PermissionDenied,
+}
+
+/// Resource limits of a process, inherited across fork
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceLimits {
+    resident: RLimit,
+    address_space: RLimit,
+}
+
+impl ResourceLimits {
+    pub const fn new() -> Self {
+        Self {
+            resident: RLimit::UNLIMITED,
+            address_space: RLimit::UNLIMITED,
+        }
+    }
+
+    /// Get the limit of a resource
+    pub fn get(&self, resource: Resource) -> RLimit {
+        match resource {
+            Resource::Resident => self.resident,
+            Resource::AddressSpace => self.address_space,
+        }
+    }
+
+    /// Change the limit of a resource
+    ///
+    /// Anyone may lower a hard limit, only `privileged` callers may raise it.
+    pub fn set(
+        &mut self,
+        resource: Resource,
+        limit: RLimit,
+        privileged: bool,
+    ) -> Result<(), LimitError> {
+        if limit.soft > limit.hard {
+            return Err(LimitError::Invalid);
+        }
+        if limit.hard > self.get(resource).hard && !privileged {
+            return Err(LimitError::PermissionDenied);
+        }
+
+        match resource {
+            Resource::Resident => self.resident = limit,
+            Resource::AddressSpace => self.address_space = limit,
+        }
+        Ok(())
+    }
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Memory use of a process in bytes
+///
+/// Laid out for copying to user space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(C)]
+pub struct MemoryUsage {
+    pub resident: u64,
+    pub peak_resident: u64,
+    pub virtual_size: u64,
+}
+
+impl MemoryUsage {
+    /// Current usage of a resource
+    pub fn of(&self, resource: Resource) -> u64 {
+        match resource {
+            Resource::Resident => self.resident,
+            Resource::AddressSpace => self.virtual_size,
+        }
+    }
+}
@@ -0,0 +1,1176 @@
+use crate::process::{Pid, Priority, Process};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+
+/// Number of run queues of the priority policy, one per `Priority`
+pub const LEVELS: usize = 3;
+
+/// Timer ticks a process may run at each level, indexed by `Priority`
+///
+/// Higher levels run first but get shorter slices, so interactive tasks
+/// respond quickly while CPU-bound ones sink and run longer at a time.
+pub const QUANTUM_TICKS: [u32; LEVELS] = [10, 5, 2];
+
+/// Ticks between two aging passes
+pub const AGING_INTERVAL_TICKS: u64 = 100;
+
+/// Ticks a process may wait in a queue before aging lifts it a level
+pub const STARVATION_TICKS: u64 = 100;
+
+/// Time slice of the round-robin policy
+pub const RR_QUANTUM_TICKS: u32 = 5;
+
+/// Ticks the fair policy lets a process run before it may be preempted
+pub const FAIR_MIN_GRANULARITY_TICKS: u32 = 2;
+
+/// Highest priority of the real-time classes, 1 being the lowest
+pub const RT_PRIORITY_MAX: u64 = 99;
+
+/// Time slice of `SchedClass::RoundRobin` processes
+pub const RT_RR_QUANTUM_TICKS: u32 = 10;
+
+/// Share of the CPU deadline processes may reserve together, in millionths
+///
+/// The rest is kept for everything else so a full deadline load cannot
+/// starve the system.
+pub const DEADLINE_BANDWIDTH_LIMIT: u64 = 950_000;
+
+/// Linux policy numbers of the scheduling classes
+pub const SCHED_NORMAL: u64 = 0;
+pub const SCHED_FIFO: u64 = 1;
+pub const SCHED_RR: u64 = 2;
+pub const SCHED_DEADLINE: u64 = 6;
+
+/// Unit of bandwidths, one being the whole CPU
+const BANDWIDTH_UNIT: u64 = 1_000_000;
+
+/// Longest accepted deadline period in nanoseconds, Linux's default.
+/// Keeps `runtime * BANDWIDTH_UNIT` and deadlines far from overflowing.
+const DEADLINE_PERIOD_MAX: u64 = 4_194_304_000;
+
+/// Load weight of a `Normal` process under the fair policy
+const NICE_0_WEIGHT: u64 = 1024;
+
+/// Length of one timer tick in nanoseconds
+pub const TICK_NS: u64 = 1_000_000_000 / crate::interrupts::TIMER_HZ as u64;
+
+/// Time slice of a level
+pub fn quantum(level: Priority) -> u32 {
+    QUANTUM_TICKS[level as usize]
+}
+
+/// Scheduling errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedError {
+    /// Unknown policy number
+    InvalidPolicy,
+    /// Priority or deadline parameters out of range
+    InvalidParameters,
+    /// Admitting the deadline process would exceed the bandwidth limit
+    Overcommitted,
+    ProcessNotFound,
+}
+
+/// Runtime, relative deadline and period of a deadline process, in nanoseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineParams {
+    pub runtime: u64,
+    pub deadline: u64,
+    pub period: u64,
+}
+
+impl DeadlineParams {
+    /// Share of the CPU the process reserves
+    fn bandwidth(&self) -> u64 {
+        self.runtime * BANDWIDTH_UNIT / self.period
+    }
+}
+
+/// Scheduling class of a process
+///
+/// Deadline processes run before real-time ones, which run before the
+/// normal processes the `SchedulerPolicy` picks from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedClass {
+    /// Scheduled by the boot-time policy according to `Priority`
+    Normal,
+    /// Runs until it blocks, yields or a higher priority arrives
+    Fifo(u8),
+    /// Like `Fifo`, taking turns with equal priorities every slice
+    RoundRobin(u8),
+    /// Earliest deadline first, throttled to its reserved runtime
+    Deadline(DeadlineParams),
+}
+
+impl SchedClass {
+    /// Build a class from a policy number and its parameters
+    pub fn from_param(policy: u64, param: &SchedParam) -> Result<Self, SchedError> {
+        let rt_priority = || match param.priority {
+            1..=RT_PRIORITY_MAX => Ok(param.priority as u8),
+            _ => Err(SchedError::InvalidParameters),
+        };
+        match policy {
+            SCHED_NORMAL if param.priority == 0 => Ok(Self::Normal),
+            SCHED_NORMAL => Err(SchedError::InvalidParameters),
+            SCHED_FIFO => Ok(Self::Fifo(rt_priority()?)),
+            SCHED_RR => Ok(Self::RoundRobin(rt_priority()?)),
+            SCHED_DEADLINE => {
+                let params = DeadlineParams {
+                    runtime: param.runtime,
+                    deadline: param.deadline,
+                    period: param.period,
+                };
+                // Budgets are accounted per tick, so less cannot be enforced
+                if param.priority != 0
+                    || params.runtime < TICK_NS
+                    || params.runtime > params.deadline
+                    || params.deadline > params.period
+                    || params.period > DEADLINE_PERIOD_MAX
+                {
+                    return Err(SchedError::InvalidParameters);
+                }
+                Ok(Self::Deadline(params))
+            }
+            _ => Err(SchedError::InvalidPolicy),
+        }
+    }
+
+    /// Linux policy number
+    pub fn policy(&self) -> u64 {
+        match self {
+            Self::Normal => SCHED_NORMAL,
+            Self::Fifo(_) => SCHED_FIFO,
+            Self::RoundRobin(_) => SCHED_RR,
+            Self::Deadline(_) => SCHED_DEADLINE,
+        }
+    }
+
+    /// Parameters as passed to `sched_setscheduler`
+    pub fn param(&self) -> SchedParam {
+        match *self {
+            Self::Normal => SchedParam::default(),
+            Self::Fifo(priority) | Self::RoundRobin(priority) => SchedParam {
+                priority: u64::from(priority),
+                ..SchedParam::default()
+            },
+            Self::Deadline(params) => SchedParam {
+                priority: 0,
+                runtime: params.runtime,
+                deadline: params.deadline,
+                period: params.period,
+            },
+        }
+    }
+}
+
+/// Scheduling parameters as user space passes them
+///
+/// `priority` is the real-time priority, the rest the deadline parameters
+/// in nanoseconds. Unused fields must be zero.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct SchedParam {
+    pub priority: u64,
+    pub runtime: u64,
+    pub deadline: u64,
+    pub period: u64,
+}
+
+/// Per-process state the policies keep in the PCB
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchedEntity {
+    pub class: SchedClass,
+    /// Ticks run since the process was last picked
+    pub ran: u32,
+    /// Run queue level under the priority policy
+    pub level: Priority,
+    /// Weighted run time in nanoseconds under the fair policy
+    pub vruntime: u64,
+}
+
+impl SchedEntity {
+    pub const fn new(priority: Priority) -> Self {
+        Self {
+            class: SchedClass::Normal,
+            ran: 0,
+            level: priority,
+            vruntime: 0,
+        }
+    }
+}
+
+/// Decides which runnable process runs next and for how long
+///
+/// The process manager owns the processes and reports when one becomes or
+/// stops being runnable; the policy owns the run queue. A process returned
+/// by `pick_next` is running and stays out of the queue until it is
+/// enqueued again.
+pub trait SchedulerPolicy: Send {
+    /// Name selecting the policy with `sched=` on the command line
+    fn name(&self) -> &'static str;
+
+    /// Queue a process that became runnable
+    fn enqueue(&mut self, process: &mut Process);
+
+    /// Remove a queued process that blocked or exited
+    fn dequeue(&mut self, pid: Pid);
+
+    /// Take the process to run next
+    fn pick_next(&mut self) -> Option<Pid>;
+
+    /// Account a timer tick to the running process, `None` when idle
+    ///
+    /// `ran` has already been advanced. Returns whether the process should
+    /// be preempted.
+    fn tick(&mut self, current: Option<&mut Process>) -> bool;
+
+    /// The running process gives up the CPU before its slice is over
+    fn yield_task(&mut self, _process: &mut Process) {}
+
+    /// Number of queued processes
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Look up a policy by name
+pub fn by_name(name: &str) -> Option<Box<dyn SchedulerPolicy>> {
+    match name {
+        "rr" => Some(Box::new(RoundRobin::new())),
+        "priority" => Some(Box::new(Mlfq::new())),
+        "fair" => Some(Box::new(Fair::new())),
+        _ => None,
+    }
+}
+
+/// The policy named by `sched=` on the command line, `priority` by default
+pub fn from_cmdline() -> Box<dyn SchedulerPolicy> {
+    let name = crate::cmdline::value("sched").unwrap_or("priority");
+    by_name(name).unwrap_or_else(|| {
+        crate::serial_println!("[SCHED] Unknown policy {}, using priority", name);
+        Box::new(Mlfq::new())
+    })
+}
+
+/// Every process gets the same slice in turn
+pub struct RoundRobin {
+    queue: VecDeque<Pid>,
+}
+
+impl RoundRobin {
+    pub const fn new() -> Self {
+        Self {
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+impl Default for RoundRobin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SchedulerPolicy for RoundRobin {
+    fn name(&self) -> &'static str {
+        "rr"
+    }
+
+    fn enqueue(&mut self, process: &mut Process) {
+        self.queue.push_back(process.pid);
+    }
+
+    fn dequeue(&mut self, pid: Pid) {
+        self.queue.retain(|&queued| queued != pid);
+    }
+
+    fn pick_next(&mut self) -> Option<Pid> {
+        self.queue.pop_front()
+    }
+
+    fn tick(&mut self, current: Option<&mut Process>) -> bool {
+        current.is_some_and(|process| process.sched.ran >= RR_QUANTUM_TICKS)
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+/// A process waiting in a feedback queue
+#[derive(Debug, Clone, Copy)]
+struct Waiting {
+    pid: Pid,
+    /// Tick it was queued at this level
+    since: u64,
+}
+
+/// Multi-level feedback queues honouring `Priority`
+///
+/// Always picks from the highest non-empty level, round-robin within a
+/// level. Processes that use up their slice are CPU-bound and drop a
+/// level; those that give up the CPU early rise one, up to their priority.
+/// Aging lifts processes that waited too long a level, so even a low
+/// priority process eventually runs while higher ones stay busy.
+pub struct Mlfq {
+    queues: [VecDeque<Waiting>; LEVELS],
+    ticks: u64,
+    /// Level the running process was picked from, aging included
+    running_level: Priority,
+}
+
+impl Mlfq {
+    pub const fn new() -> Self {
+        Self {
+            queues: [const { VecDeque::new() }; LEVELS],
+            ticks: 0,
+            running_level: Priority::Normal,
+        }
+    }
+
+    fn push(&mut self, pid: Pid, level: Priority) {
+        self.queues[level as usize].push_back(Waiting {
+            pid,
+            since: self.ticks,
+        });
+    }
+
+    /// Lift every process that waited too long one level up
+    pub fn age(&mut self) {
+        for (lower, upper) in [
+            (Priority::Normal, Priority::High),
+            (Priority::Low, Priority::Normal),
+        ] {
+            let now = self.ticks;
+            let (starved, waiting): (VecDeque<_>, VecDeque<_>) = self.queues[lower as usize]
+                .drain(..)
+                .partition(|waiting| now - waiting.since >= STARVATION_TICKS);
+            self.queues[lower as usize] = waiting;
+            for waiting in starved {
+                self.push(waiting.pid, upper);
+            }
+        }
+    }
+
+    /// Number of processes waiting at `level`
+    pub fn len_at(&self, level: Priority) -> usize {
+        self.queues[level as usize].len()
+    }
+}
+
+impl Default for Mlfq {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SchedulerPolicy for Mlfq {
+    fn name(&self) -> &'static str {
+        "priority"
+    }
+
+    fn enqueue(&mut self, process: &mut Process) {
+        self.push(process.pid, process.sched.level);
+    }
+
+    fn dequeue(&mut self, pid: Pid) {
+        for queue in &mut self.queues {
+            queue.retain(|waiting| waiting.pid != pid);
+        }
+    }
+
+    fn pick_next(&mut self) -> Option<Pid> {
+        for level in [Priority::High, Priority::Normal, Priority::Low] {
+            if let Some(waiting) = self.queues[level as usize].pop_front() {
+                self.running_level = level;
+                return Some(waiting.pid);
+            }
+        }
+        None
+    }
+
+    fn tick(&mut self, current: Option<&mut Process>) -> bool {
+        self.ticks += 1;
+        if self.ticks.is_multiple_of(AGING_INTERVAL_TICKS) {
+            self.age();
+        }
+
+        let Some(process) = current else {
+            return false;
+        };
+        process.sched.level = self.running_level;
+        if process.sched.ran < quantum(self.running_level) {
+            return false;
+        }
+        process.sched.level = self.running_level.lowered();
+        true
+    }
+
+    fn yield_task(&mut self, process: &mut Process) {
+        if process.sched.ran < quantum(self.running_level) {
+            process.sched.level = core::cmp::min(self.running_level.raised(), process.priority);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+}
+
+/// Shares the CPU in proportion to priority by weighted run time
+///
+/// Runs the process that has had the least weighted CPU time. A process
+/// that comes back from sleeping starts no further behind than the
+/// least-served queued one, so it cannot hog the CPU to catch up.
+pub struct Fair {
+    queue: BTreeSet<(u64, Pid)>,
+    min_vruntime: u64,
+}
+
+impl Fair {
+    pub const fn new() -> Self {
+        Self {
+            queue: BTreeSet::new(),
+            min_vruntime: 0,
+        }
+    }
+
+    /// Share of the CPU a process gets relative to others
+    fn weight(priority: Priority) -> u64 {
+        match priority {
+            Priority::Low => 335,
+            Priority::Normal => NICE_0_WEIGHT,
+            Priority::High => 3121,
+        }
+    }
+}
+
+impl Default for Fair {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SchedulerPolicy for Fair {
+    fn name(&self) -> &'static str {
+        "fair"
+    }
+
+    fn enqueue(&mut self, process: &mut Process) {
+        let sched = &mut process.sched;
+        sched.vruntime = core::cmp::max(sched.vruntime, self.min_vruntime);
+        self.queue.insert((sched.vruntime, process.pid));
+    }
+
+    fn dequeue(&mut self, pid: Pid) {
+        self.queue.retain(|&(_, queued)| queued != pid);
+    }
+
+    fn pick_next(&mut self) -> Option<Pid> {
+        let (vruntime, pid) = self.queue.pop_first()?;
+        self.min_vruntime = core::cmp::max(self.min_vruntime, vruntime);
+        Some(pid)
+    }
+
+    fn tick(&mut self, current: Option<&mut Process>) -> bool {
+        let Some(process) = current else {
+            return false;
+        };
+        let weight = Self::weight(process.priority);
+        process.sched.vruntime += TICK_NS * NICE_0_WEIGHT / weight;
+
+        process.sched.ran >= FAIR_MIN_GRANULARITY_TICKS
+            && self
+                .queue
+                .first()
+                .is_some_and(|&(vruntime, _)| vruntime < process.sched.vruntime)
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+/// Fixed-priority queues of the `Fifo` and `RoundRobin` classes
+pub struct RealTime {
+    queues: BTreeMap<u8, VecDeque<Pid>>,
+    /// Process a higher priority took the CPU from, resumed first
+    preempted: Option<Pid>,
+}
+
+impl RealTime {
+    pub const fn new() -> Self {
+        Self {
+            queues: BTreeMap::new(),
+            preempted: None,
+        }
+    }
+
+    fn enqueue(&mut self, pid: Pid, priority: u8) {
+        let queue = self.queues.entry(priority).or_default();
+        if self.preempted == Some(pid) {
+            self.preempted = None;
+            queue.push_front(pid);
+        } else {
+            queue.push_back(pid);
+        }
+    }
+
+    fn dequeue(&mut self, pid: Pid) {
+        for queue in self.queues.values_mut() {
+            queue.retain(|&queued| queued != pid);
+        }
+        self.queues.retain(|_, queue| !queue.is_empty());
+    }
+
+    fn pick_next(&mut self) -> Option<Pid> {
+        let mut entry = self.queues.last_entry()?;
+        let pid = entry.get_mut().pop_front();
+        if entry.get().is_empty() {
+            entry.remove();
+        }
+        pid
+    }
+
+    /// Account a tick to a running real-time process
+    fn tick(&mut self, process: &mut Process) -> bool {
+        let (priority, quantum) = match process.sched.class {
+            SchedClass::Fifo(priority) => (priority, None),
+            SchedClass::RoundRobin(priority) => (priority, Some(RT_RR_QUANTUM_TICKS)),
+            _ => return false,
+        };
+        if self
+            .queues
+            .last_key_value()
+            .is_some_and(|(&highest, _)| highest > priority)
+        {
+            self.preempted = Some(process.pid);
+            return true;
+        }
+        quantum.is_some_and(|quantum| process.sched.ran >= quantum)
+    }
+
+    fn len(&self) -> usize {
+        self.queues.values().map(VecDeque::len).sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+}
+
+impl Default for RealTime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Budget of one admitted deadline process
+#[derive(Debug, Clone, Copy)]
+struct DeadlineTask {
+    params: DeadlineParams,
+    /// Runtime left in the current period
+    remaining: u64,
+    /// Absolute deadline of the current period
+    deadline: u64,
+    /// When a throttled process gets its budget back
+    throttled_until: Option<u64>,
+    /// Whether the process is runnable and not running
+    queued: bool,
+}
+
+impl DeadlineTask {
+    /// Start a new period at `now` with a full budget
+    fn replenish(&mut self, now: u64) {
+        self.remaining = self.params.runtime;
+        self.deadline = now + self.params.deadline;
+    }
+
+    /// Drop the rest of the budget until the next period
+    fn throttle(&mut self, now: u64) {
+        let period_start = self.deadline - self.params.deadline;
+        self.remaining = 0;
+        self.throttled_until = Some(core::cmp::max(period_start + self.params.period, now));
+    }
+}
+
+/// Earliest deadline first with admission control
+///
+/// Each process reserves `runtime` out of every `period` and must get it
+/// before `deadline`. Admission keeps the reserved bandwidth under
+/// `DEADLINE_BANDWIDTH_LIMIT`, which makes every deadline meetable; a
+/// process that overruns its budget is throttled until its next period
+/// so it cannot eat into the reservations of others.
+pub struct Deadline {
+    tasks: BTreeMap<Pid, DeadlineTask>,
+    ready: BTreeSet<(u64, Pid)>,
+    /// Bandwidth admitted so far
+    bandwidth: u64,
+    /// Nanoseconds since the first tick
+    now: u64,
+}
+
+impl Deadline {
+    pub const fn new() -> Self {
+        Self {
+            tasks: BTreeMap::new(),
+            ready: BTreeSet::new(),
+            bandwidth: 0,
+            now: 0,
+        }
+    }
+
+    /// Reserve bandwidth for a process, replacing what it had
+    ///
+    /// The process must not be queued.
+    fn admit(&mut self, pid: Pid, params: DeadlineParams) -> Result<(), SchedError> {
+        let previous = self
+            .tasks
+            .get(&pid)
+            .map_or(0, |task| task.params.bandwidth());
+        let bandwidth = self.bandwidth - previous + params.bandwidth();
+        if bandwidth > DEADLINE_BANDWIDTH_LIMIT {
+            return Err(SchedError::Overcommitted);
+        }
+        self.bandwidth = bandwidth;
+        let mut task = DeadlineTask {
+            params,
+            remaining: 0,
+            deadline: 0,
+            throttled_until: None,
+            queued: false,
+        };
+        task.replenish(self.now);
+        self.tasks.insert(pid, task);
+        Ok(())
+    }
+
+    /// Give back the bandwidth of a process
+    fn release(&mut self, pid: Pid) {
+        self.dequeue(pid);
+        if let Some(task) = self.tasks.remove(&pid) {
+            self.bandwidth -= task.params.bandwidth();
+        }
+    }
+
+    /// Bandwidth admitted so far, in millionths of the CPU
+    pub fn bandwidth(&self) -> u64 {
+        self.bandwidth
+    }
+
+    fn enqueue(&mut self, pid: Pid) {
+        let now = self.now;
+        let Some(task) = self.tasks.get_mut(&pid) else {
+            return;
+        };
+        task.queued = true;
+        if task.throttled_until.is_some() {
+            return;
+        }
+        // Running the rest of the budget by the old deadline would take
+        // more than the reserved share, so start a fresh period
+        let left = task.deadline.saturating_sub(now);
+        if u128::from(task.remaining) * u128::from(task.params.period)
+            > u128::from(left) * u128::from(task.params.runtime)
+        {
+            task.replenish(now);
+        }
+        self.ready.insert((task.deadline, pid));
+    }
+
+    fn dequeue(&mut self, pid: Pid) {
+        if let Some(task) = self.tasks.get_mut(&pid) {
+            task.queued = false;
+            self.ready.remove(&(task.deadline, pid));
+        }
+    }
+
+    fn pick_next(&mut self) -> Option<Pid> {
+        let (_, pid) = self.ready.pop_first()?;
+        if let Some(task) = self.tasks.get_mut(&pid) {
+            task.queued = false;
+        }
+        Some(pid)
+    }
+
+    /// Advance time, charging the tick to `current` if it is a deadline process
+    ///
+    /// Returns whether the running process should make way, either for a
+    /// deadline process when it is not one or for an earlier deadline.
+    fn tick(&mut self, current: Option<Pid>) -> bool {
+        self.now += TICK_NS;
+        let now = self.now;
+
+        let mut preempt = false;
+        if let Some(task) = current.and_then(|pid| self.tasks.get_mut(&pid)) {
+            task.remaining = task.remaining.saturating_sub(TICK_NS);
+            if task.remaining == 0 {
+                task.throttle(now);
+                preempt = true;
+            }
+        }
+
+        for (&pid, task) in self.tasks.iter_mut() {
+            if task.throttled_until.is_some_and(|until| until <= now) {
+                task.throttled_until = None;
+                task.replenish(now);
+                if task.queued {
+                    self.ready.insert((task.deadline, pid));
+                }
+            }
+        }
+
+        let earliest = self.ready.first().map(|&(deadline, _)| deadline);
+        match current.and_then(|pid| self.tasks.get(&pid)) {
+            Some(task) => preempt || earliest.is_some_and(|earliest| earliest < task.deadline),
+            None => earliest.is_some(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.ready.len()
+    }
+}
+
+impl Default for Deadline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Every runnable process, by scheduling class
+///
+/// Deadline processes go first, then real-time ones, then whatever the
+/// boot-time policy picks among normal processes. A higher class arriving
+/// preempts a lower one on the next tick.
+pub struct RunQueue {
+    deadline: Deadline,
+    realtime: RealTime,
+    policy: Box<dyn SchedulerPolicy>,
+}
+
+impl RunQueue {
+    pub fn new(policy: Box<dyn SchedulerPolicy>) -> Self {
+        Self {
+            deadline: Deadline::new(),
+            realtime: RealTime::new(),
+            policy,
+        }
+    }
+
+    /// Name of the policy for normal processes
+    pub fn policy_name(&self) -> &'static str {
+        self.policy.name()
+    }
+
+    /// Bandwidth reserved by deadline processes, in millionths of the CPU
+    pub fn deadline_bandwidth(&self) -> u64 {
+        self.deadline.bandwidth()
+    }
+
+    /// Queue a process that became runnable
+    pub fn enqueue(&mut self, process: &mut Process) {
+        match process.sched.class {
+            SchedClass::Normal => self.policy.enqueue(process),
+            SchedClass::Fifo(priority) | SchedClass::RoundRobin(priority) => {
+                self.realtime.enqueue(process.pid, priority)
+            }
+            SchedClass::Deadline(_) => self.deadline.enqueue(process.pid),
+        }
+    }
+
+    /// Remove a queued process that stopped being runnable
+    pub fn dequeue(&mut self, pid: Pid) {
+        self.deadline.dequeue(pid);
+        self.realtime.dequeue(pid);
+        self.policy.dequeue(pid);
+    }
+
+    /// Forget an exiting process, releasing its reservation
+    pub fn remove(&mut self, pid: Pid) {
+        self.dequeue(pid);
+        self.deadline.release(pid);
+    }
+
+    /// Take the process to run next
+    pub fn pick_next(&mut self) -> Option<Pid> {
+        self.deadline
+            .pick_next()
+            .or_else(|| self.realtime.pick_next())
+            .or_else(|| self.policy.pick_next())
+    }
+
+    /// Account a timer tick to the running process, `None` when idle
+    ///
+    /// `ran` has already been advanced. Returns whether the process should
+    /// be preempted.
+    pub fn tick(&mut self, current: Option<&mut Process>) -> bool {
+        match current {
+            Some(process) if matches!(process.sched.class, SchedClass::Deadline(_)) => {
+                self.policy.tick(None);
+                self.deadline.tick(Some(process.pid))
+            }
+            Some(process) if process.sched.class != SchedClass::Normal => {
+                self.policy.tick(None);
+                let deadline = self.deadline.tick(None);
+                if deadline {
+                    self.realtime.preempted = Some(process.pid);
+                }
+                self.realtime.tick(process) || deadline
+            }
+            current => {
+                let higher = self.deadline.tick(None) || !self.realtime.is_empty();
+                self.policy.tick(current) || higher
+            }
+        }
+    }
+
+    /// The running process gives up the CPU before its slice is over
+    ///
+    /// A deadline process gives up the rest of its budget for the period.
+    pub fn yield_task(&mut self, process: &mut Process) {
+        match process.sched.class {
+            SchedClass::Normal => self.policy.yield_task(process),
+            SchedClass::Fifo(_) | SchedClass::RoundRobin(_) => {}
+            SchedClass::Deadline(_) => {
+                if let Some(task) = self.deadline.tasks.get_mut(&process.pid) {
+                    task.throttle(self.deadline.now);
+                }
+            }
+        }
+    }
+
+    /// The running process blocks
+    pub fn block(&mut self, process: &mut Process) {
+        if process.sched.class == SchedClass::Normal {
+            self.policy.yield_task(process);
+        }
+    }
+
+    /// Move a process to another class
+    ///
+    /// `queued` tells whether the process is waiting in the run queue.
+    /// Deadline parameters are admitted first and nothing changes if they
+    /// do not fit.
+    pub fn set_class(
+        &mut self,
+        process: &mut Process,
+        class: SchedClass,
+        queued: bool,
+    ) -> Result<(), SchedError> {
+        if queued {
+            self.dequeue(process.pid);
+        }
+        let admitted = match class {
+            SchedClass::Deadline(params) => self.deadline.admit(process.pid, params),
+            _ => {
+                self.deadline.release(process.pid);
+                Ok(())
+            }
+        };
+        if admitted.is_ok() {
+            process.sched.class = class;
+        }
+        if queued {
+            self.enqueue(process);
+        }
+        admitted
+    }
+
+    /// Number of queued processes
+    pub fn len(&self) -> usize {
+        self.deadline.len() + self.realtime.len() + self.policy.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::process::{Pid, Priority, Process};
+    use crate::sched::{
+        self, DeadlineParams, Fair, Mlfq, RoundRobin, RunQueue, SchedClass, SchedError, SchedParam,
+        SchedulerPolicy, STARVATION_TICKS, TICK_NS,
+    };
+    use crate::{serial_print, serial_println};
+    use alloc::boxed::Box;
+
+    fn process(pid: Pid, priority: Priority) -> Process {
+        let mut process = Process::new(pid, None, None);
+        process.set_priority(priority);
+        process
+    }
+
+    #[test_case]
+    fn test_highest_level_first() {
+        serial_print!("test_highest_level_first... ");
+        let mut policy = Mlfq::new();
+        policy.enqueue(&mut process(1, Priority::Low));
+        policy.enqueue(&mut process(2, Priority::Normal));
+        policy.enqueue(&mut process(3, Priority::High));
+        policy.enqueue(&mut process(4, Priority::High));
+
+        assert_eq!(policy.pick_next(), Some(3));
+        assert_eq!(policy.pick_next(), Some(4));
+        assert_eq!(policy.pick_next(), Some(2));
+        assert_eq!(policy.pick_next(), Some(1));
+        assert_eq!(policy.pick_next(), None);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_higher_levels_get_shorter_slices() {
+        serial_print!("test_higher_levels_get_shorter_slices... ");
+        assert!(sched::quantum(Priority::High) < sched::quantum(Priority::Normal));
+        assert!(sched::quantum(Priority::Normal) < sched::quantum(Priority::Low));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_aging_lifts_starved_processes() {
+        serial_print!("test_aging_lifts_starved_processes... ");
+        let mut policy = Mlfq::new();
+        policy.enqueue(&mut process(1, Priority::Low));
+        for _ in 0..STARVATION_TICKS - 1 {
+            policy.tick(None);
+        }
+        // A fresh arrival has not waited long enough to move
+        policy.enqueue(&mut process(2, Priority::Low));
+        policy.tick(None);
+        policy.age();
+
+        assert_eq!(policy.len_at(Priority::Normal), 1);
+        assert_eq!(policy.len_at(Priority::Low), 1);
+        assert_eq!(policy.pick_next(), Some(1));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_round_robin_preempts_after_slice() {
+        serial_print!("test_round_robin_preempts_after_slice... ");
+        let mut policy = RoundRobin::new();
+        let mut running = process(1, Priority::High);
+        policy.enqueue(&mut running);
+        policy.enqueue(&mut process(2, Priority::Low));
+        assert_eq!(policy.pick_next(), Some(1));
+
+        running.sched.ran = sched::RR_QUANTUM_TICKS - 1;
+        assert!(!policy.tick(Some(&mut running)));
+        running.sched.ran += 1;
+        assert!(policy.tick(Some(&mut running)));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_fair_runs_least_served_first() {
+        serial_print!("test_fair_runs_least_served_first... ");
+        let mut policy = Fair::new();
+        let mut busy = process(1, Priority::Normal);
+        busy.sched.vruntime = 1_000_000;
+        policy.enqueue(&mut busy);
+        policy.enqueue(&mut process(2, Priority::Normal));
+
+        assert_eq!(policy.pick_next(), Some(2));
+        assert_eq!(policy.pick_next(), Some(1));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_fair_weighs_priority() {
+        serial_print!("test_fair_weighs_priority... ");
+        let mut policy = Fair::new();
+        let mut high = process(1, Priority::High);
+        let mut low = process(2, Priority::Low);
+        policy.tick(Some(&mut high));
+        policy.tick(Some(&mut low));
+
+        assert!(high.sched.vruntime < low.sched.vruntime);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_policies_by_name() {
+        serial_print!("test_policies_by_name... ");
+        for name in ["rr", "priority", "fair"] {
+            assert_eq!(sched::by_name(name).map(|policy| policy.name()), Some(name));
+        }
+        assert!(sched::by_name("lottery").is_none());
+        serial_println!("[ok]");
+    }
+
+    fn deadline(runtime_ticks: u64, period_ticks: u64) -> SchedClass {
+        SchedClass::Deadline(DeadlineParams {
+            runtime: runtime_ticks * TICK_NS,
+            deadline: period_ticks * TICK_NS,
+            period: period_ticks * TICK_NS,
+        })
+    }
+
+    #[test_case]
+    fn test_realtime_runs_before_normal() {
+        serial_print!("test_realtime_runs_before_normal... ");
+        let mut queue = RunQueue::new(Box::new(Mlfq::new()));
+        let mut normal = process(1, Priority::High);
+        let mut low = process(2, Priority::Low);
+        let mut high = process(3, Priority::Low);
+        queue.enqueue(&mut normal);
+        queue
+            .set_class(&mut low, SchedClass::Fifo(10), false)
+            .unwrap();
+        queue
+            .set_class(&mut high, SchedClass::RoundRobin(50), false)
+            .unwrap();
+        queue.enqueue(&mut low);
+        queue.enqueue(&mut high);
+
+        assert_eq!(queue.pick_next(), Some(3));
+        assert_eq!(queue.pick_next(), Some(2));
+        assert_eq!(queue.pick_next(), Some(1));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_fifo_preempted_by_higher_priority() {
+        serial_print!("test_fifo_preempted_by_higher_priority... ");
+        let mut queue = RunQueue::new(Box::new(RoundRobin::new()));
+        let mut running = process(1, Priority::Normal);
+        queue
+            .set_class(&mut running, SchedClass::Fifo(10), false)
+            .unwrap();
+        // FIFO has no time slice
+        running.sched.ran = 1000;
+        assert!(!queue.tick(Some(&mut running)));
+
+        let mut urgent = process(2, Priority::Normal);
+        queue
+            .set_class(&mut urgent, SchedClass::Fifo(20), false)
+            .unwrap();
+        queue.enqueue(&mut urgent);
+        assert!(queue.tick(Some(&mut running)));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_earliest_deadline_first() {
+        serial_print!("test_earliest_deadline_first... ");
+        let mut queue = RunQueue::new(Box::new(RoundRobin::new()));
+        let mut rt = process(1, Priority::Normal);
+        let mut late = process(2, Priority::Normal);
+        let mut early = process(3, Priority::Normal);
+        queue
+            .set_class(&mut rt, SchedClass::Fifo(99), false)
+            .unwrap();
+        queue.set_class(&mut late, deadline(2, 20), false).unwrap();
+        queue.set_class(&mut early, deadline(2, 10), false).unwrap();
+        queue.enqueue(&mut rt);
+        queue.enqueue(&mut late);
+        queue.enqueue(&mut early);
+
+        assert_eq!(queue.pick_next(), Some(3));
+        assert_eq!(queue.pick_next(), Some(2));
+        assert_eq!(queue.pick_next(), Some(1));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_deadline_throttled_after_runtime() {
+        serial_print!("test_deadline_throttled_after_runtime... ");
+        let mut queue = RunQueue::new(Box::new(RoundRobin::new()));
+        let mut running = process(1, Priority::Normal);
+        queue
+            .set_class(&mut running, deadline(2, 10), false)
+            .unwrap();
+
+        assert!(!queue.tick(Some(&mut running)));
+        assert!(queue.tick(Some(&mut running)));
+        // Out of budget, it waits for the next period
+        queue.enqueue(&mut running);
+        assert_eq!(queue.pick_next(), None);
+        for _ in 0..8 {
+            queue.tick(None);
+        }
+        assert_eq!(queue.pick_next(), Some(1));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_deadline_admission_control() {
+        serial_print!("test_deadline_admission_control... ");
+        let mut queue = RunQueue::new(Box::new(RoundRobin::new()));
+        let mut first = process(1, Priority::Normal);
+        let mut second = process(2, Priority::Normal);
+        queue.set_class(&mut first, deadline(6, 10), false).unwrap();
+        assert_eq!(
+            queue.set_class(&mut second, deadline(4, 10), false),
+            Err(SchedError::Overcommitted)
+        );
+        assert_eq!(second.sched.class, SchedClass::Normal);
+
+        // Leaving the class gives the bandwidth back
+        queue.remove(1);
+        assert_eq!(queue.deadline_bandwidth(), 0);
+        queue
+            .set_class(&mut second, deadline(4, 10), false)
+            .unwrap();
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_sched_param_validation() {
+        serial_print!("test_sched_param_validation... ");
+        let rt = |priority| SchedParam {
+            priority,
+            ..SchedParam::default()
+        };
+        assert_eq!(
+            SchedClass::from_param(sched::SCHED_FIFO, &rt(99)),
+            Ok(SchedClass::Fifo(99))
+        );
+        assert_eq!(
+            SchedClass::from_param(sched::SCHED_RR, &rt(0)),
+            Err(SchedError::InvalidParameters)
+        );
+        assert_eq!(
+            SchedClass::from_param(sched::SCHED_NORMAL, &rt(5)),
+            Err(SchedError::InvalidParameters)
+        );
+        assert_eq!(
+            SchedClass::from_param(3, &rt(0)),
+            Err(SchedError::InvalidPolicy)
+        );
+
+        // Runtime may not exceed the deadline
+        let param = SchedParam {
+            priority: 0,
+            runtime: 5 * TICK_NS,
+            deadline: 4 * TICK_NS,
+            period: 10 * TICK_NS,
+        };
+        assert_eq!(
+            SchedClass::from_param(sched::SCHED_DEADLINE, &param),
+            Err(SchedError::InvalidParameters)
+        );
+
+        // Huge values would overflow the bandwidth
+        let param = SchedParam {
+            priority: 0,
+            runtime: u64::MAX,
+            deadline: u64::MAX,
+            period: u64::MAX,
+        };
+        assert_eq!(
+            SchedClass::from_param(sched::SCHED_DEADLINE, &param),
+            Err(SchedError::InvalidParameters)
+        );
+        let class = deadline(2, 10);
+        assert_eq!(
+            SchedClass::from_param(sched::SCHED_DEADLINE, &class.param()),
+            Ok(class)
+        );
+        serial_println!("[ok]");
+    }
+}
@@ -1,7 +1,13 @@
+use crate::page::PageOwner;
 use crate::process::Pid;
+use crate::vma::{VmProtection, VmaError};
+use alloc::vec;
 use alloc::vec::Vec;
 use spin::Mutex;
-use x86_64::VirtAddr;
+use x86_64::{
+    structures::paging::{PageSize, PhysFrame, Size2MiB, Size4KiB},
+    VirtAddr,
+};
 
 /// Shared memory segment ID
 pub type ShmId = u64;
@@ -32,6 +38,23 @@ impl ShmPermissions {
         write: true,
         execute: true,
     };
+
+    /// Check whether everything `other` allows is allowed here too
+    pub fn covers(self, other: Self) -> bool {
+        (self.read || !other.read)
+            && (self.write || !other.write)
+            && (self.execute || !other.execute)
+    }
+}
+
+impl From<VmProtection> for ShmPermissions {
+    fn from(protection: VmProtection) -> Self {
+        Self {
+            read: protection.contains(VmProtection::READ),
+            write: protection.contains(VmProtection::WRITE),
+            execute: protection.contains(VmProtection::EXEC),
+        }
+    }
 }
 
 /// Shared memory segment
@@ -41,17 +64,80 @@ pub struct SharedMemory {
     owner: Pid,
     size: usize,
     address: VirtAddr,
+    /// Access processes other than the owner may attach with
+    shared: ShmPermissions,
+    /// Processes with the segment mapped
     attached_processes: Vec<(Pid, ShmPermissions)>,
+    /// Deleted by the owner, freed once the last process detaches
+    deleted: bool,
+    /// Size of each backing block, 4 KiB or 2 MiB
+    page_size: u64,
+    /// Backing blocks of `page_size` bytes, allocated on first use
+    frames: Vec<Option<PhysFrame>>,
 }
 
 impl SharedMemory {
-    fn new(id: ShmId, owner: Pid, size: usize, address: VirtAddr) -> Self {
+    fn new(
+        id: ShmId,
+        owner: Pid,
+        size: usize,
+        address: VirtAddr,
+        shared: ShmPermissions,
+        page_size: u64,
+    ) -> Self {
         Self {
             id,
             owner,
             size,
             address,
+            shared,
             attached_processes: Vec::new(),
+            deleted: false,
+            page_size,
+            frames: vec![None; (size as u64).div_ceil(page_size) as usize],
+        }
+    }
+
+    /// Buddy order of one backing block
+    fn order(&self) -> usize {
+        if self.page_size == Size2MiB::SIZE {
+            crate::buddy::order_of::<Size2MiB>()
+        } else {
+            crate::buddy::order_of::<Size4KiB>()
+        }
+    }
+
+    /// Get the 4 KiB frame backing `offset`, allocating its block on first use
+    fn frame_at(&mut self, offset: u64) -> Result<PhysFrame, ShmError> {
+        let order = self.order();
+        let slot = self
+            .frames
+            .get_mut((offset / self.page_size) as usize)
+            .ok_or(ShmError::InvalidSize)?;
+
+        let block = match slot {
+            Some(block) => *block,
+            None => {
+                let block =
+                    crate::buddy::allocate_zeroed_frames(order).ok_or(ShmError::OutOfMemory)?;
+                for frame in PhysFrame::range(block, block + (1 << order)) {
+                    crate::page::set_owner(frame, PageOwner::SharedMemory(self.id));
+                }
+                *slot = Some(block);
+                block
+            }
+        };
+
+        Ok(PhysFrame::containing_address(
+            block.start_address() + offset % self.page_size,
+        ))
+    }
+
+    /// Return all backing frames to the physical allocator
+    fn release_frames(&mut self) {
+        let order = self.order();
+        for frame in self.frames.iter_mut().filter_map(|f| f.take()) {
+            crate::buddy::free_frames(frame, order);
         }
     }
 
@@ -62,6 +148,15 @@ impl SharedMemory {
             return Err(ShmError::AlreadyAttached);
         }
 
+        let allowed = if pid == self.owner {
+            ShmPermissions::ALL
+        } else {
+            self.shared
+        };
+        if !allowed.covers(perms) {
+            return Err(ShmError::PermissionDenied);
+        }
+
         self.attached_processes.push((pid, perms));
         Ok(())
     }
@@ -78,11 +173,11 @@ impl SharedMemory {
         }
     }
 
-    /// Check if a process has permission
-    fn check_permission(&self, pid: Pid, write: bool) -> bool {
+    /// Check if a process is attached with at least `wanted`
+    fn check_permission(&self, pid: Pid, wanted: ShmPermissions) -> bool {
         for (p, perms) in &self.attached_processes {
             if *p == pid {
-                return if write { perms.write } else { perms.read };
+                return perms.covers(wanted);
             }
         }
         false
@@ -109,33 +204,66 @@ impl ShmManager {
     }
 
     /// Create a new shared memory segment
-    pub fn create(&mut self, owner: Pid, size: usize) -> Result<ShmId, ShmError> {
+    ///
+    /// The owner may attach with any access, other processes with at most
+    /// `shared`.
+    pub fn create(
+        &mut self,
+        owner: Pid,
+        size: usize,
+        shared: ShmPermissions,
+    ) -> Result<ShmId, ShmError> {
+        self.create_with_page_size(owner, size, shared, Size4KiB::SIZE)
+    }
+
+    /// Create a new shared memory segment backed by 2 MiB pages
+    ///
+    /// The size is rounded up to a whole number of huge pages.
+    pub fn create_huge(
+        &mut self,
+        owner: Pid,
+        size: usize,
+        shared: ShmPermissions,
+    ) -> Result<ShmId, ShmError> {
+        let size = (size as u64).next_multiple_of(Size2MiB::SIZE) as usize;
+        self.create_with_page_size(owner, size, shared, Size2MiB::SIZE)
+    }
+
+    fn create_with_page_size(
+        &mut self,
+        owner: Pid,
+        size: usize,
+        shared: ShmPermissions,
+        page_size: u64,
+    ) -> Result<ShmId, ShmError> {
         if size == 0 || size > MAX_SHM_SIZE {
             return Err(ShmError::InvalidSize);
         }
 
-        // Allocate virtual address (simplified - should use proper memory allocation)
-        let address = VirtAddr::new(0x8000_0000_0000 + (self.next_id * 0x10000));
+        // Each segment id gets its own stride of the randomized window
+        let slot = self.next_id % (SHM_WINDOW_SIZE / MAX_SHM_SIZE as u64);
+        let address = VirtAddr::new(crate::kaslr::shm_base() + slot * MAX_SHM_SIZE as u64);
 
         let id = self.next_id;
         self.next_id += 1;
 
-        let mut segment = SharedMemory::new(id, owner, size, address);
-        segment.attach(owner, ShmPermissions::ALL)?;
-
+        let segment = SharedMemory::new(id, owner, size, address, shared, page_size);
         self.segments.push(segment);
 
         crate::serial_println!(
-            "[SHM] Created segment {} for process {} ({} bytes)",
+            "[SHM] Created segment {} for process {} ({} bytes, {} KB pages)",
             id,
             owner,
-            size
+            size,
+            page_size / 1024
         );
 
         Ok(id)
     }
 
     /// Attach to a shared memory segment
+    ///
+    /// Segments waiting to be deleted cannot be attached anymore.
     pub fn attach(
         &mut self,
         id: ShmId,
@@ -143,7 +271,7 @@ impl ShmManager {
         perms: ShmPermissions,
     ) -> Result<VirtAddr, ShmError> {
         for segment in &mut self.segments {
-            if segment.id == id {
+            if segment.id == id && !segment.deleted {
                 segment.attach(pid, perms)?;
                 crate::serial_println!("[SHM] Process {} attached to segment {}", pid, id);
                 return Ok(segment.address);
@@ -152,38 +280,96 @@ impl ShmManager {
         Err(ShmError::NotFound)
     }
 
+    /// Attach `child` with the access `parent` is attached with, for fork
+    pub fn duplicate(&mut self, id: ShmId, parent: Pid, child: Pid) -> Result<(), ShmError> {
+        let segment = self
+            .segments
+            .iter_mut()
+            .find(|s| s.id == id)
+            .ok_or(ShmError::NotFound)?;
+        let perms = segment
+            .attached_processes
+            .iter()
+            .find(|(p, _)| *p == parent)
+            .map(|(_, perms)| *perms)
+            .ok_or(ShmError::NotAttached)?;
+        segment.attach(child, perms)
+    }
+
     /// Detach from a shared memory segment
+    ///
+    /// The last process to detach from a deleted segment frees it.
     pub fn detach(&mut self, id: ShmId, pid: Pid) -> Result<(), ShmError> {
-        for segment in &mut self.segments {
-            if segment.id == id {
-                segment.detach(pid)?;
-                crate::serial_println!("[SHM] Process {} detached from segment {}", pid, id);
-                return Ok(());
-            }
+        let i = self
+            .segments
+            .iter()
+            .position(|s| s.id == id)
+            .ok_or(ShmError::NotFound)?;
+        let segment = &mut self.segments[i];
+        segment.detach(pid)?;
+        crate::serial_println!("[SHM] Process {} detached from segment {}", pid, id);
+
+        if segment.deleted && segment.attachment_count() == 0 {
+            self.remove(i);
         }
-        Err(ShmError::NotFound)
+        Ok(())
     }
 
     /// Delete a shared memory segment
+    ///
+    /// Its frames stay until every process has detached, which unmaps them.
     pub fn delete(&mut self, id: ShmId, pid: Pid) -> Result<(), ShmError> {
-        for (i, segment) in self.segments.iter().enumerate() {
-            if segment.id == id {
-                // Only owner can delete
-                if segment.owner != pid {
-                    return Err(ShmError::PermissionDenied);
-                }
-
-                // Check if still attached
-                if segment.attachment_count() > 0 {
-                    return Err(ShmError::StillAttached);
-                }
+        let i = self
+            .segments
+            .iter()
+            .position(|s| s.id == id && !s.deleted)
+            .ok_or(ShmError::NotFound)?;
+        let segment = &mut self.segments[i];
+
+        // Only owner can delete
+        if segment.owner != pid {
+            return Err(ShmError::PermissionDenied);
+        }
 
-                self.segments.remove(i);
-                crate::serial_println!("[SHM] Deleted segment {}", id);
-                return Ok(());
-            }
+        segment.deleted = true;
+        if segment.attachment_count() == 0 {
+            self.remove(i);
+        } else {
+            crate::serial_println!("[SHM] Segment {} deleted once detached", id);
         }
-        Err(ShmError::NotFound)
+        Ok(())
+    }
+
+    /// Drop segment `i` and free its frames, nothing may map them anymore
+    fn remove(&mut self, i: usize) {
+        let mut segment = self.segments.remove(i);
+        segment.release_frames();
+        crate::serial_println!("[SHM] Deleted segment {}", segment.id);
+    }
+
+    /// Check if a process is attached to a segment with at least `wanted`
+    pub fn check_permission(&self, id: ShmId, pid: Pid, wanted: ShmPermissions) -> bool {
+        self.segments
+            .iter()
+            .find(|s| s.id == id)
+            .is_some_and(|s| s.check_permission(pid, wanted))
+    }
+
+    /// Get the frame backing `offset` of a segment
+    pub fn frame_at(&mut self, id: ShmId, offset: u64) -> Result<PhysFrame, ShmError> {
+        self.segments
+            .iter_mut()
+            .find(|s| s.id == id)
+            .ok_or(ShmError::NotFound)?
+            .frame_at(offset)
+    }
+
+    /// Get the size, page size and preferred address of a segment
+    pub fn layout(&self, id: ShmId) -> Option<(u64, u64, VirtAddr)> {
+        self.segments
+            .iter()
+            .find(|s| s.id == id)
+            .map(|s| (s.size as u64, s.page_size, s.address))
     }
 
     /// Get segment info
@@ -205,12 +391,23 @@ pub enum ShmError {
     AlreadyAttached,
     NotAttached,
     PermissionDenied,
-    StillAttached,
+    OutOfMemory,
+    /// The segment could not be mapped
+    Map(VmaError),
+}
+
+impl From<VmaError> for ShmError {
+    fn from(err: VmaError) -> Self {
+        ShmError::Map(err)
+    }
 }
 
 /// Maximum shared memory size (16MB)
 pub const MAX_SHM_SIZE: usize = 16 * 1024 * 1024;
 
+/// Size of the user window segments are placed in (64 GiB)
+pub const SHM_WINDOW_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+
 /// Global shared memory manager
 pub static SHM_MANAGER: Mutex<ShmManager> = Mutex::new(ShmManager::new());
 
@@ -220,8 +417,18 @@ pub fn init() {
 }
 
 /// Create shared memory segment
-pub fn create(owner: Pid, size: usize) -> Result<ShmId, ShmError> {
-    SHM_MANAGER.lock().create(owner, size)
+pub fn create(owner: Pid, size: usize, shared: ShmPermissions) -> Result<ShmId, ShmError> {
+    SHM_MANAGER.lock().create(owner, size, shared)
+}
+
+/// Create shared memory segment backed by 2 MiB pages
+pub fn create_huge(owner: Pid, size: usize, shared: ShmPermissions) -> Result<ShmId, ShmError> {
+    SHM_MANAGER.lock().create_huge(owner, size, shared)
+}
+
+/// Get the size, page size and preferred address of a segment
+pub fn layout(id: ShmId) -> Option<(u64, u64, VirtAddr)> {
+    SHM_MANAGER.lock().layout(id)
 }
 
 /// Attach to shared memory
@@ -229,12 +436,81 @@ pub fn attach(id: ShmId, pid: Pid, perms: ShmPermissions) -> Result<VirtAddr, Sh
     SHM_MANAGER.lock().attach(id, pid, perms)
 }
 
+/// Attach a forked child to shared memory its parent is attached to
+pub fn duplicate(id: ShmId, parent: Pid, child: Pid) -> Result<(), ShmError> {
+    SHM_MANAGER.lock().duplicate(id, parent, child)
+}
+
 /// Detach from shared memory
 pub fn detach(id: ShmId, pid: Pid) -> Result<(), ShmError> {
     SHM_MANAGER.lock().detach(id, pid)
 }
 
+/// Check if a process is attached to shared memory with at least `wanted`
+pub fn check_permission(id: ShmId, pid: Pid, wanted: ShmPermissions) -> bool {
+    SHM_MANAGER.lock().check_permission(id, pid, wanted)
+}
+
+/// Get the frame backing `offset` of a segment
+pub fn frame_at(id: ShmId, offset: u64) -> Result<PhysFrame, ShmError> {
+    SHM_MANAGER.lock().frame_at(id, offset)
+}
+
 /// Delete shared memory segment
 pub fn delete(id: ShmId, pid: Pid) -> Result<(), ShmError> {
     SHM_MANAGER.lock().delete(id, pid)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ShmError, ShmManager, ShmPermissions};
+    use crate::{serial_print, serial_println};
+
+    #[test_case]
+    fn test_delete_waits_for_last_detach() {
+        serial_print!("test_delete_waits_for_last_detach... ");
+        let mut shm = ShmManager::new();
+        let id = shm.create(1, 4096, ShmPermissions::READ_ONLY).unwrap();
+        shm.attach(id, 1, ShmPermissions::READ_WRITE).unwrap();
+        shm.frame_at(id, 0).unwrap();
+
+        // Still mapped, so the frame must stay
+        shm.delete(id, 1).unwrap();
+        assert!(shm.get_info(id).is_some());
+        assert!(shm.frame_at(id, 0).is_ok());
+        assert_eq!(
+            shm.attach(id, 2, ShmPermissions::READ_ONLY),
+            Err(ShmError::NotFound)
+        );
+
+        shm.detach(id, 1).unwrap();
+        assert!(shm.get_info(id).is_none());
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_attach_checks_permissions() {
+        serial_print!("test_attach_checks_permissions... ");
+        let mut shm = ShmManager::new();
+        let id = shm.create(1, 4096, ShmPermissions::READ_ONLY).unwrap();
+        assert_eq!(
+            shm.attach(id, 2, ShmPermissions::READ_WRITE),
+            Err(ShmError::PermissionDenied)
+        );
+        shm.attach(id, 2, ShmPermissions::READ_ONLY).unwrap();
+        assert!(shm.check_permission(id, 2, ShmPermissions::READ_ONLY));
+        assert!(!shm.check_permission(id, 2, ShmPermissions::READ_WRITE));
+
+        // The owner gets any access, forked children what the parent has
+        shm.attach(id, 1, ShmPermissions::ALL).unwrap();
+        shm.duplicate(id, 2, 3).unwrap();
+        assert!(!shm.check_permission(id, 3, ShmPermissions::READ_WRITE));
+
+        for pid in 1..=3 {
+            shm.detach(id, pid).unwrap();
+        }
+        shm.delete(id, 1).unwrap();
+        assert!(shm.get_info(id).is_none());
+        serial_println!("[ok]");
+    }
+}
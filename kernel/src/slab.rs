@@ -0,0 +1,206 @@
+use crate::allocator::LinkedListAllocator;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr::null_mut;
+use spin::Mutex;
+
+/// Size of a slab page carved into objects
+pub const SLAB_PAGE_SIZE: usize = 4096;
+
+/// Object sizes served by the slab caches
+pub const SLAB_SIZES: [usize; 8] = [32, 64, 128, 256, 512, 1024, 2048, 4096];
+
+/// Free object link stored inside every free slot
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Usage statistics of one slab cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub pages: usize,
+    pub objects_in_use: usize,
+    pub objects_free: usize,
+    pub total_allocs: u64,
+    pub total_frees: u64,
+}
+
+/// Object cache for one size class
+pub struct SlabCache {
+    free_list: *mut FreeObject,
+    stats: SlabStats,
+}
+
+// Free objects are only reachable through the cache lock
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    const fn new(name: &'static str, object_size: usize) -> Self {
+        Self {
+            free_list: null_mut(),
+            stats: SlabStats {
+                name,
+                object_size,
+                pages: 0,
+                objects_in_use: 0,
+                objects_free: 0,
+                total_allocs: 0,
+                total_frees: 0,
+            },
+        }
+    }
+
+    /// Carve a fresh page from the backing allocator into free objects
+    unsafe fn grow(&mut self, backing: &LinkedListAllocator) -> bool {
+        let layout = Layout::from_size_align_unchecked(SLAB_PAGE_SIZE, SLAB_PAGE_SIZE);
+        let page = backing.alloc(layout);
+        if page.is_null() {
+            return false;
+        }
+
+        let object_size = self.stats.object_size;
+        for offset in (0..SLAB_PAGE_SIZE).step_by(object_size).rev() {
+            let object = page.add(offset) as *mut FreeObject;
+            (*object).next = self.free_list;
+            self.free_list = object;
+        }
+
+        self.stats.pages += 1;
+        self.stats.objects_free += SLAB_PAGE_SIZE / object_size;
+        true
+    }
+
+    unsafe fn alloc(&mut self, backing: &LinkedListAllocator) -> *mut u8 {
+        if self.free_list.is_null() && !self.grow(backing) {
+            return null_mut();
+        }
+
+        let object = self.free_list;
+        self.free_list = (*object).next;
+
+        self.stats.objects_free -= 1;
+        self.stats.objects_in_use += 1;
+        self.stats.total_allocs += 1;
+        object as *mut u8
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        let object = ptr as *mut FreeObject;
+        (*object).next = self.free_list;
+        self.free_list = object;
+
+        self.stats.objects_in_use -= 1;
+        self.stats.objects_free += 1;
+        self.stats.total_frees += 1;
+    }
+}
+
+/// Slab allocator in front of the linked list heap
+///
+/// Layouts up to 4 KiB are rounded up to a power-of-two size class and
+/// served from per-size caches. Because slab pages are page aligned, every
+/// object is aligned to its size class. Larger layouts, and layouts whose
+/// alignment exceeds a page, go straight to the linked list allocator.
+pub struct SlabAllocator {
+    caches: [Mutex<SlabCache>; SLAB_SIZES.len()],
+    backing: LinkedListAllocator,
+}
+
+impl SlabAllocator {
+    pub const fn new(backing: LinkedListAllocator) -> Self {
+        Self {
+            caches: [
+                Mutex::new(SlabCache::new("kmalloc-32", 32)),
+                Mutex::new(SlabCache::new("kmalloc-64", 64)),
+                Mutex::new(SlabCache::new("kmalloc-128", 128)),
+                Mutex::new(SlabCache::new("kmalloc-256", 256)),
+                Mutex::new(SlabCache::new("kmalloc-512", 512)),
+                Mutex::new(SlabCache::new("kmalloc-1k", 1024)),
+                Mutex::new(SlabCache::new("kmalloc-2k", 2048)),
+                Mutex::new(SlabCache::new("kmalloc-4k", 4096)),
+            ],
+            backing,
+        }
+    }
+
+    /// Initialize the backing heap
+    ///
+    /// # Safety
+    /// This function must be called only once and the heap bounds must be valid
+    pub unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        self.backing.init(heap_start, heap_size);
+    }
+
+    /// Pick the cache serving a layout, if any
+    fn cache_index(layout: &Layout) -> Option<usize> {
+        let size = core::cmp::max(layout.size(), layout.align());
+        SLAB_SIZES.iter().position(|&s| s >= size)
+    }
+
+    /// Get statistics for every cache
+    pub fn stats(&self) -> [SlabStats; SLAB_SIZES.len()] {
+        core::array::from_fn(|i| self.caches[i].lock().stats)
+    }
+}
+
+unsafe impl GlobalAlloc for SlabAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match Self::cache_index(&layout) {
+            Some(index) => self.caches[index].lock().alloc(&self.backing),
+            None => self.backing.alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match Self::cache_index(&layout) {
+            Some(index) => self.caches[index].lock().dealloc(ptr),
+            None => self.backing.dealloc(ptr, layout),
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let old_index = Self::cache_index(&layout);
+        let new_index = Self::cache_index(&new_layout);
+
+        match (old_index, new_index) {
+            // Still fits the same object
+            (Some(old), Some(new)) if old == new => ptr,
+            // Large blocks can often be resized in place
+            (None, None) => self.backing.realloc(ptr, layout, new_size),
+            _ => {
+                let new_ptr = self.alloc(new_layout);
+                if !new_ptr.is_null() {
+                    core::ptr::copy_nonoverlapping(
+                        ptr,
+                        new_ptr,
+                        core::cmp::min(layout.size(), new_size),
+                    );
+                    self.dealloc(ptr, layout);
+                }
+                new_ptr
+            }
+        }
+    }
+}
+
+/// Get statistics for every slab cache
+pub fn stats() -> [SlabStats; SLAB_SIZES.len()] {
+    crate::allocator::ALLOCATOR.stats()
+}
+
+/// Log slab cache usage to serial
+pub fn dump_stats() {
+    crate::serial_println!("[SLAB] cache          size  pages  in-use   free");
+    for cache in stats().iter() {
+        crate::serial_println!(
+            "[SLAB] {:<12} {:>6} {:>6} {:>7} {:>6}",
+            cache.name,
+            cache.object_size,
+            cache.pages,
+            cache.objects_in_use,
+            cache.objects_free
+        );
+    }
+}
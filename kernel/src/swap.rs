@@ -0,0 +1,508 @@
+use crate::address_space::{AddressSpace, PTE_SHARED};
+use crate::block::{AtaBus, AtaPio, BlockDevice, SECTOR_SIZE};
+use crate::fault::FaultError;
+use crate::memory::{phys_to_virt, physical_memory_offset};
+use crate::page::PageFlags;
+use crate::process::{Pid, PROCESS_MANAGER};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::{
+    instructions::tlb,
+    structures::paging::{page_table::PageTableEntry, Page, PageTableFlags, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+/// Software PTE bit marking a non-present entry that holds a swap slot
+pub const PTE_SWAP: PageTableFlags = PageTableFlags::BIT_11;
+
+/// Pages the fault path tries to evict before giving up on swap
+pub const SWAP_CLUSTER: usize = 32;
+
+/// Size of one swap slot
+pub const PAGE_SIZE: usize = 4096;
+
+/// Sectors per swap slot
+const SECTORS_PER_PAGE: u64 = (PAGE_SIZE / SECTOR_SIZE) as u64;
+
+/// Signature at the end of the first page, as written by Linux `mkswap`
+const SWAP_MAGIC: &[u8] = b"SWAPSPACE2";
+
+/// Offset of the `last_page` field of the swap header
+const LAST_PAGE_OFFSET: usize = 1028;
+
+/// Most mappings of one swapped page that can be counted
+const MAX_SWAP_COUNT: u8 = u8::MAX;
+
+/// Location of a swapped out page, stored in its non-present PTE
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwapEntry(u64);
+
+impl SwapEntry {
+    /// Decode a swap entry from a page table entry
+    pub fn from_pte(entry: &PageTableEntry) -> Option<Self> {
+        let flags = entry.flags();
+        if flags.contains(PageTableFlags::PRESENT) || !flags.contains(PTE_SWAP) {
+            return None;
+        }
+        Some(SwapEntry(entry.addr().as_u64() / PAGE_SIZE as u64))
+    }
+
+    /// Store this swap entry in a page table entry
+    ///
+    /// Slot numbers start at 1, so the entry never looks unused.
+    fn write_to(self, entry: &mut PageTableEntry) {
+        entry.set_addr(PhysAddr::new(self.0 * PAGE_SIZE as u64), PTE_SWAP);
+    }
+
+    /// Page slot on the swap device
+    pub fn slot(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Swap errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwapError {
+    /// A swap area is already active
+    Busy,
+    /// The device has no swap signature
+    NoSignature,
+    /// The device has no room for a single page
+    TooSmall,
+    /// The device failed a transfer
+    Io,
+    /// The stored page could not be decoded
+    Corrupt,
+    OutOfMemory,
+}
+
+/// Where swapped out pages are kept
+///
+/// Slots are numbered from 1; slot 0 is never used.
+pub trait SwapBackend: Send {
+    /// Number of slots, including slot 0
+    fn slots(&self) -> usize;
+
+    /// Save a page in a slot
+    fn store(&mut self, slot: u64, page: &[u8]) -> Result<(), SwapError>;
+
+    /// Read a page back from a slot
+    fn load(&mut self, slot: u64, page: &mut [u8]) -> Result<(), SwapError>;
+
+    /// Forget the contents of a slot that is no longer referenced
+    fn discard(&mut self, _slot: u64) {}
+
+    /// Log backend specific statistics
+    fn log_stats(&self) {}
+}
+
+/// Swap on a block device carrying a swap signature
+pub struct DiskSwap {
+    device: Box<dyn BlockDevice>,
+    pages: usize,
+}
+
+impl DiskSwap {
+    /// Validate the swap header of `device`
+    pub fn new(mut device: Box<dyn BlockDevice>) -> Result<Self, SwapError> {
+        let mut header = [0u8; PAGE_SIZE];
+        device.read(0, &mut header).map_err(|_| SwapError::Io)?;
+        if &header[PAGE_SIZE - SWAP_MAGIC.len()..] != SWAP_MAGIC {
+            return Err(SwapError::NoSignature);
+        }
+
+        let mut last_page = [0u8; 4];
+        last_page.copy_from_slice(&header[LAST_PAGE_OFFSET..LAST_PAGE_OFFSET + 4]);
+        let device_pages = device.sector_count() / SECTORS_PER_PAGE;
+        let pages = core::cmp::min(u32::from_le_bytes(last_page) as u64 + 1, device_pages);
+        if pages < 2 {
+            return Err(SwapError::TooSmall);
+        }
+
+        Ok(Self {
+            device,
+            pages: pages as usize,
+        })
+    }
+}
+
+impl SwapBackend for DiskSwap {
+    /// Page 0 holds the header, matching the unused slot 0
+    fn slots(&self) -> usize {
+        self.pages
+    }
+
+    fn store(&mut self, slot: u64, page: &[u8]) -> Result<(), SwapError> {
+        self.device
+            .write(slot * SECTORS_PER_PAGE, page)
+            .map_err(|_| SwapError::Io)
+    }
+
+    fn load(&mut self, slot: u64, page: &mut [u8]) -> Result<(), SwapError> {
+        self.device
+            .read(slot * SECTORS_PER_PAGE, page)
+            .map_err(|_| SwapError::Io)
+    }
+}
+
+/// The active swap backend and its slot use counts
+struct SwapArea {
+    backend: Box<dyn SwapBackend>,
+    /// Number of PTEs referring to each slot
+    counts: Vec<u8>,
+    free: usize,
+    /// Where the next slot search starts
+    next: usize,
+}
+
+impl SwapArea {
+    fn new(backend: Box<dyn SwapBackend>) -> Result<Self, SwapError> {
+        let slots = backend.slots();
+        if slots < 2 {
+            return Err(SwapError::TooSmall);
+        }
+
+        let mut counts = Vec::new();
+        counts
+            .try_reserve_exact(slots)
+            .map_err(|_| SwapError::OutOfMemory)?;
+        counts.resize(slots, 0);
+        // Slot 0 would encode as an unused PTE
+        counts[0] = MAX_SWAP_COUNT;
+
+        Ok(Self {
+            backend,
+            counts,
+            free: slots - 1,
+            next: 1,
+        })
+    }
+
+    fn allocate(&mut self) -> Option<SwapEntry> {
+        if self.free == 0 {
+            return None;
+        }
+        let len = self.counts.len();
+        let slot = (0..len)
+            .map(|i| (self.next + i) % len)
+            .find(|&slot| self.counts[slot] == 0)?;
+        self.counts[slot] = 1;
+        self.free -= 1;
+        self.next = slot + 1;
+        Some(SwapEntry(slot as u64))
+    }
+
+    fn duplicate(&mut self, entry: SwapEntry) -> bool {
+        match self.counts.get_mut(entry.0 as usize) {
+            Some(count) if *count > 0 && *count < MAX_SWAP_COUNT => {
+                *count += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn release(&mut self, entry: SwapEntry) {
+        if let Some(count) = self.counts.get_mut(entry.0 as usize) {
+            if *count > 0 && entry.0 != 0 {
+                *count -= 1;
+                if *count == 0 {
+                    self.free += 1;
+                    self.backend.discard(entry.0);
+                }
+            }
+        }
+    }
+
+    fn write_page(&mut self, entry: SwapEntry, frame: PhysFrame) -> Result<(), SwapError> {
+        self.backend.store(entry.0, unsafe { frame_bytes(frame) })
+    }
+
+    fn read_page(&mut self, entry: SwapEntry, frame: PhysFrame) -> Result<(), SwapError> {
+        self.backend.load(entry.0, unsafe { frame_bytes(frame) })
+    }
+}
+
+/// The contents of a frame through the physical memory mapping
+unsafe fn frame_bytes(frame: PhysFrame) -> &'static mut [u8] {
+    let virt = phys_to_virt(physical_memory_offset(), frame.start_address());
+    core::slice::from_raw_parts_mut(virt.as_mut_ptr::<u8>(), PAGE_SIZE)
+}
+
+/// Swap space and the clock page replacement scanner
+///
+/// The scanner sweeps the private 4 KiB pages of all processes like a clock
+/// hand. A page that was accessed since the last sweep loses its accessed
+/// bit and gets a second chance, otherwise it is written out and its PTE
+/// replaced by a swap entry. Faults on swap entries read the page back in.
+pub struct SwapManager {
+    area: Option<SwapArea>,
+    /// Position of the clock hand
+    hand: (Pid, VirtAddr),
+    swapped_out: u64,
+    swapped_in: u64,
+}
+
+impl SwapManager {
+    pub const fn new() -> Self {
+        Self {
+            area: None,
+            hand: (0, VirtAddr::zero()),
+            swapped_out: 0,
+            swapped_in: 0,
+        }
+    }
+
+    /// Start swapping to `backend`, returning the number of usable slots
+    pub fn swap_on(&mut self, backend: Box<dyn SwapBackend>) -> Result<usize, SwapError> {
+        if self.area.is_some() {
+            return Err(SwapError::Busy);
+        }
+        let area = SwapArea::new(backend)?;
+        let slots = area.free;
+        self.area = Some(area);
+        Ok(slots)
+    }
+
+    /// Number of free and total swap slots
+    pub fn slots(&self) -> (usize, usize) {
+        self.area
+            .as_ref()
+            .map_or((0, 0), |area| (area.free, area.counts.len() - 1))
+    }
+
+    /// Number of pages written out and read back so far
+    pub fn stats(&self) -> (u64, u64) {
+        (self.swapped_out, self.swapped_in)
+    }
+
+    /// Evict up to `target` pages of `space`, starting at `from`
+    ///
+    /// Returns the number evicted and where the sweep stopped, or `None` if
+    /// it reached the end of the address space.
+    fn sweep(
+        &mut self,
+        space: &mut AddressSpace,
+        from: VirtAddr,
+        target: usize,
+    ) -> (usize, Option<VirtAddr>) {
+        let Some(area) = self.area.as_mut() else {
+            return (0, None);
+        };
+        let is_active = space.is_active();
+        let mut evicted = 0;
+        let mut stopped = None;
+
+        space.for_each_page(|page, entry| {
+            let addr = page.start_address();
+            if stopped.is_some() || addr < from {
+                return;
+            }
+
+            let flags = entry.flags();
+            let frame = PhysFrame::containing_address(entry.addr());
+            // Shared memory, pages shared after fork and pages under I/O
+            // stay resident
+            if flags.contains(PTE_SHARED)
+                || crate::page::page_count(frame) > 1
+                || crate::page::descriptor(frame)
+                    .is_some_and(|page| page.flags().contains(PageFlags::LOCKED))
+            {
+                return;
+            }
+
+            if flags.contains(PageTableFlags::ACCESSED) {
+                entry.set_flags(flags - PageTableFlags::ACCESSED);
+                if is_active {
+                    tlb::flush(addr);
+                }
+                return;
+            }
+
+            let Some(swap) = area.allocate() else {
+                stopped = Some(addr);
+                return;
+            };
+            crate::page::set_flags(frame, PageFlags::LOCKED);
+            let written = area.write_page(swap, frame);
+            crate::page::clear_flags(frame, PageFlags::LOCKED);
+            if written.is_err() {
+                area.release(swap);
+                return;
+            }
+
+            swap.write_to(entry);
+            if is_active {
+                tlb::flush(addr);
+            }
+            crate::page::put_page(frame);
+            evicted += 1;
+            if evicted == target {
+                stopped = Some(addr + PAGE_SIZE as u64);
+            }
+        });
+
+        space.forget_resident((evicted * PAGE_SIZE) as u64);
+        self.swapped_out += evicted as u64;
+        (evicted, stopped)
+    }
+
+    /// Read a swapped out page back into a fresh frame mapped at `page`
+    pub fn swap_in(
+        &mut self,
+        space: &mut AddressSpace,
+        page: Page,
+        entry: SwapEntry,
+        flags: PageTableFlags,
+    ) -> Result<(), FaultError> {
+        let area = self.area.as_mut().ok_or(FaultError::BackingUnavailable)?;
+        let frame = crate::buddy::allocate_frames(0).ok_or(FaultError::OutOfMemory)?;
+        if area.read_page(entry, frame).is_err() {
+            crate::buddy::free_frames(frame, 0);
+            return Err(FaultError::BackingUnavailable);
+        }
+
+        space.map_swapped_page(page, frame, flags);
+        area.release(entry);
+        self.swapped_in += 1;
+        Ok(())
+    }
+}
+
+impl Default for SwapManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Global swap manager
+pub static SWAP_MANAGER: Mutex<SwapManager> = Mutex::new(SwapManager::new());
+
+/// Start swapping to `backend`, returning the number of usable slots
+pub fn swap_on(backend: Box<dyn SwapBackend>) -> Result<usize, SwapError> {
+    SWAP_MANAGER.lock().swap_on(backend)
+}
+
+/// Record one more PTE referring to a swap slot
+///
+/// Returns false if the slot cannot take another reference.
+pub fn duplicate(entry: SwapEntry) -> bool {
+    SWAP_MANAGER
+        .lock()
+        .area
+        .as_mut()
+        .is_some_and(|area| area.duplicate(entry))
+}
+
+/// Drop one PTE referring to a swap slot, freeing it with the last one
+pub fn release(entry: SwapEntry) {
+    if let Some(area) = SWAP_MANAGER.lock().area.as_mut() {
+        area.release(entry);
+    }
+}
+
+/// Read a swapped out page of the current process back in
+pub fn swap_in(
+    space: &mut AddressSpace,
+    page: Page,
+    entry: SwapEntry,
+    flags: PageTableFlags,
+) -> Result<(), FaultError> {
+    SWAP_MANAGER.lock().swap_in(space, page, entry, flags)
+}
+
+/// Move the clock hand over all processes, evicting up to `target` pages
+///
+/// Two full turns are made at most: the first may only clear accessed bits.
+/// Returns the number of pages evicted.
+pub fn reclaim(target: usize) -> usize {
+    let mut pm = PROCESS_MANAGER.lock();
+    let mut swap = SWAP_MANAGER.lock();
+    if swap.area.is_none() {
+        return 0;
+    }
+
+    let mut evicted = 0;
+    let (mut hand_pid, mut hand_addr) = swap.hand;
+    swap.hand = (0, VirtAddr::zero());
+    'turns: for _ in 0..3 {
+        for process in pm.processes_mut().filter(|p| p.pid >= hand_pid) {
+            let Some(space) = process.address_space.as_mut() else {
+                continue;
+            };
+            let from = if process.pid == hand_pid {
+                hand_addr
+            } else {
+                VirtAddr::zero()
+            };
+
+            let (count, stopped) = swap.sweep(space, from, target - evicted);
+            evicted += count;
+            if let Some(addr) = stopped {
+                swap.hand = (process.pid, addr);
+                break 'turns;
+            }
+        }
+        hand_pid = 0;
+        hand_addr = VirtAddr::zero();
+    }
+
+    if evicted > 0 {
+        crate::serial_println!("[SWAP] Evicted {} pages", evicted);
+        if let Some(area) = swap.area.as_ref() {
+            area.backend.log_stats();
+        }
+    }
+    evicted
+}
+
+/// Look for a swap device on the ATA buses, falling back to zram
+///
+/// The boot disk is the primary master, so any other disk carrying a swap
+/// signature is used, e.g. one prepared with `mkswap`.
+pub fn init() {
+    let candidates = [
+        (AtaBus::Primary, true),
+        (AtaBus::Secondary, false),
+        (AtaBus::Secondary, true),
+    ];
+
+    for (bus, slave) in candidates {
+        let Some(disk) = AtaPio::probe(bus, slave) else {
+            continue;
+        };
+        match DiskSwap::new(Box::new(disk)).and_then(|disk| swap_on(Box::new(disk))) {
+            Ok(slots) => {
+                crate::serial_println!(
+                    "[SWAP] Swapping to {:?} {} ({} KB)",
+                    bus,
+                    if slave { "slave" } else { "master" },
+                    slots * PAGE_SIZE / 1024
+                );
+                return;
+            }
+            Err(err) => {
+                crate::serial_println!(
+                    "[SWAP] Skipping {:?} {}: {:?}",
+                    bus,
+                    if slave { "slave" } else { "master" },
+                    err
+                );
+            }
+        }
+    }
+
+    crate::serial_println!("[SWAP] No swap device found, using compressed RAM");
+    match crate::zram::Zram::new().and_then(|zram| swap_on(Box::new(zram))) {
+        Ok(slots) => {
+            crate::serial_println!(
+                "[SWAP] Swapping to zram ({} KB uncompressed)",
+                slots * PAGE_SIZE / 1024
+            );
+        }
+        Err(err) => {
+            crate::serial_println!("[SWAP] zram unavailable: {:?}", err);
+        }
+    }
+}
@@ -1,5 +1,16 @@
-use crate::process;
-use x86_64::VirtAddr;
+use crate::context::CpuContext;
+use crate::ipc::IpcError;
+use crate::loader::ExecError;
+use crate::process::{self, ProcessError};
+use crate::rlimit::{LimitError, RLimit, Resource};
+use crate::sched::{SchedClass, SchedError, SchedParam};
+use crate::shm::ShmError;
+use crate::uaccess::{read_user, write_user, UserSlice};
+use crate::vma::{VmProtection, VmaError};
+use x86_64::{
+    structures::paging::{PageSize, Size2MiB, Size4KiB},
+    VirtAddr,
+};
 
 /// System call numbers
 #[derive(Debug, Clone, Copy)]
@@ -15,7 +26,23 @@ pub enum SyscallNumber {
     Wait = 61,
     GetPid = 39,
     Mmap = 9,
+    Mprotect = 10,
     Munmap = 11,
+    Brk = 12,
+    SchedYield = 24,
+    Getrlimit = 97,
+    SchedSetscheduler = 144,
+    SchedGetscheduler = 145,
+    Setrlimit = 160,
+    // FractureOS specific calls
+    IpcSend = 400,
+    IpcReceive = 401,
+    MemoryUsage = 402,
+    JitPermission = 403,
+    ShmCreate = 404,
+    ShmAttach = 405,
+    ShmDetach = 406,
+    ShmDelete = 407,
 }
 
 impl SyscallNumber {
@@ -31,14 +58,146 @@ impl SyscallNumber {
             61 => Some(Self::Wait),
             39 => Some(Self::GetPid),
             9 => Some(Self::Mmap),
+            10 => Some(Self::Mprotect),
             11 => Some(Self::Munmap),
+            12 => Some(Self::Brk),
+            24 => Some(Self::SchedYield),
+            97 => Some(Self::Getrlimit),
+            144 => Some(Self::SchedSetscheduler),
+            145 => Some(Self::SchedGetscheduler),
+            160 => Some(Self::Setrlimit),
+            400 => Some(Self::IpcSend),
+            401 => Some(Self::IpcReceive),
+            402 => Some(Self::MemoryUsage),
+            403 => Some(Self::JitPermission),
+            404 => Some(Self::ShmCreate),
+            405 => Some(Self::ShmAttach),
+            406 => Some(Self::ShmDetach),
+            407 => Some(Self::ShmDelete),
             _ => None,
         }
     }
 }
 
+/// Error numbers returned as `-errno`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Errno {
+    EPERM = 1,
+    ESRCH = 3,
+    ENOEXEC = 8,
+    EBADF = 9,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EACCES = 13,
+    EFAULT = 14,
+    EBUSY = 16,
+    EEXIST = 17,
+    ENODEV = 19,
+    EINVAL = 22,
+    EMSGSIZE = 90,
+}
+
+impl Errno {
+    /// Encode as a syscall return value
+    pub fn as_return(self) -> u64 {
+        (self as u64).wrapping_neg()
+    }
+}
+
+impl From<VmaError> for Errno {
+    fn from(err: VmaError) -> Self {
+        match err {
+            VmaError::InvalidRange => Errno::EINVAL,
+            VmaError::Overlap => Errno::EEXIST,
+            VmaError::NotMapped => Errno::ENOMEM,
+            VmaError::NoSpace => Errno::ENOMEM,
+            VmaError::WriteExec => Errno::EACCES,
+            VmaError::PermissionDenied => Errno::EACCES,
+            VmaError::OutOfMemory => Errno::ENOMEM,
+        }
+    }
+}
+
+impl From<ShmError> for Errno {
+    fn from(err: ShmError) -> Self {
+        match err {
+            ShmError::NotFound => Errno::EINVAL,
+            ShmError::InvalidSize => Errno::EINVAL,
+            ShmError::AlreadyAttached => Errno::EEXIST,
+            ShmError::NotAttached => Errno::EINVAL,
+            ShmError::PermissionDenied => Errno::EACCES,
+            ShmError::OutOfMemory => Errno::ENOMEM,
+            ShmError::Map(err) => err.into(),
+        }
+    }
+}
+
+impl From<ExecError> for Errno {
+    fn from(err: ExecError) -> Self {
+        match err {
+            ExecError::BadImage(_) => Errno::ENOEXEC,
+            ExecError::WriteExec => Errno::EACCES,
+            ExecError::LimitExceeded => Errno::ENOMEM,
+            ExecError::OutOfMemory => Errno::ENOMEM,
+            ExecError::NotFound => Errno::ESRCH,
+        }
+    }
+}
+
+impl From<IpcError> for Errno {
+    fn from(err: IpcError) -> Self {
+        match err {
+            IpcError::QueueFull => Errno::EAGAIN,
+            IpcError::ProcessNotFound => Errno::ESRCH,
+            IpcError::MessageTooLarge => Errno::EMSGSIZE,
+            IpcError::InvalidMessage => Errno::EINVAL,
+            IpcError::OutOfMemory => Errno::ENOMEM,
+        }
+    }
+}
+
+impl From<ProcessError> for Errno {
+    fn from(err: ProcessError) -> Self {
+        match err {
+            ProcessError::OutOfMemory => Errno::ENOMEM,
+            ProcessError::NotFound => Errno::ESRCH,
+        }
+    }
+}
+
+impl From<SchedError> for Errno {
+    fn from(err: SchedError) -> Self {
+        match err {
+            SchedError::InvalidPolicy => Errno::EINVAL,
+            SchedError::InvalidParameters => Errno::EINVAL,
+            SchedError::Overcommitted => Errno::EBUSY,
+            SchedError::ProcessNotFound => Errno::ESRCH,
+        }
+    }
+}
+
+/// mmap flags
+pub const MAP_SHARED: u64 = 0x01;
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_FIXED: u64 = 0x10;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+pub const MAP_HUGETLB: u64 = 0x40000;
+
+/// shm_create flags, besides the `PROT_*` bits other processes may attach with
+pub const SHM_HUGETLB: u64 = 0x800;
+
+/// Largest program image exec accepts
+pub const MAX_EXEC_IMAGE: usize = 16 * 1024 * 1024;
+
 /// System call handler
-pub fn syscall_handler(syscall_number: u64, arg1: u64, arg2: u64, arg3: u64) -> u64 {
+pub extern "C" fn syscall_handler(
+    syscall_number: u64,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+) -> u64 {
     let syscall = match SyscallNumber::from_u64(syscall_number) {
         Some(s) => s,
         None => {
@@ -48,13 +207,29 @@ pub fn syscall_handler(syscall_number: u64, arg1: u64, arg2: u64, arg3: u64) ->
     };
 
     match syscall {
-        SyscallNumber::Write => sys_write(arg1 as i32, arg2 as *const u8, arg3 as usize),
-        SyscallNumber::Read => sys_read(arg1 as i32, arg2 as *mut u8, arg3 as usize),
+        SyscallNumber::Write => errno_result(sys_write(arg1 as i32, arg2, arg3 as usize)),
+        SyscallNumber::Read => sys_read(arg1 as i32, arg2, arg3 as usize),
         SyscallNumber::Exit => sys_exit(arg1 as i32),
         SyscallNumber::GetPid => sys_getpid(),
-        SyscallNumber::Fork => sys_fork(),
-        SyscallNumber::Mmap => sys_send(arg1, arg2 as *const u8, arg3 as usize),
-        SyscallNumber::Munmap => sys_receive(arg1 as *mut u8, arg2 as usize),
+        SyscallNumber::Fork => errno_result(sys_fork()),
+        SyscallNumber::Exec => errno_result(sys_exec(arg1, arg2 as usize)),
+        SyscallNumber::Mmap => errno_result(sys_mmap(arg1, arg2, arg3, arg4)),
+        SyscallNumber::Mprotect => errno_result(sys_mprotect(arg1, arg2, arg3)),
+        SyscallNumber::Munmap => errno_result(sys_munmap(arg1, arg2)),
+        SyscallNumber::Brk => errno_result(sys_brk(arg1)),
+        SyscallNumber::SchedYield => sys_sched_yield(),
+        SyscallNumber::IpcSend => errno_result(sys_send(arg1, arg2, arg3 as usize)),
+        SyscallNumber::IpcReceive => errno_result(sys_receive(arg1, arg2 as usize)),
+        SyscallNumber::Getrlimit => errno_result(sys_getrlimit(arg1, arg2)),
+        SyscallNumber::Setrlimit => errno_result(sys_setrlimit(arg1, arg2)),
+        SyscallNumber::SchedSetscheduler => errno_result(sys_sched_setscheduler(arg1, arg2, arg3)),
+        SyscallNumber::SchedGetscheduler => errno_result(sys_sched_getscheduler(arg1, arg2)),
+        SyscallNumber::MemoryUsage => errno_result(sys_memory_usage(arg1, arg2)),
+        SyscallNumber::JitPermission => errno_result(sys_jit_permission(arg1, arg2)),
+        SyscallNumber::ShmCreate => errno_result(sys_shm_create(arg1, arg2)),
+        SyscallNumber::ShmAttach => errno_result(sys_shm_attach(arg1, arg2)),
+        SyscallNumber::ShmDetach => errno_result(sys_shm_detach(arg1)),
+        SyscallNumber::ShmDelete => errno_result(sys_shm_delete(arg1)),
         _ => {
             crate::serial_println!("[SYSCALL] Unimplemented syscall: {:?}", syscall);
             u64::MAX
@@ -63,23 +238,30 @@ pub fn syscall_handler(syscall_number: u64, arg1: u64, arg2: u64, arg3: u64) ->
 }
 
 /// sys_write - Write to file descriptor
-fn sys_write(fd: i32, buf: *const u8, count: usize) -> u64 {
+fn sys_write(fd: i32, buf: u64, count: usize) -> Result<u64, Errno> {
     if fd != 1 && fd != 2 {
-        return u64::MAX; // Only stdout/stderr supported
+        return Err(Errno::EBADF); // Only stdout/stderr supported
     }
 
-    unsafe {
-        let slice = core::slice::from_raw_parts(buf, count);
-        for &byte in slice {
-            crate::serial::SERIAL1.lock().send(byte);
+    let user = UserSlice::readable(buf, count)?;
+    let mut chunk = [0u8; 256];
+    let mut offset = 0;
+    while offset < count {
+        let len = core::cmp::min(chunk.len(), count - offset);
+        user.read_at(offset, &mut chunk[..len])?;
+
+        let mut serial = crate::serial::SERIAL1.lock();
+        for &byte in &chunk[..len] {
+            serial.send(byte);
         }
+        offset += len;
     }
 
-    count as u64
+    Ok(count as u64)
 }
 
 /// sys_read - Read from file descriptor
-fn sys_read(_fd: i32, _buf: *mut u8, _count: usize) -> u64 {
+fn sys_read(_fd: i32, _buf: u64, _count: usize) -> u64 {
     // TODO: Implement read
     0
 }
@@ -89,6 +271,19 @@ fn sys_exit(status: i32) -> u64 {
     if let Some(pid) = process::current_pid() {
         crate::serial_println!("[SYSCALL] Process {} exiting with status {}", pid, status);
         process::exit(pid);
+        leave_syscall();
+        process::run_next();
+    }
+    0
+}
+
+/// sys_sched_yield - Give up the CPU to the next ready process
+fn sys_sched_yield() -> u64 {
+    if process::current_pid().is_some() {
+        // Resumes in user mode as if the call returned 0
+        let context = unsafe { SyscallFrame::current() }.return_context(0);
+        leave_syscall();
+        process::yield_now(context);
     }
     0
 }
@@ -98,53 +293,338 @@ fn sys_getpid() -> u64 {
     process::current_pid().unwrap_or(0)
 }
 
-/// sys_fork - Create a new process
-fn sys_fork() -> u64 {
-    let parent_pid = process::current_pid();
-    let child_pid = process::create_process(parent_pid);
+/// sys_fork - Duplicate the current process
+fn sys_fork() -> Result<u64, Errno> {
+    let parent_pid = process::current_pid().ok_or(Errno::ESRCH)?;
+    // The child returns from the same call with 0
+    let context = unsafe { SyscallFrame::current() }.return_context(0);
+    let child_pid = process::fork(parent_pid, context)?;
 
     // Register child for IPC and signals
     crate::ipc::register_process(child_pid);
     crate::signal::register_process(child_pid);
 
+    crate::serial_println!("[SYSCALL] Fork: parent={}, child={}", parent_pid, child_pid);
+
+    Ok(child_pid)
+}
+
+/// sys_exec - Replace the caller's program with the ELF image at `image_ptr`
+///
+/// Only returns on failure; on success the new program starts running.
+fn sys_exec(image_ptr: u64, image_len: usize) -> Result<u64, Errno> {
+    if image_len > MAX_EXEC_IMAGE {
+        return Err(Errno::ENOMEM);
+    }
+    let pid = process::current_pid().ok_or(Errno::ESRCH)?;
+    let program = {
+        let image = UserSlice::readable(image_ptr, image_len)?.read_to_vec()?;
+        crate::loader::exec(pid, &image)?
+    };
+    leave_syscall();
+    unsafe { crate::context::switch_to(CpuContext::user(program.entry, program.stack_pointer)) }
+}
+
+/// Restore the user GS base for a call that does not return to its caller
+pub(crate) fn leave_syscall() {
+    unsafe { core::arch::asm!("swapgs", options(nomem, nostack, preserves_flags)) };
+}
+
+/// Convert a syscall result to a return value
+fn errno_result(result: Result<u64, Errno>) -> u64 {
+    result.unwrap_or_else(Errno::as_return)
+}
+
+/// Round a length up to whole pages of `page_size` bytes
+fn page_align(len: u64, page_size: u64) -> Result<u64, Errno> {
+    match len.checked_next_multiple_of(page_size) {
+        Some(len) if len > 0 => Ok(len),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+/// Decode `PROT_*` bits
+fn protection(prot: u64) -> Result<VmProtection, Errno> {
+    u32::try_from(prot)
+        .ok()
+        .and_then(VmProtection::from_bits)
+        .ok_or(Errno::EINVAL)
+}
+
+/// Check a user address before it becomes a `VirtAddr`
+fn user_addr(addr: u64) -> Result<VirtAddr, Errno> {
+    if addr >= crate::vma::USER_SPACE_END {
+        return Err(Errno::EINVAL);
+    }
+    Ok(VirtAddr::new(addr))
+}
+
+/// Run `f` on the caller's address space
+fn with_current_space<R>(
+    f: impl FnOnce(&mut crate::address_space::AddressSpace) -> Result<R, VmaError>,
+) -> Result<R, Errno> {
+    let pid = process::current_pid().ok_or(Errno::ESRCH)?;
+    process::with_address_space(pid, f)
+        .ok_or(Errno::ESRCH)?
+        .map_err(Errno::from)
+}
+
+/// sys_mmap - Map anonymous memory into the caller
+fn sys_mmap(addr: u64, len: u64, prot: u64, flags: u64) -> Result<u64, Errno> {
+    let huge_pages = flags & MAP_HUGETLB != 0;
+    let page_size = if huge_pages {
+        Size2MiB::SIZE
+    } else {
+        Size4KiB::SIZE
+    };
+    let len = page_align(len, page_size)?;
+    let protection = protection(prot)?;
+
+    if flags & !(MAP_SHARED | MAP_PRIVATE | MAP_FIXED | MAP_ANONYMOUS | MAP_HUGETLB) != 0
+        || flags & (MAP_SHARED | MAP_PRIVATE) != MAP_PRIVATE
+    {
+        return Err(Errno::EINVAL);
+    }
+    // No file descriptors to map yet
+    if flags & MAP_ANONYMOUS == 0 {
+        return Err(Errno::ENODEV);
+    }
+
+    let fixed = flags & MAP_FIXED != 0;
+    let hint = match addr {
+        0 if fixed => return Err(Errno::EINVAL),
+        0 => None,
+        addr => Some(user_addr(addr)?),
+    };
+
+    let pid = process::current_pid().ok_or(Errno::ESRCH)?;
+    let start = process::with_process(pid, |process| {
+        if !process.can_grow(Resource::AddressSpace, len) {
+            return Err(Errno::ENOMEM);
+        }
+        let space = process.address_space.as_mut().ok_or(Errno::ESRCH)?;
+        Ok(space.map_anonymous(hint, len, protection, huge_pages, fixed)?)
+    })
+    .ok_or(Errno::ESRCH)??;
+    Ok(start.as_u64())
+}
+
+/// sys_munmap - Remove mappings from the caller
+fn sys_munmap(addr: u64, len: u64) -> Result<u64, Errno> {
+    let start = user_addr(addr)?;
+    let len = page_align(len, Size4KiB::SIZE)?;
+    with_current_space(|space| space.unmap(start, len))?;
+    Ok(0)
+}
+
+/// sys_brk - Move the end of the caller's heap, 0 meaning query
+///
+/// Like Linux, returns the unchanged end when the heap cannot move.
+fn sys_brk(addr: u64) -> Result<u64, Errno> {
+    let pid = process::current_pid().ok_or(Errno::ESRCH)?;
+    process::with_process(pid, |process| {
+        let current = process.address_space.as_ref().ok_or(Errno::ESRCH)?.brk();
+        if addr == 0
+            || !process.can_grow(
+                Resource::AddressSpace,
+                addr.saturating_sub(current.as_u64()),
+            )
+        {
+            return Ok(current.as_u64());
+        }
+        let space = process.address_space.as_mut().ok_or(Errno::ESRCH)?;
+        let end = user_addr(addr).ok().and_then(|end| space.set_brk(end).ok());
+        Ok(end.unwrap_or(current).as_u64())
+    })
+    .ok_or(Errno::ESRCH)?
+}
+
+/// sys_mprotect - Change the protection of mapped memory
+fn sys_mprotect(addr: u64, len: u64, prot: u64) -> Result<u64, Errno> {
+    let start = user_addr(addr)?;
+    let len = page_align(len, Size4KiB::SIZE)?;
+    let protection = protection(prot)?;
+    with_current_space(|space| space.protect(start, len, protection))?;
+    Ok(0)
+}
+
+/// sys_getrlimit - Get a resource limit of the caller
+fn sys_getrlimit(resource: u64, limit_ptr: u64) -> Result<u64, Errno> {
+    let resource = Resource::from_u64(resource).ok_or(Errno::EINVAL)?;
+    let pid = process::current_pid().ok_or(Errno::ESRCH)?;
+    let limit = process::with_process(pid, |p| p.limits.get(resource)).ok_or(Errno::ESRCH)?;
+    unsafe { write_user(limit_ptr, &limit)? };
+    Ok(0)
+}
+
+/// sys_setrlimit - Set a resource limit of the caller
+///
+/// Only init may raise a hard limit.
+fn sys_setrlimit(resource: u64, limit_ptr: u64) -> Result<u64, Errno> {
+    let resource = Resource::from_u64(resource).ok_or(Errno::EINVAL)?;
+    let limit: RLimit = unsafe { read_user(limit_ptr)? };
+
+    let pid = process::current_pid().ok_or(Errno::ESRCH)?;
+    process::with_process(pid, |p| {
+        p.limits.set(resource, limit, pid == process::INIT_PID)
+    })
+    .ok_or(Errno::ESRCH)?
+    .map_err(|err| match err {
+        LimitError::Invalid => Errno::EINVAL,
+        LimitError::PermissionDenied => Errno::EPERM,
+    })?;
+    Ok(0)
+}
+
+/// sys_sched_setscheduler - Set the scheduling class of a process, 0 meaning the caller
+///
+/// Only init may move a process into a real-time or deadline class or
+/// change the class of another process; anyone may return to normal.
+fn sys_sched_setscheduler(pid: u64, policy: u64, param_ptr: u64) -> Result<u64, Errno> {
+    let caller = process::current_pid().ok_or(Errno::ESRCH)?;
+    let target = match pid {
+        0 => caller,
+        pid => pid,
+    };
+    let param: SchedParam = unsafe { read_user(param_ptr)? };
+    let class = SchedClass::from_param(policy, &param)?;
+    if caller != process::INIT_PID && (class != SchedClass::Normal || target != caller) {
+        return Err(Errno::EPERM);
+    }
+
+    process::set_scheduler(target, class)?;
+    Ok(0)
+}
+
+/// sys_sched_getscheduler - Get the policy of a process, 0 meaning the caller
+///
+/// Also copies out its parameters unless `param_ptr` is null.
+fn sys_sched_getscheduler(pid: u64, param_ptr: u64) -> Result<u64, Errno> {
+    let pid = match pid {
+        0 => process::current_pid().ok_or(Errno::ESRCH)?,
+        pid => pid,
+    };
+    let class = process::with_process(pid, |p| p.sched.class).ok_or(Errno::ESRCH)?;
+    if param_ptr != 0 {
+        unsafe { write_user(param_ptr, &class.param())? };
+    }
+    Ok(class.policy())
+}
+
+/// sys_memory_usage - Get the memory use of a process, 0 meaning the caller
+fn sys_memory_usage(pid: u64, usage_ptr: u64) -> Result<u64, Errno> {
+    let pid = match pid {
+        0 => process::current_pid().ok_or(Errno::ESRCH)?,
+        pid => pid,
+    };
+    let usage = process::with_process(pid, |p| p.memory_usage()).ok_or(Errno::ESRCH)?;
+    unsafe { write_user(usage_ptr, &usage)? };
+    Ok(0)
+}
+
+/// sys_jit_permission - Allow a process to map writable and executable memory
+///
+/// Only init may grant the permission or change it for another process,
+/// anyone may give up their own. `pid` 0 means the caller.
+fn sys_jit_permission(pid: u64, allow: u64) -> Result<u64, Errno> {
+    let caller = process::current_pid().ok_or(Errno::ESRCH)?;
+    let target = match pid {
+        0 => caller,
+        pid => pid,
+    };
+    let allow = allow != 0;
+    if caller != process::INIT_PID && (allow || target != caller) {
+        return Err(Errno::EPERM);
+    }
+
+    process::with_address_space(target, |space| space.set_allow_write_exec(allow))
+        .ok_or(Errno::ESRCH)?;
     crate::serial_println!(
-        "[SYSCALL] Fork: parent={:?}, child={}",
-        parent_pid,
-        child_pid
+        "[SYSCALL] JIT permission of PID {} {}",
+        target,
+        if allow { "granted" } else { "revoked" }
     );
+    Ok(0)
+}
 
-    child_pid
+/// sys_shm_create - Create a shared memory segment owned by the caller
+///
+/// The low `PROT_*` bits of `flags` are the access other processes may
+/// attach with; `SHM_HUGETLB` backs the segment with 2 MiB pages.
+fn sys_shm_create(size: u64, flags: u64) -> Result<u64, Errno> {
+    let shared = protection(flags & !SHM_HUGETLB)?;
+    let size = usize::try_from(size).map_err(|_| Errno::EINVAL)?;
+    let pid = process::current_pid().ok_or(Errno::ESRCH)?;
+    let id = if flags & SHM_HUGETLB != 0 {
+        crate::shm::create_huge(pid, size, shared.into())?
+    } else {
+        crate::shm::create(pid, size, shared.into())?
+    };
+    Ok(id)
 }
 
-/// sys_send - Send IPC message
-fn sys_send(receiver: u64, data_ptr: *const u8, data_len: usize) -> u64 {
-    if let Some(sender) = process::current_pid() {
-        unsafe {
-            let data = core::slice::from_raw_parts(data_ptr, data_len);
-            match crate::ipc::send_message(sender, receiver, data) {
-                Ok(()) => 0,
-                Err(_) => u64::MAX,
-            }
+/// sys_shm_attach - Map a whole shared memory segment into the caller
+fn sys_shm_attach(id: u64, prot: u64) -> Result<u64, Errno> {
+    let protection = protection(prot)?;
+    let (size, _, _) = crate::shm::layout(id).ok_or(Errno::EINVAL)?;
+    let pid = process::current_pid().ok_or(Errno::ESRCH)?;
+    let start = process::with_process(pid, |process| {
+        if !process.can_grow(Resource::AddressSpace, size) {
+            return Err(Errno::ENOMEM);
         }
-    } else {
-        u64::MAX
+        let space = process.address_space.as_mut().ok_or(Errno::ESRCH)?;
+        Ok(space.map_shared_memory(id, protection)?)
+    })
+    .ok_or(Errno::ESRCH)??;
+    Ok(start.as_u64())
+}
+
+/// sys_shm_detach - Unmap a shared memory segment from the caller
+fn sys_shm_detach(id: u64) -> Result<u64, Errno> {
+    let pid = process::current_pid().ok_or(Errno::ESRCH)?;
+    process::with_address_space(pid, |space| space.unmap_shared_memory(id))
+        .ok_or(Errno::ESRCH)??;
+    Ok(0)
+}
+
+/// sys_shm_delete - Delete a segment the caller owns
+///
+/// It goes away once every process has detached.
+fn sys_shm_delete(id: u64) -> Result<u64, Errno> {
+    let pid = process::current_pid().ok_or(Errno::ESRCH)?;
+    crate::shm::delete(id, pid)?;
+    Ok(0)
+}
+
+/// sys_send - Send IPC message
+fn sys_send(receiver: u64, data_ptr: u64, data_len: usize) -> Result<u64, Errno> {
+    let sender = process::current_pid().ok_or(Errno::ESRCH)?;
+    if data_len > crate::ipc::MAX_MESSAGE_SIZE {
+        return Err(Errno::EMSGSIZE);
     }
+
+    let data = UserSlice::readable(data_ptr, data_len)?.read_to_vec()?;
+    crate::ipc::send_message(sender, receiver, &data)?;
+    Ok(0)
 }
 
 /// sys_receive - Receive IPC message
-fn sys_receive(buffer_ptr: *mut u8, buffer_len: usize) -> u64 {
-    if let Some(pid) = process::current_pid() {
-        if let Some(msg) = crate::ipc::receive_message(pid) {
-            let copy_len = core::cmp::min(msg.data.len(), buffer_len);
-            unsafe {
-                core::ptr::copy_nonoverlapping(msg.data.as_ptr(), buffer_ptr, copy_len);
+fn sys_receive(buffer_ptr: u64, buffer_len: usize) -> Result<u64, Errno> {
+    let pid = process::current_pid().ok_or(Errno::ESRCH)?;
+
+    // Check the buffer before the message leaves the queue
+    let buffer = UserSlice::writable(buffer_ptr, buffer_len)?;
+    match crate::ipc::receive_message(pid) {
+        Some(msg) => {
+            // The buffer can still fault, e.g. on a swap-in that fails
+            let copy_len = core::cmp::min(msg.data.len(), buffer.len());
+            if let Err(err) = buffer.write_at(0, &msg.data[..copy_len]) {
+                crate::ipc::requeue_message(msg);
+                return Err(err);
             }
-            copy_len as u64
-        } else {
-            0
+            Ok(copy_len as u64)
         }
-    } else {
-        u64::MAX
+        None => Ok(0),
     }
 }
 
@@ -164,69 +644,126 @@ pub fn init() {
     use x86_64::registers::model_specific::{LStar, SFMask, Star};
     use x86_64::registers::rflags::RFlags;
 
-    unsafe {
-        // Set SYSCALL entry point
-        LStar::write(VirtAddr::new(syscall_entry as u64));
+    // Set SYSCALL entry point
+    LStar::write(VirtAddr::new(syscall_entry as usize as u64));
 
-        // Set segment selectors for SYSCALL/SYSRET
-        // STAR[47:32] = Kernel CS (0x08)
-        // STAR[63:48] = User CS (0x18)
-        use x86_64::registers::segmentation::SegmentSelector;
-        Star::write(
-            SegmentSelector(0x0008),
-            SegmentSelector(0x0010),
-            SegmentSelector(0x0018),
-            SegmentSelector(0x0020),
-        )
-        .unwrap();
+    // Set segment selectors for SYSCALL/SYSRET
+    // SYSCALL loads the kernel CS and SS, SYSRET the user SS and CS
+    let selectors = crate::gdt::selectors();
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    )
+    .unwrap();
 
-        // Set RFLAGS mask (clear IF and TF on syscall)
-        SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG);
-    }
+    // Set RFLAGS mask: clear IF and TF on syscall, AC so user space
+    // cannot switch SMAP off for the kernel and DF for the C ABI
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::ALIGNMENT_CHECK
+            | RFlags::DIRECTION_FLAG,
+    );
 
     crate::serial_println!("[SYSCALL] System call handler initialized");
     crate::serial_println!("[SYSCALL] SYSCALL/SYSRET enabled");
 }
 
+/// User registers `syscall_entry` saves at the top of the kernel stack
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    pub rflags: u64,
+    pub rip: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    /// The frame of the system call being handled
+    ///
+    /// # Safety
+    /// Must only be called while handling a system call
+    pub unsafe fn current() -> Self {
+        let top = crate::gdt::kernel_stack();
+        *(top - core::mem::size_of::<Self>() as u64).as_ptr::<Self>()
+    }
+
+    /// Context returning to user space from the call with `result`
+    ///
+    /// The caller-saved registers other than the result come back zeroed.
+    /// RFLAGS are the caller's, as SYSRET would restore them from R11, with
+    /// interrupts on.
+    pub fn return_context(&self, result: u64) -> CpuContext {
+        use x86_64::registers::rflags::RFlags;
+
+        CpuContext {
+            r15: self.r15,
+            r14: self.r14,
+            r13: self.r13,
+            r12: self.r12,
+            rbx: self.rbx,
+            rbp: self.rbp,
+            rax: result,
+            rflags: self.rflags | RFlags::INTERRUPT_FLAG.bits(),
+            ..CpuContext::user(VirtAddr::new(self.rip), VirtAddr::new(self.rsp))
+        }
+    }
+}
+
 /// Low-level syscall entry point
 #[unsafe(naked)]
 extern "C" fn syscall_entry() {
-    unsafe {
-        core::arch::naked_asm!(
-            // Save user stack
-            "mov [gs:0x00], rsp",
-            // Load kernel stack
-            "mov rsp, [gs:0x08]",
-            // Save registers
-            "push rcx",  // User RIP
-            "push r11",  // User RFLAGS
-            "push rbp",
-            "push rbx",
-            "push r12",
-            "push r13",
-            "push r14",
-            "push r15",
-            // Call handler
-            "mov rdi, rax",  // syscall number
-            "mov rsi, rdi",  // arg1
-            "mov rdx, rsi",  // arg2
-            "mov rcx, rdx",  // arg3
-            "call {}",
-            // Restore registers
-            "pop r15",
-            "pop r14",
-            "pop r13",
-            "pop r12",
-            "pop rbx",
-            "pop rbp",
-            "pop r11",
-            "pop rcx",
-            // Restore user stack
-            "mov rsp, [gs:0x00]",
-            // Return to userspace
-            "sysretq",
-            sym syscall_handler,
-            options(noreturn)
-        );
-    }
+    core::arch::naked_asm!(
+        // Switch to the kernel GS base
+        "swapgs",
+        // String instructions count up, whatever SFMASK holds
+        "cld",
+        // Save user stack
+        "mov gs:[0x00], rsp",
+        // Load kernel stack
+        "mov rsp, gs:[0x08]",
+        // Save registers, see SyscallFrame
+        "push qword ptr gs:[0x00]",  // User RSP
+        "push rcx",  // User RIP
+        "push r11",  // User RFLAGS
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        // Keep the stack 16 byte aligned for the call
+        "sub rsp, 8",
+        // Shuffle the syscall ABI into the C ABI, last argument first
+        "mov r8, r10",   // arg4
+        "mov rcx, rdx",  // arg3
+        "mov rdx, rsi",  // arg2
+        "mov rsi, rdi",  // arg1
+        "mov rdi, rax",  // syscall number
+        "call {}",
+        "add rsp, 8",
+        // Restore registers
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "pop r11",
+        "pop rcx",
+        // Restore user stack
+        "pop rsp",
+        // Return to userspace
+        "swapgs",
+        "sysretq",
+        sym syscall_handler,
+    );
 }
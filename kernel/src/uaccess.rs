@@ -0,0 +1,223 @@
+use crate::syscall::Errno;
+use crate::vma::{VmProtection, USER_SPACE_END};
+use alloc::vec::Vec;
+use core::mem::{size_of, MaybeUninit};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::{
+    registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+    VirtAddr,
+};
+
+/// Whether SMAP is enabled and user accesses need a STAC/CLAC window
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    static uaccess_copy_insn: u8;
+    static uaccess_copy_fixup: u8;
+}
+
+/// Copy `len` bytes with `rep movsb`
+///
+/// Returns the number of bytes left uncopied. A fault on the copy
+/// instruction that demand paging cannot resolve resumes at the fixup
+/// label with the remaining count still in `rcx`.
+#[unsafe(naked)]
+unsafe extern "C" fn copy_raw(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    core::arch::naked_asm!(
+        "mov rcx, rdx",
+        ".globl uaccess_copy_insn",
+        "uaccess_copy_insn:",
+        "rep movsb",
+        "xor eax, eax",
+        "ret",
+        ".globl uaccess_copy_fixup",
+        "uaccess_copy_fixup:",
+        "mov rax, rcx",
+        "ret",
+    );
+}
+
+/// Where to resume after an unresolvable fault at `rip`, if it is a user copy
+pub fn fixup_address(rip: VirtAddr) -> Option<VirtAddr> {
+    let insn = core::ptr::addr_of!(uaccess_copy_insn) as u64;
+    let fixup = core::ptr::addr_of!(uaccess_copy_fixup) as u64;
+    (rip.as_u64() == insn).then(|| VirtAddr::new(fixup))
+}
+
+/// Lets the kernel touch user pages while SMAP is on
+///
+/// STAC on creation, CLAC on drop.
+struct AccessWindow;
+
+impl AccessWindow {
+    fn open() -> Self {
+        if SMAP_ENABLED.load(Ordering::Relaxed) {
+            unsafe { core::arch::asm!("stac", options(nomem, nostack)) };
+        }
+        AccessWindow
+    }
+}
+
+impl Drop for AccessWindow {
+    fn drop(&mut self) {
+        if SMAP_ENABLED.load(Ordering::Relaxed) {
+            unsafe { core::arch::asm!("clac", options(nomem, nostack)) };
+        }
+    }
+}
+
+/// A range of user memory checked against the caller's areas
+#[derive(Debug, Clone, Copy)]
+pub struct UserSlice {
+    addr: VirtAddr,
+    len: usize,
+}
+
+impl UserSlice {
+    /// Validate `len` bytes at `addr` for access of the given kind
+    fn new(addr: u64, len: usize, access: VmProtection) -> Result<Self, Errno> {
+        let end = addr
+            .checked_add(len as u64)
+            .filter(|&end| end <= USER_SPACE_END)
+            .ok_or(Errno::EFAULT)?;
+        let slice = Self {
+            addr: VirtAddr::new(addr),
+            len,
+        };
+        if len == 0 {
+            return Ok(slice);
+        }
+
+        let pid = crate::process::current_pid().ok_or(Errno::EFAULT)?;
+        let allowed = crate::process::with_address_space(pid, |space| {
+            space
+                .vmas
+                .allows_range(slice.addr, VirtAddr::new(end), access)
+        });
+        match allowed {
+            Some(true) => Ok(slice),
+            _ => Err(Errno::EFAULT),
+        }
+    }
+
+    /// Validate user memory the kernel will read from
+    pub fn readable(addr: u64, len: usize) -> Result<Self, Errno> {
+        Self::new(addr, len, VmProtection::READ)
+    }
+
+    /// Validate user memory the kernel will write to
+    pub fn writable(addr: u64, len: usize) -> Result<Self, Errno> {
+        Self::new(addr, len, VmProtection::WRITE)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Address of `offset` bytes in, making sure `count` bytes fit
+    fn at(&self, offset: usize, count: usize) -> Result<u64, Errno> {
+        match offset.checked_add(count) {
+            Some(end) if end <= self.len => Ok(self.addr.as_u64() + offset as u64),
+            _ => Err(Errno::EFAULT),
+        }
+    }
+
+    /// Copy `buf.len()` bytes starting `offset` bytes in into `buf`
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<(), Errno> {
+        let src = self.at(offset, buf.len())?;
+        let _window = AccessWindow::open();
+        match unsafe { copy_raw(buf.as_mut_ptr(), src as *const u8, buf.len()) } {
+            0 => Ok(()),
+            _ => Err(Errno::EFAULT),
+        }
+    }
+
+    /// Copy `data` to `offset` bytes in
+    pub fn write_at(&self, offset: usize, data: &[u8]) -> Result<(), Errno> {
+        let dst = self.at(offset, data.len())?;
+        let _window = AccessWindow::open();
+        match unsafe { copy_raw(dst as *mut u8, data.as_ptr(), data.len()) } {
+            0 => Ok(()),
+            _ => Err(Errno::EFAULT),
+        }
+    }
+
+    /// Copy the whole range into a new buffer
+    pub fn read_to_vec(&self) -> Result<Vec<u8>, Errno> {
+        let mut buf = Vec::new();
+        buf.try_reserve_exact(self.len).map_err(|_| Errno::ENOMEM)?;
+        buf.resize(self.len, 0);
+        self.read_at(0, &mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Copy `dst.len()` bytes from user address `src`
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), Errno> {
+    UserSlice::readable(src, dst.len())?.read_at(0, dst)
+}
+
+/// Copy `src` to user address `dst`
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), Errno> {
+    UserSlice::writable(dst, src.len())?.write_at(0, src)
+}
+
+/// Copy a plain value from user address `src`
+///
+/// # Safety
+/// Every bit pattern of the right size must be a valid `T`
+pub unsafe fn read_user<T: Copy>(src: u64) -> Result<T, Errno> {
+    let mut value = MaybeUninit::<T>::uninit();
+    let bytes = core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>());
+    copy_from_user(bytes, src)?;
+    Ok(value.assume_init())
+}
+
+/// Copy a plain value to user address `dst`
+///
+/// # Safety
+/// `T` must not contain padding bytes
+pub unsafe fn write_user<T: Copy>(dst: u64, value: &T) -> Result<(), Errno> {
+    let bytes = core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>());
+    copy_to_user(dst, bytes)
+}
+
+/// Enable write protection in ring 0 and SMEP/SMAP where supported
+///
+/// Write protection makes kernel writes to copy-on-write user pages fault
+/// like user writes do.
+pub fn init() {
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+
+    // CPUID.(EAX=07h,ECX=0):EBX.SMEP[bit 7] and EBX.SMAP[bit 20]
+    let max_leaf = unsafe { core::arch::x86_64::__cpuid(0) }.eax;
+    let features = if max_leaf >= 7 {
+        unsafe { core::arch::x86_64::__cpuid_count(7, 0) }.ebx
+    } else {
+        0
+    };
+    let smep = features & (1 << 7) != 0;
+    let smap = features & (1 << 20) != 0;
+
+    unsafe {
+        Cr4::update(|flags| {
+            if smep {
+                flags.insert(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION);
+            }
+            if smap {
+                flags.insert(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION);
+            }
+        });
+    }
+    SMAP_ENABLED.store(smap, Ordering::Relaxed);
+
+    crate::serial_println!(
+        "[UACCESS] SMEP {}, SMAP {}",
+        if smep { "enabled" } else { "unsupported" },
+        if smap { "enabled" } else { "unsupported" }
+    );
+}
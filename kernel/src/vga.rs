@@ -43,6 +43,9 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
+/// Physical address of the text mode buffer
+const VGA_BUFFER_ADDR: u64 = 0xb8000;
+
 const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
 
@@ -121,6 +124,9 @@ lazy_static! {
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        buffer: unsafe {
+            let addr = crate::memory::physical_memory_offset() + VGA_BUFFER_ADDR;
+            &mut *addr.as_mut_ptr::<Buffer>()
+        },
     });
 }
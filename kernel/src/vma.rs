@@ -0,0 +1,388 @@
+use crate::shm::ShmId;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use x86_64::{
+    structures::paging::{PageSize, PageTableFlags, Size2MiB, Size4KiB},
+    VirtAddr,
+};
+
+/// End of the user half of the address space
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+/// Lowest address mmap picks when the caller gives no usable hint
+pub const MMAP_BASE: u64 = 0x0000_1000_0000_0000;
+
+bitflags! {
+    /// Access permissions of a virtual memory area
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct VmProtection: u32 {
+        const READ = 1;
+        const WRITE = 2;
+        const EXEC = 4;
+    }
+}
+
+impl VmProtection {
+    /// Check whether this is both writable and executable
+    pub fn is_write_exec(self) -> bool {
+        self.contains(VmProtection::WRITE | VmProtection::EXEC)
+    }
+}
+
+/// What a virtual memory area is backed by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaBacking {
+    /// Zero-filled private memory
+    Anonymous,
+    /// Pages of a file, starting at `offset`
+    File { inode: u64, offset: u64 },
+    /// Pages of a shared memory segment, starting at `offset`
+    SharedMemory { id: ShmId, offset: u64 },
+}
+
+impl VmaBacking {
+    /// Segment of shared memory backings
+    pub fn shm_id(self) -> Option<ShmId> {
+        match self {
+            VmaBacking::SharedMemory { id, .. } => Some(id),
+            _ => None,
+        }
+    }
+
+    /// The same backing `bytes` further in
+    fn advance(self, bytes: u64) -> Self {
+        match self {
+            VmaBacking::Anonymous => VmaBacking::Anonymous,
+            VmaBacking::File { inode, offset } => VmaBacking::File {
+                inode,
+                offset: offset + bytes,
+            },
+            VmaBacking::SharedMemory { id, offset } => VmaBacking::SharedMemory {
+                id,
+                offset: offset + bytes,
+            },
+        }
+    }
+}
+
+/// A contiguous range of user virtual memory with uniform protection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub protection: VmProtection,
+    pub backing: VmaBacking,
+    /// Populated with 2 MiB pages instead of 4 KiB pages
+    pub huge_pages: bool,
+}
+
+impl Vma {
+    pub fn new(
+        start: VirtAddr,
+        end: VirtAddr,
+        protection: VmProtection,
+        backing: VmaBacking,
+    ) -> Self {
+        Self {
+            start,
+            end,
+            protection,
+            backing,
+            huge_pages: false,
+        }
+    }
+
+    /// Populate this area with 2 MiB pages
+    pub fn with_huge_pages(mut self) -> Self {
+        self.huge_pages = true;
+        self
+    }
+
+    /// Size of the pages backing this area
+    pub fn page_size(&self) -> u64 {
+        if self.huge_pages {
+            Size2MiB::SIZE
+        } else {
+            Size4KiB::SIZE
+        }
+    }
+
+    /// Check whether splitting at `addr` would cut a page in half
+    pub fn splits_page(&self, addr: VirtAddr) -> bool {
+        self.start < addr && addr < self.end && !addr.is_aligned(self.page_size())
+    }
+
+    /// Length in bytes
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// Check whether an address falls inside this area
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// Check whether an access with the given permissions is allowed
+    ///
+    /// Areas without READ allow nothing: x86 pages cannot be writable or
+    /// executable without being readable, so they are kept from user mode.
+    pub fn allows(&self, access: VmProtection) -> bool {
+        self.is_user_accessible() && self.protection.contains(access)
+    }
+
+    /// Check whether user mode may touch the pages of this area at all
+    pub fn is_user_accessible(&self) -> bool {
+        self.protection.contains(VmProtection::READ)
+    }
+
+    /// Split off the part from `addr` onward, keeping `start..addr` in self
+    fn split_at(&mut self, addr: VirtAddr) -> Vma {
+        let tail = Vma {
+            start: addr,
+            backing: self.backing.advance(addr - self.start),
+            ..*self
+        };
+        self.end = addr;
+        tail
+    }
+
+    /// Check whether `next` seamlessly continues this area
+    fn can_merge(&self, next: &Vma) -> bool {
+        self.end == next.start
+            && self.protection == next.protection
+            && self.huge_pages == next.huge_pages
+            && self.backing.advance(self.size()) == next.backing
+    }
+
+    /// Page table flags for pages mapped in this area
+    ///
+    /// Pages of areas without READ stay present so their frames are still
+    /// tracked, but are left supervisor-only so any user access faults.
+    pub fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.is_user_accessible() {
+            flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+        if self.protection.contains(VmProtection::WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.protection.contains(VmProtection::EXEC) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        crate::memory::page_flags(flags)
+    }
+}
+
+/// Virtual memory area errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    InvalidRange,
+    Overlap,
+    NotMapped,
+    NoSpace,
+    /// Writable and executable without the JIT permission
+    WriteExec,
+    /// More access than the backing shared memory was attached with
+    PermissionDenied,
+    OutOfMemory,
+}
+
+/// Non-overlapping areas of one address space, keyed by start address
+#[derive(Debug, Clone)]
+pub struct VmaList {
+    areas: BTreeMap<u64, Vma>,
+}
+
+impl VmaList {
+    pub const fn new() -> Self {
+        Self {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    /// Find the area containing an address
+    pub fn find(&self, addr: VirtAddr) -> Option<&Vma> {
+        self.areas
+            .range(..=addr.as_u64())
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    /// Check whether any area overlaps `start..end`
+    pub fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        self.areas
+            .range(..end.as_u64())
+            .next_back()
+            .is_some_and(|(_, vma)| vma.end > start)
+    }
+
+    /// Add a new area
+    pub fn insert(&mut self, vma: Vma) -> Result<(), VmaError> {
+        if vma.start >= vma.end
+            || !vma.start.is_aligned(vma.page_size())
+            || !vma.end.is_aligned(vma.page_size())
+            || vma.end.as_u64() > USER_SPACE_END
+        {
+            return Err(VmaError::InvalidRange);
+        }
+
+        if self.overlaps(vma.start, vma.end) {
+            return Err(VmaError::Overlap);
+        }
+
+        self.areas.insert(vma.start.as_u64(), vma);
+        Ok(())
+    }
+
+    /// Add a new area and merge it with compatible neighbours
+    pub fn insert_merged(&mut self, vma: Vma) -> Result<(), VmaError> {
+        self.insert(vma)?;
+        self.merge_range(vma.start, vma.end);
+        Ok(())
+    }
+
+    /// Split the area containing `addr` so that an area starts there
+    fn split_at(&mut self, addr: VirtAddr) {
+        if let Some(mut head) = self.find(addr).copied() {
+            if head.start != addr {
+                let tail = head.split_at(addr);
+                self.areas.insert(head.start.as_u64(), head);
+                self.areas.insert(tail.start.as_u64(), tail);
+            }
+        }
+    }
+
+    /// Merge areas around `start..end` with compatible neighbours
+    fn merge_range(&mut self, start: VirtAddr, end: VirtAddr) {
+        let mut cursor = self
+            .areas
+            .range(..start.as_u64())
+            .next_back()
+            .map(|(&key, _)| key)
+            .unwrap_or(start.as_u64());
+
+        while let Some(current) = self.areas.range(cursor..).next().map(|(_, v)| *v) {
+            if current.start > end {
+                break;
+            }
+            let next = match self.areas.range(current.start.as_u64() + 1..).next() {
+                Some((_, next)) => *next,
+                None => break,
+            };
+
+            if current.can_merge(&next) {
+                self.areas.remove(&next.start.as_u64());
+                if let Some(vma) = self.areas.get_mut(&current.start.as_u64()) {
+                    vma.end = next.end;
+                }
+                cursor = current.start.as_u64();
+            } else {
+                cursor = next.start.as_u64();
+            }
+        }
+    }
+
+    /// Check whether every byte of `start..end` is covered by an area
+    pub fn is_fully_mapped(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        self.allows_range(start, end, VmProtection::empty())
+    }
+
+    /// Check whether `start..end` is fully mapped and allows `access`
+    pub fn allows_range(&self, start: VirtAddr, end: VirtAddr, access: VmProtection) -> bool {
+        let mut addr = start;
+        while addr < end {
+            match self.find(addr) {
+                Some(vma) if vma.allows(access) => addr = vma.end,
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// Remove `start..end`, splitting partially covered areas
+    ///
+    /// Returns the removed pieces.
+    pub fn remove_range(&mut self, start: VirtAddr, end: VirtAddr) -> Vec<Vma> {
+        self.split_at(start);
+        self.split_at(end);
+
+        let keys: Vec<u64> = self
+            .areas
+            .range(start.as_u64()..end.as_u64())
+            .map(|(&key, _)| key)
+            .collect();
+        keys.into_iter()
+            .filter_map(|key| self.areas.remove(&key))
+            .collect()
+    }
+
+    /// Change the protection of `start..end`, which must be fully mapped
+    pub fn protect_range(
+        &mut self,
+        start: VirtAddr,
+        end: VirtAddr,
+        protection: VmProtection,
+    ) -> Result<(), VmaError> {
+        if !self.is_fully_mapped(start, end) {
+            return Err(VmaError::NotMapped);
+        }
+
+        self.split_at(start);
+        self.split_at(end);
+        for (_, vma) in self.areas.range_mut(start.as_u64()..end.as_u64()) {
+            vma.protection = protection;
+        }
+        self.merge_range(start, end);
+        Ok(())
+    }
+
+    /// Check whether splitting at `addr` would cut a huge page in half
+    pub fn splits_page(&self, addr: VirtAddr) -> bool {
+        self.find(addr).is_some_and(|vma| vma.splits_page(addr))
+    }
+
+    /// Find a free gap of `len` bytes aligned to `align` at or above `hint`
+    pub fn find_free(&self, len: u64, align: u64, hint: VirtAddr) -> Option<VirtAddr> {
+        let mut candidate = core::cmp::max(hint.as_u64(), MMAP_BASE).next_multiple_of(align);
+
+        for vma in self.areas.values() {
+            if vma.end.as_u64() <= candidate {
+                continue;
+            }
+            if vma.start.as_u64() >= candidate.checked_add(len)? {
+                break;
+            }
+            candidate = vma.end.as_u64().next_multiple_of(align);
+        }
+
+        if candidate.checked_add(len)? > USER_SPACE_END {
+            return None;
+        }
+        Some(VirtAddr::new(candidate))
+    }
+
+    /// Remove the area starting at `start`
+    pub fn remove(&mut self, start: VirtAddr) -> Result<Vma, VmaError> {
+        self.areas
+            .remove(&start.as_u64())
+            .ok_or(VmaError::NotMapped)
+    }
+
+    /// Iterate over all areas in address order
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+
+    /// Total mapped bytes
+    pub fn total_size(&self) -> u64 {
+        self.areas.values().map(|vma| vma.size()).sum()
+    }
+}
+
+impl Default for VmaList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
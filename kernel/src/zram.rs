@@ -0,0 +1,334 @@
+use crate::lz4::{self, Compressor};
+use crate::memory::{phys_to_virt, physical_memory_offset};
+use crate::swap::{SwapBackend, SwapError, PAGE_SIZE};
+use alloc::vec::Vec;
+use x86_64::structures::paging::PhysFrame;
+
+/// Allocation granularity of the compressed pool
+const CHUNK_SIZE: usize = 64;
+
+/// Chunks in one pool page, one bit each in `PoolPage::used`
+const CHUNKS_PER_PAGE: usize = PAGE_SIZE / CHUNK_SIZE;
+
+/// Pages that compress worse than this are stored as they are
+const MAX_COMPRESSED: usize = PAGE_SIZE * 3 / 4;
+
+/// Pool frames held back so pages can be evicted with no free memory left
+const RESERVE_FRAMES: usize = 8;
+
+/// Without `zram=`, the device holds up to this fraction of free memory
+const DEFAULT_SIZE_DIVISOR: usize = 4;
+
+/// Kernel heap taken per page of capacity by the slot and pool tables
+const PAGE_OVERHEAD: usize =
+    core::mem::size_of::<ZSlot>() + core::mem::size_of::<Option<PoolPage>>();
+
+/// What a zram slot holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ZSlot {
+    Empty,
+    /// A page of zeroes, kept without pool memory
+    Zero,
+    /// `len` bytes at `chunk` of pool page `page`, uncompressed if a full page
+    Stored {
+        page: u32,
+        chunk: u8,
+        len: u16,
+    },
+}
+
+/// Sizes of what zram holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ZramStats {
+    /// Pages stored, including zero pages
+    pub pages: u64,
+    /// Pages of zeroes, which take no pool memory
+    pub zero_pages: u64,
+    /// Size of the stored pages before compression
+    pub original_bytes: u64,
+    /// Size of the stored pages after compression
+    pub compressed_bytes: u64,
+    /// Memory taken by the pool
+    pub pool_bytes: u64,
+}
+
+/// A pool page divided into chunks
+struct PoolPage {
+    frame: PhysFrame,
+    used: u64,
+}
+
+/// Mask of `count` chunks starting at chunk 0
+fn run_mask(count: usize) -> u64 {
+    if count == CHUNKS_PER_PAGE {
+        u64::MAX
+    } else {
+        (1 << count) - 1
+    }
+}
+
+/// Packs compressed pages into chunks of pool pages
+///
+/// Objects never cross a page boundary. Every vector is sized up front so
+/// the pool never needs the kernel heap while memory is short.
+struct ZPool {
+    pages: Vec<Option<PoolPage>>,
+    reserve: Vec<PhysFrame>,
+    in_use: usize,
+}
+
+impl ZPool {
+    fn new(max_pages: usize) -> Result<Self, SwapError> {
+        let mut pool = Self {
+            pages: Vec::new(),
+            reserve: Vec::new(),
+            in_use: 0,
+        };
+        pool.pages
+            .try_reserve_exact(max_pages)
+            .map_err(|_| SwapError::OutOfMemory)?;
+        pool.reserve
+            .try_reserve_exact(RESERVE_FRAMES)
+            .map_err(|_| SwapError::OutOfMemory)?;
+        pool.refill();
+        Ok(pool)
+    }
+
+    /// Top the reserve back up from the buddy allocator
+    fn refill(&mut self) {
+        while self.reserve.len() < RESERVE_FRAMES {
+            match crate::buddy::allocate_frames(0) {
+                Some(frame) => self.reserve.push(frame),
+                None => break,
+            }
+        }
+    }
+
+    /// Find room for `len` bytes, returning the pool page and first chunk
+    fn allocate(&mut self, len: usize) -> Option<(u32, u8)> {
+        let count = len.div_ceil(CHUNK_SIZE);
+        let mask = run_mask(count);
+
+        for (index, page) in self.pages.iter_mut().enumerate() {
+            let Some(page) = page else {
+                continue;
+            };
+            let start = (0..=CHUNKS_PER_PAGE - count).find(|&i| page.used & (mask << i) == 0);
+            if let Some(start) = start {
+                page.used |= mask << start;
+                return Some((index as u32, start as u8));
+            }
+        }
+
+        let index = match self.pages.iter().position(Option::is_none) {
+            Some(index) => index,
+            None if self.pages.len() < self.pages.capacity() => {
+                self.pages.push(None);
+                self.pages.len() - 1
+            }
+            None => return None,
+        };
+        let frame = crate::buddy::allocate_frames(0).or_else(|| self.reserve.pop())?;
+        self.pages[index] = Some(PoolPage { frame, used: mask });
+        self.in_use += 1;
+        Some((index as u32, 0))
+    }
+
+    /// Free `len` bytes at `chunk` of pool page `page`
+    fn free(&mut self, page: u32, chunk: u8, len: usize) {
+        let Some(slot) = self.pages.get_mut(page as usize) else {
+            return;
+        };
+        let Some(pool_page) = slot.as_mut() else {
+            return;
+        };
+        pool_page.used &= !(run_mask(len.div_ceil(CHUNK_SIZE)) << chunk);
+
+        if pool_page.used == 0 {
+            let frame = pool_page.frame;
+            *slot = None;
+            self.in_use -= 1;
+            if self.reserve.len() < RESERVE_FRAMES {
+                self.reserve.push(frame);
+            } else {
+                crate::buddy::free_frames(frame, 0);
+            }
+        }
+    }
+
+    /// The `len` bytes at `chunk` of pool page `page`
+    fn bytes(&mut self, page: u32, chunk: u8, len: usize) -> &mut [u8] {
+        let frame = self.pages[page as usize]
+            .as_ref()
+            .expect("zram object in a free pool page")
+            .frame;
+        let virt = phys_to_virt(physical_memory_offset(), frame.start_address());
+        unsafe {
+            let start = virt.as_mut_ptr::<u8>().add(chunk as usize * CHUNK_SIZE);
+            core::slice::from_raw_parts_mut(start, len)
+        }
+    }
+}
+
+/// Swap backend compressing pages with LZ4 into a RAM pool
+pub struct Zram {
+    slots: Vec<ZSlot>,
+    pool: ZPool,
+    compressor: Compressor,
+    buffer: [u8; MAX_COMPRESSED],
+    stats: ZramStats,
+}
+
+impl Zram {
+    /// Create a zram device sized by `zram=`, a quarter of free memory by default
+    ///
+    /// The capacity is capped to what is free now and so that the slot and
+    /// pool tables take at most half the heap still left to grow into.
+    pub fn new() -> Result<Self, SwapError> {
+        let free_pages = crate::buddy::BUDDY_ALLOCATOR.lock().free_memory() as usize / PAGE_SIZE;
+        let wanted = match crate::cmdline::value("zram") {
+            Some(value) => match crate::cmdline::parse_size(value) {
+                Some(bytes) => bytes / PAGE_SIZE,
+                None => {
+                    crate::serial_println!("[ZRAM] Ignoring bad zram={}", value);
+                    free_pages / DEFAULT_SIZE_DIVISOR
+                }
+            },
+            None => free_pages / DEFAULT_SIZE_DIVISOR,
+        };
+        let heap_room = crate::allocator::heap_limit() - crate::allocator::heap_size();
+        let max_pages = wanted.min(free_pages).min(heap_room / 2 / PAGE_OVERHEAD);
+        if max_pages == 0 {
+            return Err(SwapError::TooSmall);
+        }
+        // Slot 0 is never used
+        let count = max_pages + 1;
+
+        let mut slots = Vec::new();
+        slots
+            .try_reserve_exact(count)
+            .map_err(|_| SwapError::OutOfMemory)?;
+        slots.resize(count, ZSlot::Empty);
+
+        Ok(Self {
+            slots,
+            pool: ZPool::new(max_pages)?,
+            compressor: Compressor::new(),
+            buffer: [0; MAX_COMPRESSED],
+            stats: ZramStats::default(),
+        })
+    }
+
+    /// Current statistics
+    pub fn stats(&self) -> ZramStats {
+        ZramStats {
+            pool_bytes: (self.pool.in_use * PAGE_SIZE) as u64,
+            ..self.stats
+        }
+    }
+
+    fn slot_mut(&mut self, slot: u64) -> Result<&mut ZSlot, SwapError> {
+        self.slots.get_mut(slot as usize).ok_or(SwapError::Corrupt)
+    }
+}
+
+impl SwapBackend for Zram {
+    fn slots(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn store(&mut self, slot: u64, page: &[u8]) -> Result<(), SwapError> {
+        self.discard(slot);
+
+        if page.iter().all(|&byte| byte == 0) {
+            *self.slot_mut(slot)? = ZSlot::Zero;
+            self.stats.pages += 1;
+            self.stats.zero_pages += 1;
+            self.stats.original_bytes += PAGE_SIZE as u64;
+            return Ok(());
+        }
+
+        let data = match self.compressor.compress(page, &mut self.buffer) {
+            Some(len) => &self.buffer[..len],
+            None => page,
+        };
+        let (pool_page, chunk) = self
+            .pool
+            .allocate(data.len())
+            .ok_or(SwapError::OutOfMemory)?;
+        self.pool
+            .bytes(pool_page, chunk, data.len())
+            .copy_from_slice(data);
+
+        let len = data.len();
+        *self.slot_mut(slot)? = ZSlot::Stored {
+            page: pool_page,
+            chunk,
+            len: len as u16,
+        };
+        self.stats.pages += 1;
+        self.stats.original_bytes += PAGE_SIZE as u64;
+        self.stats.compressed_bytes += len as u64;
+        self.pool.refill();
+        Ok(())
+    }
+
+    fn load(&mut self, slot: u64, page: &mut [u8]) -> Result<(), SwapError> {
+        match *self.slot_mut(slot)? {
+            ZSlot::Empty => Err(SwapError::Corrupt),
+            ZSlot::Zero => {
+                page.fill(0);
+                Ok(())
+            }
+            ZSlot::Stored {
+                page: pool_page,
+                chunk,
+                len,
+            } => {
+                let data = self.pool.bytes(pool_page, chunk, len as usize);
+                if len as usize == PAGE_SIZE {
+                    page.copy_from_slice(data);
+                    return Ok(());
+                }
+                match lz4::decompress(data, page) {
+                    Ok(PAGE_SIZE) => Ok(()),
+                    _ => Err(SwapError::Corrupt),
+                }
+            }
+        }
+    }
+
+    fn discard(&mut self, slot: u64) {
+        let Ok(entry) = self.slot_mut(slot) else {
+            return;
+        };
+        let old = core::mem::replace(entry, ZSlot::Empty);
+
+        match old {
+            ZSlot::Empty => return,
+            ZSlot::Zero => self.stats.zero_pages -= 1,
+            ZSlot::Stored {
+                page: pool_page,
+                chunk,
+                len,
+            } => {
+                self.pool.free(pool_page, chunk, len as usize);
+                self.stats.compressed_bytes -= len as u64;
+            }
+        }
+        self.stats.pages -= 1;
+        self.stats.original_bytes -= PAGE_SIZE as u64;
+    }
+
+    fn log_stats(&self) {
+        let stats = self.stats();
+        crate::serial_println!(
+            "[ZRAM] {} pages ({} zero), {} KB compressed to {} KB, pool {} KB",
+            stats.pages,
+            stats.zero_pages,
+            stats.original_bytes / 1024,
+            stats.compressed_bytes / 1024,
+            stats.pool_bytes / 1024
+        );
+    }
+}
@@ -38,7 +38,7 @@ class IPC {
 public:
     static ssize_t send(int32_t receiver, const void* data, size_t size) {
         return syscall::syscall3(
-            syscall::SyscallNumber::MMAP,
+            syscall::SyscallNumber::IPC_SEND,
             receiver,
             reinterpret_cast<uint64_t>(data),
             size
@@ -47,7 +47,7 @@ public:
     
     static ssize_t receive(void* buffer, size_t size) {
         return syscall::syscall3(
-            syscall::SyscallNumber::MUNMAP,
+            syscall::SyscallNumber::IPC_RECEIVE,
             reinterpret_cast<uint64_t>(buffer),
             size,
             0
@@ -60,6 +60,34 @@ public:
     }
 };
 
+// Shared memory segments. Flags to create() are the protection other
+// processes may attach with, plus HUGETLB for 2 MiB pages. A deleted
+// segment goes away once every process has detached.
+class SharedMemory {
+public:
+    static constexpr uint64_t HUGETLB = 0x800;
+
+    // Returns the segment id, or -errno
+    static int64_t create(size_t size, uint64_t flags) {
+        return syscall::syscall2(syscall::SyscallNumber::SHM_CREATE, size, flags);
+    }
+
+    // Maps the whole segment, returns nullptr on failure
+    static void* attach(uint64_t id, int prot) {
+        uint64_t ret = syscall::syscall2(syscall::SyscallNumber::SHM_ATTACH, id, prot);
+        return ret > static_cast<uint64_t>(-4096) ? nullptr : reinterpret_cast<void*>(ret);
+    }
+
+    static int detach(uint64_t id) {
+        return syscall::syscall1(syscall::SyscallNumber::SHM_DETACH, id);
+    }
+
+    // Only the creator may delete a segment
+    static int remove(uint64_t id) {
+        return syscall::syscall1(syscall::SyscallNumber::SHM_DELETE, id);
+    }
+};
+
 } // namespace ipc
 } // namespace fracture
 
@@ -16,6 +16,24 @@ enum class MemoryProtection : int {
     EXEC = 4,
 };
 
+enum class MapFlags : uint64_t {
+    SHARED = 0x01,
+    PRIVATE = 0x02,
+    FIXED = 0x10,
+    ANONYMOUS = 0x20,
+};
+
+inline MapFlags operator|(MapFlags a, MapFlags b) {
+    return static_cast<MapFlags>(
+        static_cast<uint64_t>(a) | static_cast<uint64_t>(b)
+    );
+}
+
+// Syscalls return -errno on failure
+inline bool is_error(uint64_t ret) {
+    return ret > static_cast<uint64_t>(-4096);
+}
+
 inline MemoryProtection operator|(MemoryProtection a, MemoryProtection b) {
     return static_cast<MemoryProtection>(
         static_cast<int>(a) | static_cast<int>(b)
@@ -24,15 +42,21 @@ inline MemoryProtection operator|(MemoryProtection a, MemoryProtection b) {
 
 class MemoryMapper {
 public:
-    static void* map(void* addr, size_t length, MemoryProtection prot) {
-        return reinterpret_cast<void*>(
-            syscall::syscall3(
-                syscall::SyscallNumber::MMAP,
-                reinterpret_cast<uint64_t>(addr),
-                length,
-                static_cast<uint64_t>(prot)
-            )
+    static void* map(void* addr, size_t length, MemoryProtection prot,
+                     MapFlags flags = MapFlags::PRIVATE | MapFlags::ANONYMOUS) {
+        uint64_t ret = syscall::syscall4(
+            syscall::SyscallNumber::MMAP,
+            reinterpret_cast<uint64_t>(addr),
+            length,
+            static_cast<uint64_t>(prot),
+            static_cast<uint64_t>(flags)
         );
+        return is_error(ret) ? nullptr : reinterpret_cast<void*>(ret);
+    }
+    
+    static void* map_fixed(void* addr, size_t length, MemoryProtection prot) {
+        return map(addr, length, prot,
+                   MapFlags::PRIVATE | MapFlags::ANONYMOUS | MapFlags::FIXED);
     }
     
     static int unmap(void* addr, size_t length) {
@@ -44,6 +68,15 @@ public:
         );
     }
     
+    static int protect(void* addr, size_t length, MemoryProtection prot) {
+        return syscall::syscall3(
+            syscall::SyscallNumber::MPROTECT,
+            reinterpret_cast<uint64_t>(addr),
+            length,
+            static_cast<uint64_t>(prot)
+        );
+    }
+    
     static void* allocate_pages(size_t count) {
         return map(nullptr, count * PAGE_SIZE, 
                    MemoryProtection::READ | MemoryProtection::WRITE);
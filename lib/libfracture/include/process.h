@@ -9,6 +9,25 @@ namespace process {
 
 using pid_t = int32_t;
 
+constexpr uint64_t RLIM_INFINITY = ~0ULL;
+
+enum class Resource : uint64_t {
+    RESIDENT = 5,
+    ADDRESS_SPACE = 9,
+};
+
+struct ResourceLimit {
+    uint64_t soft;
+    uint64_t hard;
+};
+
+// Memory use in bytes
+struct MemoryUsage {
+    uint64_t resident;
+    uint64_t peak_resident;
+    uint64_t virtual_size;
+};
+
 class Process {
 public:
     static pid_t current_pid() {
@@ -35,6 +54,31 @@ public:
             0
         );
     }
+    
+    static int get_limit(Resource resource, ResourceLimit* limit) {
+        return syscall::syscall2(
+            syscall::SyscallNumber::GETRLIMIT,
+            static_cast<uint64_t>(resource),
+            reinterpret_cast<uint64_t>(limit)
+        );
+    }
+    
+    static int set_limit(Resource resource, const ResourceLimit& limit) {
+        return syscall::syscall2(
+            syscall::SyscallNumber::SETRLIMIT,
+            static_cast<uint64_t>(resource),
+            reinterpret_cast<uint64_t>(&limit)
+        );
+    }
+    
+    // pid 0 queries the calling process
+    static int memory_usage(pid_t pid, MemoryUsage* usage) {
+        return syscall::syscall2(
+            syscall::SyscallNumber::MEMORY_USAGE,
+            pid,
+            reinterpret_cast<uint64_t>(usage)
+        );
+    }
 };
 
 } // namespace process
@@ -17,7 +17,37 @@ enum class SyscallNumber : uint64_t {
     WAIT = 61,
     GETPID = 39,
     MMAP = 9,
+    MPROTECT = 10,
     MUNMAP = 11,
+    GETRLIMIT = 97,
+    SCHED_SETSCHEDULER = 144,
+    SCHED_GETSCHEDULER = 145,
+    SETRLIMIT = 160,
+    // FractureOS specific calls
+    IPC_SEND = 400,
+    IPC_RECEIVE = 401,
+    MEMORY_USAGE = 402,
+    JIT_PERMISSION = 403,
+    SHM_CREATE = 404,
+    SHM_ATTACH = 405,
+    SHM_DETACH = 406,
+    SHM_DELETE = 407,
+};
+
+// Scheduling policies, numbered as on Linux
+constexpr int SCHED_NORMAL = 0;
+constexpr int SCHED_FIFO = 1;
+constexpr int SCHED_RR = 2;
+constexpr int SCHED_DEADLINE = 6;
+
+// priority is the real-time priority (1-99) for SCHED_FIFO and SCHED_RR;
+// runtime, deadline and period are in nanoseconds for SCHED_DEADLINE.
+// Fields the policy does not use must be zero.
+struct SchedParam {
+    uint64_t priority;
+    uint64_t runtime;
+    uint64_t deadline;
+    uint64_t period;
 };
 
 inline uint64_t syscall0(SyscallNumber num) {
@@ -42,6 +72,17 @@ inline uint64_t syscall1(SyscallNumber num, uint64_t arg1) {
     return ret;
 }
 
+inline uint64_t syscall2(SyscallNumber num, uint64_t arg1, uint64_t arg2) {
+    uint64_t ret;
+    asm volatile(
+        "syscall"
+        : "=a"(ret)
+        : "a"(static_cast<uint64_t>(num)), "D"(arg1), "S"(arg2)
+        : "rcx", "r11", "memory"
+    );
+    return ret;
+}
+
 inline uint64_t syscall3(SyscallNumber num, uint64_t arg1, uint64_t arg2, uint64_t arg3) {
     uint64_t ret;
     asm volatile(
@@ -53,6 +94,19 @@ inline uint64_t syscall3(SyscallNumber num, uint64_t arg1, uint64_t arg2, uint64
     return ret;
 }
 
+inline uint64_t syscall4(SyscallNumber num, uint64_t arg1, uint64_t arg2, uint64_t arg3,
+                         uint64_t arg4) {
+    uint64_t ret;
+    register uint64_t r10 asm("r10") = arg4;
+    asm volatile(
+        "syscall"
+        : "=a"(ret)
+        : "a"(static_cast<uint64_t>(num)), "D"(arg1), "S"(arg2), "d"(arg3), "r"(r10)
+        : "rcx", "r11", "memory"
+    );
+    return ret;
+}
+
 // High-level wrappers
 inline ssize_t read(int fd, void* buf, size_t count) {
     return syscall3(SyscallNumber::READ, fd, 
@@ -73,6 +127,25 @@ inline int getpid() {
     return syscall0(SyscallNumber::GETPID);
 }
 
+// pid 0 is the caller. Only init may pick a real-time or deadline
+// policy, or change another process.
+inline int sched_setscheduler(int pid, int policy, const SchedParam* param) {
+    return syscall3(SyscallNumber::SCHED_SETSCHEDULER, pid, policy,
+                    reinterpret_cast<uint64_t>(param));
+}
+
+// Returns the policy; param may be null
+inline int sched_getscheduler(int pid, SchedParam* param) {
+    return syscall2(SyscallNumber::SCHED_GETSCHEDULER, pid,
+                    reinterpret_cast<uint64_t>(param));
+}
+
+// Allows or forbids writable+executable mappings for pid (0 is the
+// caller). Only init may grant it, or change another process.
+inline int jit_permission(int pid, bool allow) {
+    return syscall2(SyscallNumber::JIT_PERMISSION, pid, allow);
+}
+
 } // namespace syscall
 } // namespace fracture
 
@@ -17,6 +17,11 @@ dd if="$BUILD_DIR/boot.bin" of="$IMAGE" conv=notrunc bs=512 count=1
 # Write stage 2
 dd if="$BUILD_DIR/stage2.bin" of="$IMAGE" conv=notrunc bs=512 seek=1 count=4
 
+# Kernel command line (e.g. CMDLINE="nokaslr"), stored at offset 1792 of stage 2
+if [ -n "$CMDLINE" ]; then
+    printf '%s\0' "${CMDLINE:0:255}" | dd of="$IMAGE" conv=notrunc bs=1 seek=$((512 + 1792))
+fi
+
 # Write kernel (starting at sector 6)
 if [ -f "$BUILD_DIR/kernel.bin" ]; then
     dd if="$BUILD_DIR/kernel.bin" of="$IMAGE" conv=notrunc bs=512 seek=5